@@ -2,6 +2,20 @@
 fn main() -> anyhow::Result<()> {
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
     embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
-    prost_build::compile_protos(&["src/messages.proto"], &["src/"])?;
+
+    let git_hash = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash.trim());
+    println!("cargo:rustc-env=BUILD_PROFILE={}", std::env::var("PROFILE")?);
+    // any rerun-if-changed turns off the default rescan of the whole package,
+    // so everything the script reads has to be listed
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
     Ok(())
 }
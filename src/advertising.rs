@@ -0,0 +1,218 @@
+//! What the watch puts in its advertisements, so several watches in one place
+//! can be told apart by name and filtered by service.
+//!
+//! The config is the protobuf message the phone sets it with, and is kept in
+//! NVS encoded the same way.
+
+use std::sync::Mutex;
+
+use color_eyre::eyre::ensure;
+use once_cell::sync::Lazy;
+use prost::Message as _;
+use tracing::{error, info};
+
+use crate::message::{AdvertisingConfig, AdvertisingField};
+use crate::reconnect::DEFAULT_GIVE_UP_SECS;
+use crate::storage::Nvs;
+
+const NAMESPACE: &str = "advertising";
+const CONFIG: &str = "config";
+
+pub const DEFAULT_NAME: &str = "\u{1F980}";
+/// About what NimBLE picks when left to itself
+pub const DEFAULT_INTERVAL_MS: u32 = 50;
+pub const DEFAULT_DURATION_SECS: u32 = 60;
+
+/// Advertisements and scan responses each carry at most this many bytes
+const MAX_PAYLOAD_LEN: usize = 31;
+/// Every field starts with a length byte and a type byte
+const FIELD_HEADER_LEN: usize = 2;
+const FLAGS_LEN: usize = FIELD_HEADER_LEN + 1;
+const ADV_INTERVAL_LEN: usize = FIELD_HEADER_LEN + 2;
+const TX_POWER_LEN: usize = FIELD_HEADER_LEN + 1;
+const APPEARANCE_LEN: usize = FIELD_HEADER_LEN + 2;
+
+/// Leaves room for the length and type bytes in a 31 byte packet
+const MAX_NAME_LEN: usize = MAX_PAYLOAD_LEN - FIELD_HEADER_LEN;
+/// The range of advertising intervals the spec allows
+const MIN_INTERVAL_MS: u32 = 20;
+const MAX_INTERVAL_MS: u32 = 10240;
+/// NimBLE takes the duration in milliseconds as an `i32`
+const MAX_DURATION_SECS: u32 = i32::MAX as u32 / 1000;
+
+const BATTERY_SERVICE_UUID: u16 = 0x180F;
+/// Advertised in HID mode, since hosts look for it when choosing what to
+/// pair with
+pub const HID_SERVICE_UUID: u16 = 0x1812;
+
+/// Advertises the battery and data services, with everything else in the
+/// scan response so the 128 bit UUID fits.
+pub fn default_config() -> AdvertisingConfig {
+    let mut data_service = *crate::bluetooth::DATA_SERVICE_UUID.as_bytes();
+    data_service.reverse();
+
+    AdvertisingConfig {
+        name: DEFAULT_NAME.to_owned(),
+        service_uuids: vec![
+            BATTERY_SERVICE_UUID.to_le_bytes().to_vec(),
+            data_service.to_vec(),
+        ],
+        scan_response: vec![
+            AdvertisingField::Name as i32,
+            AdvertisingField::TxPower as i32,
+        ],
+        interval_ms: DEFAULT_INTERVAL_MS,
+        duration_secs: DEFAULT_DURATION_SECS,
+        reconnect_secs: DEFAULT_GIVE_UP_SECS,
+    }
+}
+
+fn load() -> color_eyre::Result<Option<AdvertisingConfig>> {
+    let nvs = Nvs::open(NAMESPACE)?;
+    let config = match nvs.get_blob(CONFIG)? {
+        Some(buf) => buf,
+        None => return Ok(None),
+    };
+
+    // fills in anything added since it was saved
+    let config = validate(AdvertisingConfig::decode(config.as_slice())?)?;
+    Ok(Some(config))
+}
+
+static CURRENT: Lazy<Mutex<AdvertisingConfig>> = Lazy::new(|| {
+    let config = load()
+        .unwrap_or_else(|err| {
+            error!(
+                ?err,
+                "Failed to load the advertising config, using the default"
+            );
+            None
+        })
+        .unwrap_or_else(default_config);
+    Mutex::new(config)
+});
+
+pub fn current() -> AdvertisingConfig {
+    CURRENT.lock().unwrap().clone()
+}
+
+/// How many bytes `config` takes up in the advertisement and in the scan
+/// response, laid out as [`crate::bluetooth`] lays them out. `hid_mode` adds
+/// the HID service and the keyboard appearance.
+fn payload_lens(config: &AdvertisingConfig, hid_mode: bool) -> (usize, usize) {
+    let mut adv_len = FLAGS_LEN + ADV_INTERVAL_LEN;
+    let mut rsp_len = 0;
+    if hid_mode {
+        rsp_len += APPEARANCE_LEN;
+    }
+
+    let mut place = |field: AdvertisingField, len: usize| {
+        if config.scan_response.contains(&(field as i32)) {
+            rsp_len += len;
+        } else {
+            adv_len += len;
+        }
+    };
+
+    place(AdvertisingField::Name, FIELD_HEADER_LEN + config.name.len());
+    place(AdvertisingField::TxPower, TX_POWER_LEN);
+
+    let hid_uuid = HID_SERVICE_UUID.to_le_bytes();
+    let mut uuids16 = config
+        .service_uuids
+        .iter()
+        .filter(|uuid| uuid.len() == 2)
+        .count();
+    if hid_mode && !config.service_uuids.iter().any(|uuid| *uuid == hid_uuid) {
+        uuids16 += 1;
+    }
+    let uuids128 = config
+        .service_uuids
+        .iter()
+        .filter(|uuid| uuid.len() == 16)
+        .count();
+    let mut uuids_len = 0;
+    if uuids16 > 0 {
+        uuids_len += FIELD_HEADER_LEN + 2 * uuids16;
+    }
+    if uuids128 > 0 {
+        uuids_len += FIELD_HEADER_LEN + 16 * uuids128;
+    }
+    place(AdvertisingField::ServiceUuids, uuids_len);
+
+    (adv_len, rsp_len)
+}
+
+/// Fills in the defaults for an empty name or zero interval or reconnect time,
+/// and checks the rest is something we can advertise
+fn validate(mut config: AdvertisingConfig) -> color_eyre::Result<AdvertisingConfig> {
+    if config.name.is_empty() {
+        config.name = DEFAULT_NAME.to_owned();
+    }
+    if config.interval_ms == 0 {
+        config.interval_ms = DEFAULT_INTERVAL_MS;
+    }
+    if config.reconnect_secs == 0 {
+        config.reconnect_secs = DEFAULT_GIVE_UP_SECS;
+    }
+
+    ensure!(
+        config.name.len() <= MAX_NAME_LEN,
+        "Names can be at most {} bytes, got {}",
+        MAX_NAME_LEN,
+        config.name.len()
+    );
+    ensure!(
+        !config.name.contains('\0'),
+        "Names can't contain a nul byte"
+    );
+    ensure!(
+        (MIN_INTERVAL_MS..=MAX_INTERVAL_MS).contains(&config.interval_ms),
+        "Advertising interval must be between {}ms and {}ms, got {}ms",
+        MIN_INTERVAL_MS,
+        MAX_INTERVAL_MS,
+        config.interval_ms
+    );
+    ensure!(
+        config.duration_secs <= MAX_DURATION_SECS,
+        "Advertising duration must be at most {}s, got {}s",
+        MAX_DURATION_SECS,
+        config.duration_secs
+    );
+    for uuid in &config.service_uuids {
+        ensure!(
+            uuid.len() == 2 || uuid.len() == 16,
+            "Service UUIDs must be 2 or 16 bytes, got {}",
+            uuid.len()
+        );
+    }
+
+    // HID mode only adds to both, and can be turned on at any time
+    let (adv_len, rsp_len) = payload_lens(&config, true);
+    ensure!(
+        adv_len <= MAX_PAYLOAD_LEN,
+        "The advertisement would be {} bytes in HID mode, over the {} byte limit",
+        adv_len,
+        MAX_PAYLOAD_LEN
+    );
+    ensure!(
+        rsp_len <= MAX_PAYLOAD_LEN,
+        "The scan response would be {} bytes in HID mode, over the {} byte limit",
+        rsp_len,
+        MAX_PAYLOAD_LEN
+    );
+
+    Ok(config)
+}
+
+/// Replaces the config, persisting it for the next boot. Takes effect the
+/// next time the watch advertises.
+pub fn set(config: AdvertisingConfig) -> color_eyre::Result<()> {
+    let config = validate(config)?;
+
+    Nvs::open(NAMESPACE)?.set_blob(CONFIG, &config.encode_to_vec())?;
+    info!(?config, "Updated the advertising config");
+    *CURRENT.lock().unwrap() = config;
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use eos::Timestamp;
+
+use crate::message::{self, Action, Category, Priority};
+
+/// A notification pushed from the phone, as shown on the watch
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub id: u32,
+    pub title: String,
+    pub body: String,
+    pub app_id: String,
+    pub sender: String,
+    pub timestamp: Option<eos::DateTime>,
+    pub category: Category,
+    pub priority: Priority,
+    pub actions: Vec<String>,
+    pub source: Source,
+}
+
+/// Where an alert came from, and so where the user's response goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    App,
+    /// An iPhone's Notification Center Service, which has no way of opening
+    /// anything
+    Ancs,
+}
+
+/// Something the user can do with an alert from the watch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Choice {
+    Open,
+    Custom(usize),
+    Dismiss,
+}
+
+impl From<message::PushNotification> for Alert {
+    fn from(notif: message::PushNotification) -> Self {
+        let category = notif.category();
+        let priority = notif.priority();
+
+        Self {
+            id: notif.id,
+            title: notif.title,
+            body: notif.body,
+            app_id: notif.app_id,
+            sender: notif.sender,
+            timestamp: notif
+                .timestamp
+                .map(|ts| Timestamp::new(ts.seconds, ts.nanos as u32).to_utc()),
+            category,
+            priority,
+            actions: notif.actions,
+            source: Source::App,
+        }
+    }
+}
+
+impl Alert {
+    /// Calls ring through regardless, anything else has to be at least normal
+    /// priority to turn the screen on.
+    pub fn should_wake(&self) -> bool {
+        self.category == Category::Call || urgency(self.priority) >= urgency(Priority::Normal)
+    }
+
+    /// How long the alert stays on screen before going back to the clock
+    pub fn display_for(&self) -> Duration {
+        if self.category == Category::Call {
+            return Duration::from_secs(30);
+        }
+
+        match self.priority {
+            Priority::Low => Duration::from_secs(5),
+            Priority::Normal => Duration::from_secs(10),
+            Priority::High => Duration::from_secs(20),
+            Priority::Urgent => Duration::from_secs(30),
+        }
+    }
+
+    /// The line shown above the body: the title, falling back to whoever sent it
+    pub fn heading(&self) -> &str {
+        [&self.title, &self.sender, &self.app_id]
+            .into_iter()
+            .find(|s| !s.is_empty())
+            .map(String::as_str)
+            .unwrap_or("")
+    }
+
+    /// Everything the user can pick from, in the order the front button
+    /// cycles through them
+    pub fn choices(&self) -> Vec<Choice> {
+        std::iter::once(Choice::Open)
+            .filter(|_| self.source == Source::App)
+            .chain((0..self.actions.len()).map(Choice::Custom))
+            .chain(std::iter::once(Choice::Dismiss))
+            .collect()
+    }
+
+    pub fn choice_label(&self, choice: Choice) -> &str {
+        match choice {
+            Choice::Open => "Open",
+            Choice::Custom(idx) => &self.actions[idx],
+            Choice::Dismiss => "Dismiss",
+        }
+    }
+
+    pub fn respond(&self, choice: Choice) -> message::Notification {
+        let (action, index) = match choice {
+            Choice::Open => (Action::Open, 0),
+            Choice::Custom(idx) => (Action::Custom, idx as u32),
+            Choice::Dismiss => (Action::Dismiss, 0),
+        };
+
+        let response = message::NotificationAction {
+            id: self.id,
+            action: action as i32,
+            index,
+            label: self.choice_label(choice).to_owned(),
+        };
+
+        message::Notification {
+            body: Some(message::notification::Body::NotificationAction(response)),
+        }
+    }
+}
+
+pub fn category_label(category: Category) -> &'static str {
+    match category {
+        Category::Other => "Notification",
+        Category::Message => "Message",
+        Category::Call => "Call",
+        Category::Calendar => "Calendar",
+        Category::Email => "Email",
+    }
+}
+
+/// Priorities ordered from least to most urgent
+fn urgency(priority: Priority) -> u8 {
+    match priority {
+        Priority::Low => 0,
+        Priority::Normal => 1,
+        Priority::High => 2,
+        Priority::Urgent => 3,
+    }
+}
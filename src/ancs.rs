@@ -0,0 +1,205 @@
+//! Parses and builds the messages of Apple's Notification Center Service, which
+//! iPhones expose so accessories can show their notifications without an app.
+
+use color_eyre::eyre::{bail, ensure};
+
+use crate::message::{self, Category, Priority};
+
+pub const SERVICE_UUID: uuid::Uuid = uuid::uuid!("7905f431-b5ce-4e99-a40f-4b1e122d00d0");
+pub const NOTIFICATION_SOURCE_UUID: uuid::Uuid =
+    uuid::uuid!("9fbf120d-6301-42d9-8c58-25e699a21dbd");
+pub const CONTROL_POINT_UUID: uuid::Uuid = uuid::uuid!("69d1d8f3-45e1-49a8-9821-9bbdfdaad9d9");
+pub const DATA_SOURCE_UUID: uuid::Uuid = uuid::uuid!("22eac6e9-24d6-4bb5-be44-b36ace7c7bfb");
+
+/// Set as the origin of the messages we push for iPhone notifications, so
+/// responses go back over ANCS rather than to the app
+pub const ORIGIN: u32 = u32::from_be_bytes(*b"ANCS");
+
+pub const NOTIFICATION_SOURCE_LEN: usize = 8;
+
+const FLAG_SILENT: u8 = 1 << 0;
+const FLAG_IMPORTANT: u8 = 1 << 1;
+const FLAG_PRE_EXISTING: u8 = 1 << 2;
+const FLAG_POSITIVE_ACTION: u8 = 1 << 3;
+
+const COMMAND_GET_NOTIFICATION_ATTRIBUTES: u8 = 0;
+const COMMAND_PERFORM_NOTIFICATION_ACTION: u8 = 2;
+
+const ATTRIBUTE_APP_IDENTIFIER: u8 = 0;
+const ATTRIBUTE_TITLE: u8 = 1;
+const ATTRIBUTE_MESSAGE: u8 = 3;
+const ATTRIBUTE_POSITIVE_ACTION_LABEL: u8 = 6;
+
+/// What we ask for, with the most bytes we want of the ones that need a
+/// limit. The screen can't show much more than this anyway.
+const REQUESTED_ATTRIBUTES: [(u8, Option<u16>); 4] = [
+    (ATTRIBUTE_APP_IDENTIFIER, None),
+    (ATTRIBUTE_TITLE, Some(64)),
+    (ATTRIBUTE_MESSAGE, Some(256)),
+    (ATTRIBUTE_POSITIVE_ACTION_LABEL, None),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Added,
+    Modified,
+    Removed,
+}
+
+/// One Notification Source notification, telling us something changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotificationSource {
+    pub event: Event,
+    pub flags: u8,
+    pub category: u8,
+    pub uid: u32,
+}
+
+impl NotificationSource {
+    /// The phone tells us about everything already in its notification centre
+    /// when we subscribe, which is old news
+    pub fn is_new(&self) -> bool {
+        self.event != Event::Removed && self.flags & FLAG_PRE_EXISTING == 0
+    }
+}
+
+pub fn parse_notification_source(buf: &[u8]) -> color_eyre::Result<NotificationSource> {
+    ensure!(
+        buf.len() >= NOTIFICATION_SOURCE_LEN,
+        "Notification Source is {} bytes, expected {}",
+        buf.len(),
+        NOTIFICATION_SOURCE_LEN
+    );
+
+    let event = match buf[0] {
+        0 => Event::Added,
+        1 => Event::Modified,
+        2 => Event::Removed,
+        id => bail!("Unknown Notification Source event {}", id),
+    };
+
+    Ok(NotificationSource {
+        event,
+        flags: buf[1],
+        category: buf[2],
+        // buf[3] is how many notifications there are in the category
+        uid: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+    })
+}
+
+/// The Control Point command asking for a notification's attributes
+pub fn get_notification_attributes(uid: u32) -> Vec<u8> {
+    let mut cmd = vec![COMMAND_GET_NOTIFICATION_ATTRIBUTES];
+    cmd.extend_from_slice(&uid.to_le_bytes());
+    for (id, max_len) in REQUESTED_ATTRIBUTES {
+        cmd.push(id);
+        if let Some(max_len) = max_len {
+            cmd.extend_from_slice(&max_len.to_le_bytes());
+        }
+    }
+    cmd
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Positive = 0,
+    Negative = 1,
+}
+
+/// The Control Point command performing one of a notification's actions
+pub fn perform_notification_action(uid: u32, action: Action) -> Vec<u8> {
+    let mut cmd = vec![COMMAND_PERFORM_NOTIFICATION_ACTION];
+    cmd.extend_from_slice(&uid.to_le_bytes());
+    cmd.push(action as u8);
+    cmd
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attributes {
+    pub uid: u32,
+    pub app_id: String,
+    pub title: String,
+    pub message: String,
+    pub positive_action: String,
+}
+
+/// Parses the Data Source response to [`get_notification_attributes`], which
+/// can be split over several notifications. Gives `None` until all of it has
+/// arrived.
+pub fn parse_attributes(buf: &[u8]) -> color_eyre::Result<Option<Attributes>> {
+    if buf.len() < 5 {
+        return Ok(None);
+    }
+    ensure!(
+        buf[0] == COMMAND_GET_NOTIFICATION_ATTRIBUTES,
+        "Data Source response is for command {}",
+        buf[0]
+    );
+
+    let mut attrs = Attributes {
+        uid: u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]),
+        ..Default::default()
+    };
+
+    // they come back in the order we asked for them
+    let mut rest = &buf[5..];
+    for _ in REQUESTED_ATTRIBUTES {
+        if rest.len() < 3 {
+            return Ok(None);
+        }
+        let len = u16::from_le_bytes([rest[1], rest[2]]) as usize;
+        let value = match rest.get(3..3 + len) {
+            Some(value) => String::from_utf8_lossy(value).into_owned(),
+            None => return Ok(None),
+        };
+
+        match rest[0] {
+            ATTRIBUTE_APP_IDENTIFIER => attrs.app_id = value,
+            ATTRIBUTE_TITLE => attrs.title = value,
+            ATTRIBUTE_MESSAGE => attrs.message = value,
+            ATTRIBUTE_POSITIVE_ACTION_LABEL => attrs.positive_action = value,
+            id => bail!("Unexpected attribute {} in Data Source response", id),
+        }
+        rest = &rest[3 + len..];
+    }
+
+    Ok(Some(attrs))
+}
+
+/// The notification as if the app had pushed it. The positive action is the
+/// only custom one, the negative action is what dismissing it does.
+pub fn push_notification(
+    source: &NotificationSource,
+    attrs: Attributes,
+) -> message::PushNotification {
+    let category = match source.category {
+        1 => Category::Call,
+        4 => Category::Message,
+        5 => Category::Calendar,
+        6 => Category::Email,
+        _ => Category::Other,
+    };
+    let priority = if source.flags & FLAG_IMPORTANT != 0 {
+        Priority::High
+    } else if source.flags & FLAG_SILENT != 0 {
+        Priority::Low
+    } else {
+        Priority::Normal
+    };
+
+    let mut actions = Vec::new();
+    if source.flags & FLAG_POSITIVE_ACTION != 0 && !attrs.positive_action.is_empty() {
+        actions.push(attrs.positive_action);
+    }
+
+    message::PushNotification {
+        body: attrs.message,
+        title: attrs.title,
+        app_id: attrs.app_id,
+        category: category as i32,
+        priority: priority as i32,
+        actions,
+        id: source.uid,
+        ..Default::default()
+    }
+}
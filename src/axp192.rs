@@ -1,14 +1,40 @@
 #![allow(dead_code)]
 
-use std::sync::atomic::AtomicU8;
+use std::sync::atomic::{AtomicU32, AtomicU8};
+use std::sync::Mutex;
 use std::thread::JoinHandle;
 use std::time::Duration;
 
+use crossbeam::channel;
 use embedded_hal::i2c::blocking::I2c;
+use once_cell::sync::Lazy;
 
 use crate::utils::I2c0;
 
 pub static BATTERY_PERCENT: AtomicU8 = AtomicU8::new(0);
+/// From the last reading, charging power while plugged in and 0 before the
+/// first reading
+pub static BATTERY_POWER_MW: AtomicU32 = AtomicU32::new(0);
+pub static POWER_STATE: Mutex<PowerState> = Mutex::new(PowerState {
+    battery_present: false,
+    vbus_present: false,
+    charging: false,
+});
+
+/// Poked by the battery thread whenever the level or power state changes
+static BATTERY_CHANGES: Lazy<(channel::Sender<()>, channel::Receiver<()>)> =
+    Lazy::new(|| channel::bounded(1));
+
+pub fn battery_changes() -> channel::Receiver<()> {
+    BATTERY_CHANGES.1.clone()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerState {
+    pub battery_present: bool,
+    pub vbus_present: bool,
+    pub charging: bool,
+}
 
 #[derive(Clone)]
 pub struct Axp192 {
@@ -17,7 +43,10 @@ pub struct Axp192 {
 
 const ADDR: u8 = 0x34;
 const POWER_STATUS: u8 = 0x00;
+const POWER_STATUS_VBUS_PRESENT: u8 = 0b0010_0000;
 const MODE_CHARGING_STATUS: u8 = 0x01;
+const MODE_CHARGING_STATUS_CHARGING: u8 = 0b0100_0000;
+const MODE_CHARGING_STATUS_BATT_PRESENT: u8 = 0b0010_0000;
 
 const EXTEN_DCDC2_CTRL: u8 = 0x10;
 const EXTEN_DCDC2_CTRL_EXTEN: u8 = 0b0000_0100;
@@ -210,14 +239,41 @@ impl Axp192 {
     pub fn start_battery_thread(&self) -> JoinHandle<()> {
         let this = self.clone();
         std::thread::spawn(move || loop {
+            let mut changed = false;
+
             if let Ok(pct) = this.get_batt_pct() {
-                BATTERY_PERCENT.store(pct, std::sync::atomic::Ordering::Relaxed);
+                let old = BATTERY_PERCENT.swap(pct, std::sync::atomic::Ordering::Relaxed);
+                changed |= old != pct;
+            }
+
+            if let Ok(power) = this.get_batt_power() {
+                BATTERY_POWER_MW.store(power as u32, std::sync::atomic::Ordering::Relaxed);
+            }
+
+            if let Ok(state) = this.get_power_state() {
+                let old = std::mem::replace(&mut *POWER_STATE.lock().unwrap(), state);
+                changed |= old != state;
+            }
+
+            if changed {
+                let _ = BATTERY_CHANGES.0.try_send(());
             }
 
             std::thread::sleep(Duration::from_secs(10));
         })
     }
 
+    pub fn get_power_state(&self) -> color_eyre::Result<PowerState> {
+        let power = self.read(POWER_STATUS)?;
+        let charging = self.read(MODE_CHARGING_STATUS)?;
+
+        Ok(PowerState {
+            battery_present: charging & MODE_CHARGING_STATUS_BATT_PRESENT != 0,
+            vbus_present: power & POWER_STATUS_VBUS_PRESENT != 0,
+            charging: charging & MODE_CHARGING_STATUS_CHARGING != 0,
+        })
+    }
+
     pub fn get_batt_pct(&self) -> color_eyre::Result<u8> {
         let batt_volt = self.get_batt_voltage()?;
         let batt_pct = (batt_volt.clamp(3.0, 4.2) - 3.0) / (4.2 - 3.0);
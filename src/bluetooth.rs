@@ -1,51 +1,120 @@
 use core::ffi::{c_char, c_int};
-use std::cell::UnsafeCell;
-use std::ffi::{c_void, CStr};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{c_void, CStr, CString};
 use std::ptr::null_mut;
-use std::sync::atomic::AtomicU8;
-use std::sync::{Mutex, Arc, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::Mutex;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use color_eyre::eyre::eyre;
 use crossbeam::channel;
 use esp_idf_svc::eventloop::EspEventFetchData;
 use esp_idf_sys::{
-    ble_gap_adv_params, ble_gap_adv_set_fields, ble_gap_adv_start, ble_gap_conn_desc,
-    ble_gap_conn_find, ble_gap_event, ble_gatt_access_ctxt, ble_gatt_chr_def,
-    ble_gatt_register_ctxt, ble_gatt_svc_def, ble_gattc_notify_custom, ble_gatts_add_svcs,
-    ble_gatts_count_cfg, ble_hs_adv_fields, ble_hs_cfg, ble_hs_id_copy_addr, ble_hs_id_infer_auto,
-    ble_hs_mbuf_from_flat, ble_hs_mbuf_to_flat, ble_hs_util_ensure_addr, ble_store_util_status_rr,
-    ble_uuid128_t, ble_uuid16_t, ble_uuid_cmp, ble_uuid_t, ble_uuid_to_str, esp,
-    esp_nimble_hci_and_controller_deinit, esp_nimble_hci_and_controller_init,
+    ble_addr_t, ble_error_codes_BLE_ERR_AUTH_FAIL, ble_error_codes_BLE_ERR_CONN_TERM_LOCAL,
+    ble_gap_adv_active, ble_gap_adv_params, ble_gap_adv_rsp_set_data, ble_gap_adv_set_fields,
+    ble_gap_adv_start, ble_gap_adv_stop, ble_gap_conn_desc, ble_gap_conn_find, ble_gap_event,
+    ble_gap_security_initiate, ble_gap_set_data_len, ble_gap_terminate, ble_gap_upd_params,
+    ble_gap_update_params, ble_gap_wl_set, ble_gatt_access_ctxt, ble_gatt_attr, ble_gatt_chr,
+    ble_gatt_chr_def, ble_gatt_dsc, ble_gatt_dsc_def, ble_gatt_error, ble_gatt_register_ctxt,
+    ble_gatt_svc, ble_gatt_svc_def, ble_gattc_disc_all_chrs, ble_gattc_disc_all_dscs,
+    ble_gattc_disc_svc_by_uuid, ble_gattc_exchange_mtu, ble_gattc_indicate_custom,
+    ble_gattc_notify_custom, ble_gattc_read, ble_gattc_write_flat, ble_gatts_add_svcs,
+    ble_gatts_count_cfg, ble_gatts_reset, ble_hs_adv_fields, ble_hs_adv_set_fields, ble_hs_cfg,
+    ble_hs_id_copy_addr, ble_hs_id_infer_auto, ble_hs_mbuf_from_flat, ble_hs_mbuf_to_flat,
+    ble_hs_stop, ble_hs_stop_listener, ble_hs_util_ensure_addr, ble_sm_inject_io, ble_sm_io,
+    ble_sm_io__bindgen_ty_1, ble_store_util_status_rr, ble_uuid128_t, ble_uuid16_t, ble_uuid_cmp,
+    ble_uuid_t, ble_uuid_to_str, ble_uuid_u16, esp, esp_nimble_hci_and_controller_deinit,
+    esp_nimble_hci_and_controller_init, esp_random, nimble_port_deinit,
     nimble_port_freertos_deinit, nimble_port_freertos_init, nimble_port_init, nimble_port_run,
-    os_mbuf, os_mbuf_append, strlen, BLE_ATT_ERR_INSUFFICIENT_RES, BLE_GAP_CONN_MODE_UND,
-    BLE_GAP_DISC_MODE_GEN, BLE_GAP_EVENT_ADV_COMPLETE, BLE_GAP_EVENT_CONNECT,
-    BLE_GAP_EVENT_CONN_UPDATE, BLE_GAP_EVENT_DISCONNECT, BLE_GAP_EVENT_MTU,
-    BLE_GATT_ACCESS_OP_READ_CHR, BLE_GATT_ACCESS_OP_WRITE_CHR, BLE_GATT_CHR_F_NOTIFY,
-    BLE_GATT_CHR_F_READ, BLE_GATT_CHR_F_WRITE, BLE_GATT_REGISTER_OP_CHR, BLE_GATT_REGISTER_OP_DSC,
-    BLE_GATT_REGISTER_OP_SVC, BLE_GATT_SVC_TYPE_PRIMARY, BLE_HS_ADV_F_BREDR_UNSUP,
-    BLE_HS_ADV_F_DISC_GEN, BLE_HS_ADV_TX_PWR_LVL_AUTO, BLE_UUID_STR_LEN, BLE_UUID_TYPE_128,
-    BLE_UUID_TYPE_16, CONFIG_BT_NIMBLE_MAX_CONNECTIONS, ble_hs_stop, ble_hs_stop_listener, nimble_port_stop, nimble_port_deinit,
+    nimble_port_stop, os_mbuf, os_mbuf_append, BLE_ATT_ERR_INSUFFICIENT_RES,
+    BLE_ATT_ERR_INVALID_ATTR_VALUE_LEN, BLE_ATT_ERR_READ_NOT_PERMITTED, BLE_ATT_ERR_UNLIKELY,
+    BLE_ATT_ERR_WRITE_NOT_PERMITTED, BLE_ATT_F_READ, BLE_ATT_F_READ_ENC, BLE_ATT_F_WRITE,
+    BLE_ATT_F_WRITE_ENC, BLE_GAP_CONN_MODE_DIR, BLE_GAP_CONN_MODE_UND, BLE_GAP_DISC_MODE_GEN,
+    BLE_GAP_EVENT_ADV_COMPLETE, BLE_GAP_EVENT_CONNECT, BLE_GAP_EVENT_CONN_UPDATE,
+    BLE_GAP_EVENT_DISCONNECT, BLE_GAP_EVENT_ENC_CHANGE, BLE_GAP_EVENT_MTU, BLE_GAP_EVENT_NOTIFY_RX,
+    BLE_GAP_EVENT_NOTIFY_TX, BLE_GAP_EVENT_PASSKEY_ACTION, BLE_GAP_EVENT_SUBSCRIBE,
+    BLE_GATT_ACCESS_OP_READ_CHR, BLE_GATT_ACCESS_OP_READ_DSC, BLE_GATT_ACCESS_OP_WRITE_CHR,
+    BLE_GATT_ACCESS_OP_WRITE_DSC, BLE_GATT_CHR_F_INDICATE, BLE_GATT_CHR_F_NOTIFY,
+    BLE_GATT_CHR_F_READ, BLE_GATT_CHR_F_READ_ENC, BLE_GATT_CHR_F_WRITE, BLE_GATT_CHR_F_WRITE_ENC,
+    BLE_GATT_CHR_F_WRITE_NO_RSP, BLE_GATT_REGISTER_OP_CHR, BLE_GATT_REGISTER_OP_DSC,
+    BLE_GATT_REGISTER_OP_SVC, BLE_GATT_SVC_TYPE_PRIMARY, BLE_HCI_ADV_FILT_CONN,
+    BLE_HS_ADV_F_BREDR_UNSUP, BLE_HS_ADV_F_DISC_GEN, BLE_HS_ADV_MAX_SZ, BLE_HS_ADV_TX_PWR_LVL_AUTO,
+    BLE_HS_ADV_TYPE_SOL_UUIDS128, BLE_HS_EDONE, BLE_HS_ENOMEM, BLE_HS_ERR_HCI_BASE, BLE_HS_FOREVER,
+    BLE_HS_IO_DISPLAY_ONLY, BLE_OWN_ADDR_PUBLIC, BLE_OWN_ADDR_RPA_PUBLIC_DEFAULT,
+    BLE_OWN_ADDR_RPA_RANDOM_DEFAULT, BLE_SM_IOACT_DISP, BLE_SM_PAIR_KEY_DIST_ENC,
+    BLE_SM_PAIR_KEY_DIST_ID, BLE_UUID_STR_LEN, BLE_UUID_TYPE_128, BLE_UUID_TYPE_16,
 };
 use once_cell::sync::{Lazy, OnceCell};
-use prost::Message;
-use tracing::{error, info};
+use tracing::{debug, error, info};
+
+use crate::axp192::{BATTERY_PERCENT, POWER_STATE};
+use crate::conn_params::{self, Profile};
+use crate::connections::{self, Connection, DEFAULT_MTU};
+use crate::framing::Link;
+use crate::message::AdvertisingField;
+#[cfg(feature = "nus")]
+use crate::nus;
+use crate::outbox::{self, Cursors, Outbox};
+use crate::reconnect::{Reconnect, Step};
+use crate::{
+    advertising, ancs, axp192, bonds, cts, delivery, device_info, framing, hid, message, ota,
+    protocol, storage,
+};
+
+/// Encoded notifications on their way to the outbox
+static OUTBOUND: Lazy<(channel::Sender<Vec<u8>>, channel::Receiver<Vec<u8>>)> =
+    Lazy::new(|| channel::bounded(4));
+
+/// Notifications sent while the phone isn't subscribed are held in the outbox
+/// until it is.
+struct BleTransport;
+
+impl BleTransport {
+    const NAME: &'static str = "ble";
+}
 
-use crate::axp192::BATTERY_PERCENT;
-use crate::message;
+impl protocol::Transport for BleTransport {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
 
-pub static QUEUE: Lazy<(
-    channel::Sender<message::Notification>,
-    channel::Receiver<message::Notification>,
-)> = Lazy::new(|| channel::bounded(4));
+    fn send(&mut self, payload: &[u8]) -> color_eyre::Result<()> {
+        OUTBOUND.0.try_send(payload.to_vec()).map_err(|_| {
+            delivery::dropped();
+            eyre!("BLE outbound queue full")
+        })
+    }
+}
 
 static TX_THREAD: OnceCell<JoinHandle<()>> = OnceCell::new();
+static BATTERY_THREAD: OnceCell<JoinHandle<()>> = OnceCell::new();
+static CONN_PARAMS_THREAD: OnceCell<JoinHandle<()>> = OnceCell::new();
 
-static CONN_COUNT: AtomicU8 = AtomicU8::new(0);
+/// Poked when someone subscribes so the tx thread flushes the outbox
+static FLUSH: Lazy<(channel::Sender<()>, channel::Receiver<()>)> =
+    Lazy::new(|| channel::bounded(1));
+
+/// Has the tx thread look at the outbox again, e.g. after its config changed
+pub fn poke_outbox() {
+    let _ = FLUSH.0.try_send(());
+}
+
+/// The largest value an attribute can hold, and so the largest single write
+const MAX_ATTR_LEN: usize = 512;
+/// Opcode and attribute handle of a notification or write
+const ATT_HEADER_LEN: u16 = 3;
+
+const MAX_INBOUND_LEN: usize = 16 * 1024;
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A reassembler for each connection that's written part of a message, so
+/// phones writing at the same time don't trample each other's fragments
+static REASSEMBLERS: Lazy<Mutex<HashMap<u16, framing::Reassembler>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
 pub fn ble_connected() -> bool {
-    let n = CONN_COUNT.load(std::sync::atomic::Ordering::Relaxed);
+    let n = connections::count();
     info!(n, "Ongoing bluetooth connections");
     n > 0
 }
@@ -66,417 +135,2948 @@ const fn inv(
     ]
 }
 
-static mut BLE_BAT_SERVICE: ble_uuid16_t = ble_uuid16_t {
+/// A 16 bit SIG assigned UUID or a full 128 bit one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BleUuid {
+    Uuid16(u16),
+    Uuid128(uuid::Uuid),
+}
+
+impl From<u16> for BleUuid {
+    fn from(uuid: u16) -> Self {
+        BleUuid::Uuid16(uuid)
+    }
+}
+
+impl From<uuid::Uuid> for BleUuid {
+    fn from(uuid: uuid::Uuid) -> Self {
+        BleUuid::Uuid128(uuid)
+    }
+}
+
+impl BleUuid {
+    /// NimBLE keeps pointers to the UUIDs in the GATT tables, so they live
+    /// forever
+    fn leak(self) -> *const ble_uuid_t {
+        match self {
+            BleUuid::Uuid16(value) => {
+                &Box::leak(Box::new(ble_uuid16_t {
+                    u: BLE_UUID_TYPE_16_,
+                    value,
+                }))
+                .u
+            }
+            BleUuid::Uuid128(uuid) => {
+                &Box::leak(Box::new(ble_uuid128_t {
+                    u: BLE_UUID_TYPE_128_,
+                    value: inv(*uuid.as_bytes()),
+                }))
+                .u
+            }
+        }
+    }
+}
+
+/// What a peer may do with an attribute. The `_ENC` flags additionally
+/// require an encrypted link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flags(u16);
+
+impl Flags {
+    pub const READ: Flags = Flags(BLE_GATT_CHR_F_READ as u16);
+    pub const READ_ENC: Flags = Flags(BLE_GATT_CHR_F_READ_ENC as u16);
+    pub const WRITE: Flags = Flags(BLE_GATT_CHR_F_WRITE as u16);
+    pub const WRITE_NO_RSP: Flags = Flags(BLE_GATT_CHR_F_WRITE_NO_RSP as u16);
+    pub const WRITE_ENC: Flags = Flags(BLE_GATT_CHR_F_WRITE_ENC as u16);
+    pub const NOTIFY: Flags = Flags(BLE_GATT_CHR_F_NOTIFY as u16);
+    pub const INDICATE: Flags = Flags(BLE_GATT_CHR_F_INDICATE as u16);
+
+    fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Descriptors take ATT permissions rather than characteristic properties
+    fn att_flags(self) -> u8 {
+        [
+            (Flags::READ, BLE_ATT_F_READ),
+            (Flags::READ_ENC, BLE_ATT_F_READ_ENC),
+            (Flags::WRITE, BLE_ATT_F_WRITE),
+            (Flags::WRITE_ENC, BLE_ATT_F_WRITE_ENC),
+        ]
+        .into_iter()
+        .filter(|&(flag, _)| self.contains(flag))
+        .fold(0, |acc, (_, att_flag)| acc | att_flag as u8)
+    }
+}
+
+impl std::ops::BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, rhs: Flags) -> Flags {
+        Flags(self.0 | rhs.0)
+    }
+}
+
+/// The value handle of a characteristic, filled in once the GATT server has
+/// been registered with the host
+pub struct AttrHandle(AtomicU16);
+
+impl AttrHandle {
+    pub const fn new() -> Self {
+        Self(AtomicU16::new(0))
+    }
+
+    pub fn get(&self) -> u16 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, handle: u16) {
+        self.0.store(handle, Ordering::Relaxed)
+    }
+}
+
+impl Default for AttrHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Given the connection reading, gives the value
+type ReadFn = Box<dyn Fn(u16) -> Vec<u8> + Send + Sync>;
+/// Given the connection writing and what it wrote
+type WriteFn = Box<dyn Fn(u16, &[u8]) + Send + Sync>;
+/// Given the connection and whether it's now subscribed
+type SubscribeFn = Box<dyn Fn(u16, bool) + Send + Sync>;
+
+/// What runs when a peer touches an attribute, pointed to by the attribute's
+/// `arg` in the NimBLE tables
+#[derive(Default)]
+struct Callbacks {
+    read: Option<ReadFn>,
+    write: Option<WriteFn>,
+    subscribe: Option<SubscribeFn>,
+    val_handle: AttrHandle,
+    handle: Option<&'static AttrHandle>,
+}
+
+pub struct Descriptor {
+    uuid: BleUuid,
+    flags: Flags,
+    callbacks: Callbacks,
+}
+
+impl Descriptor {
+    pub fn new(uuid: impl Into<BleUuid>, flags: Flags) -> Self {
+        Self {
+            uuid: uuid.into(),
+            flags,
+            callbacks: Callbacks::default(),
+        }
+    }
+
+    pub fn on_read(mut self, f: impl Fn(u16) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        self.callbacks.read = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_write(mut self, f: impl Fn(u16, &[u8]) + Send + Sync + 'static) -> Self {
+        self.callbacks.write = Some(Box::new(f));
+        self
+    }
+}
+
+pub struct Characteristic {
+    uuid: BleUuid,
+    flags: Flags,
+    callbacks: Callbacks,
+    descriptors: Vec<Descriptor>,
+}
+
+impl Characteristic {
+    pub fn new(uuid: impl Into<BleUuid>, flags: Flags) -> Self {
+        Self {
+            uuid: uuid.into(),
+            flags,
+            callbacks: Callbacks::default(),
+            descriptors: Vec::new(),
+        }
+    }
+
+    pub fn on_read(mut self, f: impl Fn(u16) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        self.callbacks.read = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_write(mut self, f: impl Fn(u16, &[u8]) + Send + Sync + 'static) -> Self {
+        self.callbacks.write = Some(Box::new(f));
+        self
+    }
+
+    /// Called when a peer turns notifications on or off
+    pub fn on_subscribe(mut self, f: impl Fn(u16, bool) + Send + Sync + 'static) -> Self {
+        self.callbacks.subscribe = Some(Box::new(f));
+        self
+    }
+
+    /// Where to put the value handle, for notifying
+    pub fn handle(mut self, handle: &'static AttrHandle) -> Self {
+        self.callbacks.handle = Some(handle);
+        self
+    }
+
+    pub fn descriptor(mut self, descriptor: Descriptor) -> Self {
+        self.descriptors.push(descriptor);
+        self
+    }
+}
+
+pub struct Service {
+    uuid: BleUuid,
+    characteristics: Vec<Characteristic>,
+}
+
+impl Service {
+    pub fn new(uuid: impl Into<BleUuid>) -> Self {
+        Self {
+            uuid: uuid.into(),
+            characteristics: Vec::new(),
+        }
+    }
+
+    pub fn characteristic(mut self, characteristic: Characteristic) -> Self {
+        self.characteristics.push(characteristic);
+        self
+    }
+}
+
+/// Collects the services to expose, then turns them into NimBLE's tables
+#[derive(Default)]
+pub struct GattBuilder {
+    services: Vec<Service>,
+}
+
+impl GattBuilder {
+    pub fn service(mut self, service: Service) -> Self {
+        self.services.push(service);
+        self
+    }
+
+    /// Leaks everything, since NimBLE holds on to the tables for as long as
+    /// the host is up and we might bring it up again later
+    pub fn build(self) -> Gatt {
+        let mut characteristics = Vec::new();
+
+        let mut svc_defs: Vec<_> = self
+            .services
+            .into_iter()
+            .map(|svc| {
+                let mut chr_defs: Vec<_> = svc
+                    .characteristics
+                    .into_iter()
+                    .map(|chr| {
+                        let callbacks: &'static Callbacks = Box::leak(Box::new(chr.callbacks));
+                        characteristics.push(callbacks);
+
+                        ble_gatt_chr_def {
+                            uuid: chr.uuid.leak(),
+                            access_cb: Some(gatt_access),
+                            arg: callbacks as *const Callbacks as *mut c_void,
+                            descriptors: Self::descriptors(chr.descriptors),
+                            flags: chr.flags.0,
+                            min_key_size: 0,
+                            val_handle: null_mut(),
+                        }
+                    })
+                    .collect();
+                chr_defs.push(ble_gatt_chr_def::default());
+
+                ble_gatt_svc_def {
+                    type_: BLE_GATT_SVC_TYPE_PRIMARY as u8,
+                    uuid: svc.uuid.leak(),
+                    includes: null_mut(),
+                    characteristics: chr_defs.leak().as_ptr(),
+                }
+            })
+            .collect();
+        svc_defs.push(ble_gatt_svc_def::default());
+
+        Gatt {
+            services: svc_defs.leak().as_ptr(),
+            characteristics,
+        }
+    }
+
+    fn descriptors(descriptors: Vec<Descriptor>) -> *mut ble_gatt_dsc_def {
+        if descriptors.is_empty() {
+            return null_mut();
+        }
+
+        let mut dsc_defs: Vec<_> = descriptors
+            .into_iter()
+            .map(|dsc| ble_gatt_dsc_def {
+                uuid: dsc.uuid.leak(),
+                att_flags: dsc.flags.att_flags(),
+                min_key_size: 0,
+                access_cb: Some(gatt_access),
+                arg: Box::leak(Box::new(dsc.callbacks)) as *const Callbacks as *mut c_void,
+            })
+            .collect();
+        dsc_defs.push(ble_gatt_dsc_def::default());
+
+        dsc_defs.leak().as_mut_ptr()
+    }
+}
+
+/// The built GATT tables
+pub struct Gatt {
+    services: *const ble_gatt_svc_def,
+    characteristics: Vec<&'static Callbacks>,
+}
+
+// the tables are never written to after being built
+unsafe impl Send for Gatt {}
+unsafe impl Sync for Gatt {}
+
+impl Gatt {
+    pub fn builder() -> GattBuilder {
+        GattBuilder::default()
+    }
+
+    fn register(&self) -> color_eyre::Result<()> {
+        unsafe {
+            esp!(ble_gatts_count_cfg(self.services))?;
+            esp!(ble_gatts_add_svcs(self.services))?;
+        }
+
+        Ok(())
+    }
+
+    fn registered(&self, arg: *mut c_void, val_handle: u16) {
+        let callbacks = self
+            .characteristics
+            .iter()
+            .find(|&&callbacks| std::ptr::eq(callbacks, arg as *const Callbacks));
+        if let Some(callbacks) = callbacks {
+            callbacks.val_handle.set(val_handle);
+            if let Some(handle) = callbacks.handle {
+                handle.set(val_handle);
+            }
+        }
+    }
+
+    fn subscribed(&self, conn_handle: u16, attr_handle: u16, subscribed: bool) {
+        let callbacks = self
+            .characteristics
+            .iter()
+            .find(|callbacks| callbacks.val_handle.get() == attr_handle);
+        if let Some(subscribe) = callbacks.and_then(|callbacks| callbacks.subscribe.as_ref()) {
+            subscribe(conn_handle, subscribed);
+        }
+    }
+}
+
+/// Every attribute we expose is accessed through here, and handed to the
+/// callbacks it was built with
+unsafe extern "C" fn gatt_access(
+    conn_handle: u16,
+    attr_handle: u16,
+    ctxt: *mut ble_gatt_access_ctxt,
+    arg: *mut c_void,
+) -> i32 {
+    let ctxt_ = *ctxt;
+    let callbacks = &*(arg as *const Callbacks);
+
+    match ctxt_.op as u32 {
+        BLE_GATT_ACCESS_OP_READ_CHR | BLE_GATT_ACCESS_OP_READ_DSC => {
+            let value = match &callbacks.read {
+                Some(read) => read(conn_handle),
+                None => return BLE_ATT_ERR_READ_NOT_PERMITTED as i32,
+            };
+
+            let rc = os_mbuf_append(ctxt_.om, value.as_ptr() as *const _, value.len() as u16);
+            if rc != 0 {
+                return BLE_ATT_ERR_INSUFFICIENT_RES as i32;
+            }
+        }
+        BLE_GATT_ACCESS_OP_WRITE_CHR | BLE_GATT_ACCESS_OP_WRITE_DSC => {
+            let write = match &callbacks.write {
+                Some(write) => write,
+                None => return BLE_ATT_ERR_WRITE_NOT_PERMITTED as i32,
+            };
+
+            let mut buf = [0u8; MAX_ATTR_LEN];
+            let mut out_len = 0u16;
+            let rc = ble_hs_mbuf_to_flat(
+                ctxt_.om,
+                &mut buf as *mut u8 as *mut _,
+                MAX_ATTR_LEN as u16,
+                &mut out_len as *mut _,
+            );
+            if rc != 0 {
+                error!(attr_handle, "Couldn't fetch mbuf in write handler");
+                return BLE_ATT_ERR_INVALID_ATTR_VALUE_LEN as i32;
+            }
+
+            write(conn_handle, &buf[..out_len as usize]);
+        }
+        op => {
+            error!(op, attr_handle, "Unexpected GATT access");
+            return BLE_ATT_ERR_UNLIKELY as i32;
+        }
+    }
+
+    0
+}
+
+const BLE_BAT_SERVICE: u16 = 0x180F;
+const BLE_BAT_CHAR: u16 = 0x2A19;
+const BLE_BAT_LEVEL_STATUS_CHAR: u16 = 0x2BED;
+
+const BLE_DIS_SERVICE: u16 = 0x180A;
+const BLE_DIS_MANUFACTURER_CHAR: u16 = 0x2A29;
+const BLE_DIS_MODEL_NUMBER_CHAR: u16 = 0x2A24;
+const BLE_DIS_SERIAL_NUMBER_CHAR: u16 = 0x2A25;
+const BLE_DIS_HARDWARE_REVISION_CHAR: u16 = 0x2A27;
+const BLE_DIS_FIRMWARE_REVISION_CHAR: u16 = 0x2A26;
+
+static mut BLE_CTS_SERVICE: ble_uuid16_t = ble_uuid16_t {
     u: BLE_UUID_TYPE_16_,
-    value: 0x180f,
+    value: 0x1805,
 };
 
-static mut BLE_BAT_CHAR: ble_uuid16_t = ble_uuid16_t {
-    u: BLE_UUID_TYPE_16_,
-    value: 0x2A19,
+const BLE_CTS_CURRENT_TIME_CHAR: u16 = 0x2A2B;
+const BLE_CTS_LOCAL_TIME_INFO_CHAR: u16 = 0x2A0F;
+const BLE_CCCD: u16 = 0x2902;
+
+static mut BLE_ANCS_SERVICE: ble_uuid128_t = ble_uuid128_t {
+    u: BLE_UUID_TYPE_128_,
+    value: inv(*ancs::SERVICE_UUID.as_bytes()),
 };
 
-static mut BLE_DATA_IN_SERVICE: ble_uuid128_t = ble_uuid128_t {
+static mut BLE_ANCS_NOTIFICATION_SOURCE_CHAR: ble_uuid128_t = ble_uuid128_t {
     u: BLE_UUID_TYPE_128_,
-    value: inv(*uuid::uuid!("98200001-2160-4474-82b4-1a25cef92156").as_bytes()),
+    value: inv(*ancs::NOTIFICATION_SOURCE_UUID.as_bytes()),
 };
 
-static mut BLE_DATA_IN_CHAR: ble_uuid128_t = ble_uuid128_t {
+static mut BLE_ANCS_CONTROL_POINT_CHAR: ble_uuid128_t = ble_uuid128_t {
     u: BLE_UUID_TYPE_128_,
-    value: inv(*uuid::uuid!("98200002-2160-4474-82b4-1a25cef92156").as_bytes()),
+    value: inv(*ancs::CONTROL_POINT_UUID.as_bytes()),
 };
 
-static mut BLE_DATA_OUT_CHAR: ble_uuid128_t = ble_uuid128_t {
+static mut BLE_ANCS_DATA_SOURCE_CHAR: ble_uuid128_t = ble_uuid128_t {
     u: BLE_UUID_TYPE_128_,
-    value: inv(*uuid::uuid!("98200003-2160-4474-82b4-1a25cef92156").as_bytes()),
+    value: inv(*ancs::DATA_SOURCE_UUID.as_bytes()),
 };
 
-static mut BLE_DATA_IN_HANDLE: UnsafeCell<u16> = UnsafeCell::new(0);
-static mut BLE_DATA_OUT_HANDLE: UnsafeCell<u16> = UnsafeCell::new(0);
-static mut BLE_LE_BAT_CHAR_HANDLE: UnsafeCell<u16> = UnsafeCell::new(0);
+const BLE_HID_SERVICE: u16 = 0x1812;
+const BLE_HID_INFORMATION_CHAR: u16 = 0x2A4A;
+const BLE_HID_REPORT_MAP_CHAR: u16 = 0x2A4B;
+const BLE_HID_CONTROL_POINT_CHAR: u16 = 0x2A4C;
+const BLE_HID_REPORT_CHAR: u16 = 0x2A4D;
+const BLE_HID_REPORT_REFERENCE_DSC: u16 = 0x2908;
+
+/// Report ID and type (1 = input) for each report characteristic
+const HID_KEYBOARD_REPORT_REFERENCE: [u8; 2] = [hid::KEYBOARD_REPORT_ID, 1];
+const HID_CONSUMER_REPORT_REFERENCE: [u8; 2] = [hid::CONSUMER_REPORT_ID, 1];
+
+/// Shown by hosts picking something to pair with while in a HID mode
+const HID_APPEARANCE_KEYBOARD: u16 = 0x03C1;
+
+/// The service the phone talks to us through
+pub const DATA_SERVICE_UUID: uuid::Uuid = uuid::uuid!("98200001-2160-4474-82b4-1a25cef92156");
+const BLE_DATA_IN_CHAR: uuid::Uuid = uuid::uuid!("98200002-2160-4474-82b4-1a25cef92156");
+const BLE_DATA_OUT_CHAR: uuid::Uuid = uuid::uuid!("98200003-2160-4474-82b4-1a25cef92156");
+
+const BLE_DFU_SERVICE: uuid::Uuid = uuid::uuid!("98200010-2160-4474-82b4-1a25cef92156");
+const BLE_DFU_CONTROL_POINT_CHAR: uuid::Uuid = uuid::uuid!("98200011-2160-4474-82b4-1a25cef92156");
+const BLE_DFU_DATA_CHAR: uuid::Uuid = uuid::uuid!("98200012-2160-4474-82b4-1a25cef92156");
+
+#[cfg(feature = "nus")]
+const BLE_NUS_SERVICE: uuid::Uuid = uuid::uuid!("6e400001-b5a3-f393-e0a9-e50e24dcca9e");
+#[cfg(feature = "nus")]
+const BLE_NUS_RX_CHAR: uuid::Uuid = uuid::uuid!("6e400002-b5a3-f393-e0a9-e50e24dcca9e");
+#[cfg(feature = "nus")]
+const BLE_NUS_TX_CHAR: uuid::Uuid = uuid::uuid!("6e400003-b5a3-f393-e0a9-e50e24dcca9e");
+
+static BLE_DATA_OUT_HANDLE: AttrHandle = AttrHandle::new();
+static BLE_LE_BAT_CHAR_HANDLE: AttrHandle = AttrHandle::new();
+static BLE_BAT_LEVEL_STATUS_HANDLE: AttrHandle = AttrHandle::new();
+#[cfg(feature = "nus")]
+static BLE_NUS_TX_HANDLE: AttrHandle = AttrHandle::new();
+static BLE_HID_KEYBOARD_HANDLE: AttrHandle = AttrHandle::new();
+static BLE_HID_CONSUMER_HANDLE: AttrHandle = AttrHandle::new();
+static BLE_DFU_CONTROL_POINT_HANDLE: AttrHandle = AttrHandle::new();
+
+/// The tables without and with the HID service, each built the first time
+/// it's needed
+static GATT: OnceCell<Gatt> = OnceCell::new();
+static HID_GATT: OnceCell<Gatt> = OnceCell::new();
+
+/// Whether the tables the host was last started with have the HID service,
+/// `None` before it's been started
+static HID_REGISTERED: Mutex<Option<bool>> = Mutex::new(None);
+
+/// Set when the host is started with different tables to last time, so the
+/// phones that cached the old ones are told to look again once it syncs
+static GATT_CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// The tables the host was last started with
+fn gatt() -> Option<&'static Gatt> {
+    match *HID_REGISTERED.lock().unwrap() {
+        Some(true) => HID_GATT.get(),
+        Some(false) => GATT.get(),
+        None => None,
+    }
+}
 
-static mut GATT_SERVICES: [esp_idf_sys::ble_gatt_svc_def; 3] = unsafe {
-    [
-        ble_gatt_svc_def {
-            type_: BLE_GATT_SVC_TYPE_PRIMARY as u8,
-            uuid: &BLE_BAT_SERVICE.u,
-            characteristics: &[
-                ble_gatt_chr_def {
-                    uuid: &BLE_BAT_CHAR.u,
-                    access_cb: Some(ble_batt_handler),
-                    val_handle: BLE_LE_BAT_CHAR_HANDLE.get(),
-                    flags: (BLE_GATT_CHR_F_READ | BLE_GATT_CHR_F_NOTIFY) as u16,
-                    arg: std::ptr::null_mut(),
-                    descriptors: std::ptr::null_mut(),
-                    min_key_size: 0,
-                },
-                const_zero::const_zero!(ble_gatt_chr_def),
-            ] as *const _,
-            includes: std::ptr::null_mut(),
-        },
-        ble_gatt_svc_def {
-            type_: BLE_GATT_SVC_TYPE_PRIMARY as u8,
-            uuid: &BLE_DATA_IN_SERVICE.u,
-            characteristics: &[
-                ble_gatt_chr_def {
-                    uuid: &BLE_DATA_IN_CHAR.u,
-                    access_cb: Some(ble_data_in_handler),
-                    val_handle: BLE_DATA_IN_HANDLE.get(),
-                    flags: BLE_GATT_CHR_F_WRITE as u16,
-                    arg: std::ptr::null_mut(),
-                    descriptors: std::ptr::null_mut(),
-                    min_key_size: 0,
-                },
-                ble_gatt_chr_def {
-                    uuid: &BLE_DATA_OUT_CHAR.u,
-                    access_cb: Some(ble_data_in_handler),
-                    val_handle: BLE_DATA_OUT_HANDLE.get(),
-                    flags: BLE_GATT_CHR_F_NOTIFY as u16,
-                    arg: std::ptr::null_mut(),
-                    descriptors: std::ptr::null_mut(),
-                    min_key_size: 0,
-                },
-                const_zero::const_zero!(ble_gatt_chr_def),
-            ] as *const _,
-            includes: std::ptr::null_mut(),
-        },
-        const_zero::const_zero!(ble_gatt_svc_def),
-    ]
-};
+fn hid_registered() -> bool {
+    HID_REGISTERED.lock().unwrap().unwrap_or(false)
+}
+
+/// A read callback for a value that never changes
+fn static_value(value: &'static [u8]) -> impl Fn(u16) -> Vec<u8> + Send + Sync {
+    move |_| value.to_vec()
+}
+
+/// Everything we expose over GATT, bar the optional services
+fn gatt_services() -> GattBuilder {
+    Gatt::builder()
+        .service(
+            Service::new(BLE_BAT_SERVICE)
+                .characteristic(
+                    Characteristic::new(BLE_BAT_CHAR, Flags::READ | Flags::NOTIFY)
+                        .handle(&BLE_LE_BAT_CHAR_HANDLE)
+                        .on_read(|_| {
+                            let batt_level = BATTERY_PERCENT.load(Ordering::Relaxed);
+                            info!(batt_level, "Reading battery level");
+                            vec![batt_level]
+                        }),
+                )
+                .characteristic(
+                    Characteristic::new(BLE_BAT_LEVEL_STATUS_CHAR, Flags::READ | Flags::NOTIFY)
+                        .handle(&BLE_BAT_LEVEL_STATUS_HANDLE)
+                        .on_read(|_| battery_level_status().to_vec()),
+                ),
+        )
+        .service(
+            Service::new(DATA_SERVICE_UUID)
+                .characteristic(
+                    Characteristic::new(BLE_DATA_IN_CHAR, Flags::WRITE).on_write(data_received),
+                )
+                .characteristic(
+                    Characteristic::new(BLE_DATA_OUT_CHAR, Flags::NOTIFY | Flags::INDICATE)
+                        .handle(&BLE_DATA_OUT_HANDLE)
+                        .on_subscribe(|_, subscribed| {
+                            if subscribed {
+                                let _ = FLUSH.0.try_send(());
+                            }
+                        }),
+                ),
+        )
+        .service(
+            Service::new(BLE_DIS_SERVICE)
+                .characteristic(
+                    Characteristic::new(BLE_DIS_MANUFACTURER_CHAR, Flags::READ)
+                        .on_read(static_value(device_info::MANUFACTURER.as_bytes())),
+                )
+                .characteristic(
+                    Characteristic::new(BLE_DIS_MODEL_NUMBER_CHAR, Flags::READ)
+                        .on_read(static_value(device_info::BOARD.as_bytes())),
+                )
+                .characteristic(
+                    Characteristic::new(BLE_DIS_SERIAL_NUMBER_CHAR, Flags::READ)
+                        .on_read(|_| device_info::serial_number().into_bytes()),
+                )
+                .characteristic(
+                    Characteristic::new(BLE_DIS_HARDWARE_REVISION_CHAR, Flags::READ)
+                        .on_read(|_| device_info::hardware_revision().into_bytes()),
+                )
+                .characteristic(
+                    Characteristic::new(BLE_DIS_FIRMWARE_REVISION_CHAR, Flags::READ)
+                        .on_read(static_value(env!("CARGO_PKG_VERSION").as_bytes())),
+                ),
+        )
+        // firmware updates, which obviously need a paired phone
+        .service(
+            Service::new(BLE_DFU_SERVICE)
+                .characteristic(
+                    Characteristic::new(
+                        BLE_DFU_CONTROL_POINT_CHAR,
+                        Flags::WRITE | Flags::WRITE_ENC | Flags::NOTIFY,
+                    )
+                    .handle(&BLE_DFU_CONTROL_POINT_HANDLE)
+                    .on_write(|conn_handle, buf| {
+                        dfu_received(conn_handle, ota::parse_command(buf))
+                    }),
+                )
+                .characteristic(
+                    Characteristic::new(BLE_DFU_DATA_CHAR, Flags::WRITE_NO_RSP | Flags::WRITE_ENC)
+                        .on_write(|conn_handle, buf| {
+                            dfu_received(conn_handle, ota::parse_data(buf))
+                        }),
+                ),
+        )
+}
+
+/// HID over GATT, for using the buttons as a remote. Only registered in the
+/// HID modes, since hosts that see it treat the watch as a keyboard. Hosts only
+/// use it once paired, so everything needs encryption.
+fn hid_service() -> Service {
+    Service::new(BLE_HID_SERVICE)
+        .characteristic(
+            Characteristic::new(BLE_HID_INFORMATION_CHAR, Flags::READ | Flags::READ_ENC)
+                .on_read(static_value(&hid::HID_INFORMATION)),
+        )
+        .characteristic(
+            Characteristic::new(BLE_HID_REPORT_MAP_CHAR, Flags::READ | Flags::READ_ENC)
+                .on_read(static_value(hid::REPORT_MAP)),
+        )
+        .characteristic(
+            Characteristic::new(
+                BLE_HID_CONTROL_POINT_CHAR,
+                Flags::WRITE_NO_RSP | Flags::WRITE_ENC,
+            )
+            // suspend and exit suspend, neither of which we care about
+            .on_write(|_, _| info!("HID control point written")),
+        )
+        .characteristic(
+            Characteristic::new(
+                BLE_HID_REPORT_CHAR,
+                Flags::READ | Flags::READ_ENC | Flags::NOTIFY,
+            )
+            .handle(&BLE_HID_KEYBOARD_HANDLE)
+            .on_read(static_value(&[0; hid::KEYBOARD_REPORT_LEN]))
+            .descriptor(
+                Descriptor::new(BLE_HID_REPORT_REFERENCE_DSC, Flags::READ | Flags::READ_ENC)
+                    .on_read(static_value(&HID_KEYBOARD_REPORT_REFERENCE)),
+            ),
+        )
+        .characteristic(
+            Characteristic::new(
+                BLE_HID_REPORT_CHAR,
+                Flags::READ | Flags::READ_ENC | Flags::NOTIFY,
+            )
+            .handle(&BLE_HID_CONSUMER_HANDLE)
+            .on_read(static_value(&[0; hid::CONSUMER_REPORT_LEN]))
+            .descriptor(
+                Descriptor::new(BLE_HID_REPORT_REFERENCE_DSC, Flags::READ | Flags::READ_ENC)
+                    .on_read(static_value(&HID_CONSUMER_REPORT_REFERENCE)),
+            ),
+        )
+}
+
+#[cfg(feature = "nus")]
+fn nus_service() -> Service {
+    Service::new(BLE_NUS_SERVICE)
+        .characteristic(
+            // commands skip the MAC, so only peers that paired can send them
+            Characteristic::new(
+                BLE_NUS_RX_CHAR,
+                Flags::WRITE | Flags::WRITE_NO_RSP | Flags::WRITE_ENC,
+            )
+            .on_write(nus_received),
+        )
+        .characteristic(
+            Characteristic::new(BLE_NUS_TX_CHAR, Flags::NOTIFY).handle(&BLE_NUS_TX_HANDLE),
+        )
+}
+
+unsafe extern "C" fn ble_spp_server_on_reset(reason: c_int) {
+    info!(reason, "Resetting ble");
+}
+
+static mut OWN_ADDR_TYPE: u8 = 0;
+
+/// The address type to reconnect with. Directed advertising only reaches a
+/// phone on a private address when the controller fills it in from the
+/// phone's IRK, which it does for these types; they fall back to our identity
+/// address when there's no IRK to hand.
+unsafe fn resolving_addr_type() -> u8 {
+    if OWN_ADDR_TYPE == BLE_OWN_ADDR_PUBLIC as u8 {
+        BLE_OWN_ADDR_RPA_PUBLIC_DEFAULT as u8
+    } else {
+        BLE_OWN_ADDR_RPA_RANDOM_DEFAULT as u8
+    }
+}
+
+unsafe extern "C" fn ble_spp_server_on_sync() {
+    let rc = ble_hs_util_ensure_addr(0);
+    assert_eq!(rc, 0, "ble_hs_util_ensure_addr");
+
+    let rc = ble_hs_id_infer_auto(0, &mut OWN_ADDR_TYPE as *mut _);
+    if rc != 0 {
+        error!(rc, "Failed to determine address type");
+        return;
+    }
+
+    let mut addr_val = [0u8; 6];
+    ble_hs_id_copy_addr(OWN_ADDR_TYPE, &mut addr_val as *mut _, std::ptr::null_mut());
+
+    info!(device_address = ?addr_val, "Found device address");
+
+    if GATT_CHANGED.swap(false, Ordering::SeqCst) {
+        info!("Services changed, telling phones to rediscover them");
+        ble_svc_gatt_changed(0x0001, 0xffff);
+    }
+
+    ble_spp_server_advertise();
+}
+
+/// Battery Level Status: flags, power state and the battery level
+fn battery_level_status() -> [u8; 4] {
+    const FLAG_BATTERY_LEVEL_PRESENT: u8 = 0b0000_0010;
+
+    let state = *POWER_STATE.lock().unwrap();
+    let level = BATTERY_PERCENT.load(std::sync::atomic::Ordering::Relaxed);
+
+    // 1 = charging, 2 = discharging while in use
+    let charge_state: u16 = if state.charging { 1 } else { 2 };
+    // 1 = good, 2 = low, 3 = critical
+    let charge_level: u16 = match level {
+        0..=5 => 3,
+        6..=15 => 2,
+        _ => 1,
+    };
+
+    let power_state = state.battery_present as u16
+        | (state.vbus_present as u16) << 1
+        | charge_state << 5
+        | charge_level << 7;
+    let [lo, hi] = power_state.to_le_bytes();
+
+    [FLAG_BATTERY_LEVEL_PRESENT, lo, hi, level]
+}
+
+/// Sends `value` to everyone subscribed to `attr_handle`
+fn notify_subscribers(attr_handle: u16, value: &[u8]) {
+    for conn_handle in connections::subscribers(attr_handle) {
+        let mut link = NotifyLink::new(conn_handle, attr_handle);
+        if let Err(err) = link.send_fragment(value) {
+            error!(?err, conn_handle, attr_handle, "Failed to notify");
+        }
+    }
+}
+
+fn battery_thread() {
+    for () in axp192::battery_changes() {
+        let level = BATTERY_PERCENT.load(std::sync::atomic::Ordering::Relaxed);
+        notify_subscribers(BLE_LE_BAT_CHAR_HANDLE.get(), &[level]);
+        notify_subscribers(BLE_BAT_LEVEL_STATUS_HANDLE.get(), &battery_level_status());
+    }
+}
+
+/// A write to the data characteristic, which is a fragment of a message
+fn data_received(conn_handle: u16, buf: &[u8]) {
+    info!(conn_handle, len = buf.len(), "Data received in write event");
+
+    let payload = REASSEMBLERS
+        .lock()
+        .unwrap()
+        .entry(conn_handle)
+        .or_insert_with(|| framing::Reassembler::new(MAX_INBOUND_LEN, REASSEMBLY_TIMEOUT))
+        .push(buf, std::time::Instant::now());
+
+    match payload {
+        Ok(Some(payload)) => {
+            protocol::receive(BleTransport::NAME, &payload, link_encrypted(conn_handle))
+        }
+        // more fragments to come
+        Ok(None) => busy(conn_handle),
+        Err(err) => {
+            error!(?err, "While reassembling a message");
+            protocol::send_notification(message::Notification::error(
+                0,
+                message::ErrorCode::DecodeFailure,
+                format!("{:?}", err),
+            ));
+        }
+    }
+}
+
+#[cfg(feature = "nus")]
+fn nus_received(conn_handle: u16, buf: &[u8]) {
+    // terminals send a line per write, often without the newline
+    let text = String::from_utf8_lossy(buf);
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        nus_command(conn_handle, line);
+    }
+}
+
+#[cfg(feature = "nus")]
+fn nus_command(conn_handle: u16, line: &str) {
+    static REQUEST_ID: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(1);
+
+    info!(conn_handle, line, "NUS command");
+    match nus::parse(line) {
+        Ok(nus::Command::Help) => nus_reply(conn_handle, nus::HELP),
+        Ok(nus::Command::Send(body)) => protocol::dispatch(
+            NusTransport::NAME,
+            message::Message {
+                request_id: REQUEST_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+                body: Some(body),
+                ..Default::default()
+            },
+        ),
+        Err(err) => nus_reply(conn_handle, &format!("error: {}", err)),
+    }
+}
+
+/// Writes a line to a peer's NUS TX, split up to fit its MTU
+#[cfg(feature = "nus")]
+fn nus_reply(conn_handle: u16, line: &str) {
+    let mut link = NotifyLink::new(conn_handle, BLE_NUS_TX_HANDLE.get());
+
+    let line = format!("{}\n", line);
+    for chunk in line.as_bytes().chunks(link.max_fragment_len()) {
+        if let Err(err) = link.send_fragment(chunk) {
+            error!(?err, conn_handle, "Failed to write NUS reply");
+            return;
+        }
+    }
+}
+
+/// Every notification as a line of text, to peers subscribed to NUS TX
+#[cfg(feature = "nus")]
+struct NusTransport;
+
+#[cfg(feature = "nus")]
+impl NusTransport {
+    const NAME: &'static str = "nus";
+}
+
+#[cfg(feature = "nus")]
+impl protocol::Transport for NusTransport {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn send(&mut self, payload: &[u8]) -> color_eyre::Result<()> {
+        let notif = <message::Notification as prost::Message>::decode(payload)?;
+        let line = nus::describe(&notif);
+
+        for conn_handle in connections::subscribers(BLE_NUS_TX_HANDLE.get()) {
+            // notifications meant for the app shouldn't go to anyone who
+            // hasn't paired
+            if link_encrypted(conn_handle) {
+                nus_reply(conn_handle, &line);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Presses and releases `key` on every paired host listening for it
+pub fn send_hid_key(key: hid::Key) {
+    let attr_handle = match key {
+        hid::Key::Keyboard(_) => BLE_HID_KEYBOARD_HANDLE.get(),
+        hid::Key::Consumer(_) => BLE_HID_CONSUMER_HANDLE.get(),
+    };
+
+    for conn_handle in connections::subscribers(attr_handle) {
+        if !link_encrypted(conn_handle) {
+            continue;
+        }
+
+        let mut link = NotifyLink::new(conn_handle, attr_handle);
+        for report in [key.pressed(), key.released()] {
+            if let Err(err) = link.send_fragment(&report) {
+                error!(?err, conn_handle, ?key, "Failed to send HID report");
+                break;
+            }
+        }
+    }
+}
+
+fn dfu_received(conn_handle: u16, request: color_eyre::Result<ota::Request>) {
+    busy(conn_handle);
+
+    // flash is slow, so the writing happens on another thread
+    let result = match request {
+        Ok(request) => DFU_REQUESTS
+            .0
+            .try_send((conn_handle, request))
+            .map_err(|_| ota::Status::Busy),
+        Err(err) => {
+            error!(?err, "Bad DFU request");
+            Err(ota::Status::BadCommand)
+        }
+    };
+    if let Err(status) = result {
+        dfu_respond(conn_handle, status, 0);
+    }
+}
+
+fn link_encrypted(conn_handle: u16) -> bool {
+    connections::get(conn_handle).map_or(false, |conn| conn.encrypted)
+}
+
+/// Held while advertising is being set up, so it can't overlap with itself or
+/// with the stack stopping
+static ADV_LOCK: Mutex<()> = Mutex::new(());
+
+/// The bonded phone we lost, while we're advertising to get it back
+static RECONNECT: Mutex<Option<Reconnect>> = Mutex::new(None);
+
+/// Advertises for anything to connect, replacing any advertising that's going
+/// on, including for a lost phone
+pub fn ble_spp_server_advertise() {
+    *RECONNECT.lock().unwrap() = None;
+    advertise(None);
+}
+
+/// Takes the next step in getting back the phone we lost, or gives up
+fn reconnect_advertise() {
+    let give_up = Duration::from_secs(advertising::current().reconnect_secs as u64);
+    let mut reconnect = RECONNECT.lock().unwrap();
+    let target = reconnect.as_mut().and_then(|reconnect| {
+        let step = reconnect.next(give_up, Instant::now())?;
+        let addr = ble_addr_t {
+            type_: reconnect.addr_type,
+            val: reconnect.addr,
+        };
+        Some((addr, step))
+    });
+    if target.is_none() && reconnect.take().is_some() {
+        info!("Giving up on reconnecting");
+    }
+    std::mem::drop(reconnect);
+
+    if let Some(target) = target {
+        info!(step = ?target.1, "Advertising to reconnect");
+        advertise(Some(target));
+    }
+}
+
+/// A Service Solicitation field asking for the Notification Center Service
+fn ancs_solicitation() -> [u8; 18] {
+    let mut field = [0; 18];
+    field[0] = field.len() as u8 - 1;
+    field[1] = BLE_HS_ADV_TYPE_SOL_UUIDS128 as u8;
+    field[2..].copy_from_slice(unsafe { &BLE_ANCS_SERVICE.value });
+    field
+}
+
+/// Forgets about reconnecting to `addr`, once it's no longer bonded
+pub fn cancel_reconnect(addr: [u8; 6]) {
+    let mut reconnect = RECONNECT.lock().unwrap();
+    if matches!(&*reconnect, Some(r) if r.addr == addr) {
+        *reconnect = None;
+    }
+}
+
+/// Advertises to everyone, or only to `target` when reconnecting
+fn advertise(target: Option<(ble_addr_t, Step)>) {
+    let _handle = ADV_LOCK.lock().unwrap();
+    if !radio_on() {
+        info!("Bluetooth is off, not advertising");
+        return;
+    }
+
+    let config = advertising::current();
+    let name = match CString::new(config.name.as_str()) {
+        Ok(name) => name,
+        Err(err) => {
+            error!(?err, "Bad device name");
+            return;
+        }
+    };
+
+    let hid_mode = hid_registered();
+
+    let mut uuids16: Vec<_> = config
+        .service_uuids
+        .iter()
+        .filter(|uuid| uuid.len() == 2)
+        .map(|uuid| ble_uuid16_t {
+            u: BLE_UUID_TYPE_16_,
+            value: u16::from_le_bytes([uuid[0], uuid[1]]),
+        })
+        .collect();
+    // hosts look for the HID service when choosing what to pair with
+    if hid_mode
+        && !uuids16
+            .iter()
+            .any(|uuid| uuid.value == advertising::HID_SERVICE_UUID)
+    {
+        uuids16.push(ble_uuid16_t {
+            u: BLE_UUID_TYPE_16_,
+            value: advertising::HID_SERVICE_UUID,
+        });
+    }
+    let uuids128: Vec<_> = config
+        .service_uuids
+        .iter()
+        .filter_map(|uuid| uuid.as_slice().try_into().ok())
+        .map(|value| ble_uuid128_t {
+            u: BLE_UUID_TYPE_128_,
+            value,
+        })
+        .collect();
+
+    let in_scan_response = |field: AdvertisingField| config.scan_response.contains(&(field as i32));
+    // itvl is in units of 0.625ms
+    let itvl = (config.interval_ms * 8 / 5) as u16;
+
+    unsafe {
+        if ble_gap_adv_active() != 0 {
+            let rc = ble_gap_adv_stop();
+            if rc != 0 {
+                error!(rc, "error stopping advertisement");
+                return;
+            }
+        }
+
+        if let Err(err) = esp!(ble_svc_gap_device_name_set(name.as_ptr())) {
+            error!(?err, "error setting device name");
+            return;
+        }
+
+        let mut adv_fields = ble_hs_adv_fields {
+            flags: (BLE_HS_ADV_F_DISC_GEN | BLE_HS_ADV_F_BREDR_UNSUP) as u8,
+            adv_itvl: itvl,
+            ..Default::default()
+        };
+        adv_fields.set_adv_itvl_is_present(1);
+        let mut rsp_fields = ble_hs_adv_fields::default();
+        if hid_mode {
+            rsp_fields.appearance = HID_APPEARANCE_KEYBOARD;
+            rsp_fields.set_appearance_is_present(1);
+        }
+
+        let fields = if in_scan_response(AdvertisingField::Name) {
+            &mut rsp_fields
+        } else {
+            &mut adv_fields
+        };
+        fields.name = name.as_ptr() as *const _;
+        fields.name_len = config.name.len() as u8;
+        fields.set_name_is_complete(1);
+
+        let fields = if in_scan_response(AdvertisingField::TxPower) {
+            &mut rsp_fields
+        } else {
+            &mut adv_fields
+        };
+        fields.tx_pwr_lvl = BLE_HS_ADV_TX_PWR_LVL_AUTO as i8;
+        fields.set_tx_pwr_lvl_is_present(1);
+
+        let fields = if in_scan_response(AdvertisingField::ServiceUuids) {
+            &mut rsp_fields
+        } else {
+            &mut adv_fields
+        };
+        if !uuids16.is_empty() {
+            fields.uuids16 = uuids16.as_ptr();
+            fields.num_uuids16 = uuids16.len() as u8;
+            fields.set_uuids16_is_complete(1);
+        }
+        if !uuids128.is_empty() {
+            fields.uuids128 = uuids128.as_ptr();
+            fields.num_uuids128 = uuids128.len() as u8;
+            fields.set_uuids128_is_complete(1);
+        }
+
+        let rc = ble_gap_adv_set_fields(&adv_fields);
+        if rc != 0 {
+            error!(rc, "error setting advertisement data");
+            return;
+        }
+
+        // iOS only offers the Notification Center Service to accessories that
+        // solicit it. NimBLE has no field for that, so it goes on the end of
+        // the scan response when there's room.
+        let mut rsp = [0u8; BLE_HS_ADV_MAX_SZ as usize];
+        let mut rsp_len = 0;
+        let rc = ble_hs_adv_set_fields(
+            &rsp_fields,
+            rsp.as_mut_ptr(),
+            &mut rsp_len as *mut _,
+            rsp.len() as u8,
+        );
+        if rc != 0 {
+            error!(rc, "error encoding scan response data");
+            return;
+        }
+        let solicitation = ancs_solicitation();
+        let start = rsp_len as usize;
+        if let Some(dst) = rsp.get_mut(start..start + solicitation.len()) {
+            dst.copy_from_slice(&solicitation);
+            rsp_len += solicitation.len() as u8;
+        } else {
+            debug!("No room in the scan response to solicit ANCS");
+        }
+        let rc = ble_gap_adv_rsp_set_data(rsp.as_ptr(), rsp_len as c_int);
+        if rc != 0 {
+            error!(rc, "error setting scan response data");
+            return;
+        }
+
+        let mut adv_params = ble_gap_adv_params {
+            conn_mode: BLE_GAP_CONN_MODE_UND as u8,
+            disc_mode: BLE_GAP_DISC_MODE_GEN as u8,
+            itvl_min: itvl,
+            itvl_max: itvl,
+            ..Default::default()
+        };
+        let mut direct_addr = std::ptr::null();
+        let duration_ms = match target {
+            None if config.duration_secs == 0 => BLE_HS_FOREVER as i32,
+            None => Duration::from_secs(config.duration_secs as u64).as_millis() as i32,
+            Some((ref addr, Step::Directed)) => {
+                adv_params.conn_mode = BLE_GAP_CONN_MODE_DIR as u8;
+                adv_params.set_high_duty_cycle(1);
+                direct_addr = addr as *const _;
+                BLE_HS_FOREVER as i32
+            }
+            Some((
+                ref addr,
+                Step::Whitelisted {
+                    interval_ms,
+                    duration,
+                },
+            )) => {
+                let rc = ble_gap_wl_set(addr, 1);
+                if rc != 0 {
+                    error!(rc, "error setting the whitelist");
+                    return;
+                }
+                let itvl = (interval_ms * 8 / 5) as u16;
+                adv_params.itvl_min = itvl;
+                adv_params.itvl_max = itvl;
+                adv_params.filter_policy = BLE_HCI_ADV_FILT_CONN as u8;
+                duration.as_millis() as i32
+            }
+        };
+        let own_addr_type = if target.is_some() {
+            resolving_addr_type()
+        } else {
+            OWN_ADDR_TYPE
+        };
+        let rc = ble_gap_adv_start(
+            own_addr_type,
+            direct_addr,
+            duration_ms,
+            &adv_params,
+            Some(ble_spp_server_gap_event),
+            std::ptr::null_mut(),
+        );
+        if rc != 0 {
+            error!(rc, "error enabling advertisement");
+            return;
+        }
+
+        info!("started to advertise");
+    }
+}
+
+/// Picks up a new advertising config, if we're advertising at the moment
+pub fn restart_advertising() {
+    if !advertising() {
+        return;
+    }
+
+    unsafe {
+        let rc = ble_gap_adv_stop();
+        if rc != 0 {
+            error!(rc, "error stopping advertisement");
+            return;
+        }
+    }
+
+    ble_spp_server_advertise();
+}
+
+unsafe extern "C" fn ble_spp_server_gap_event(event: *mut ble_gap_event, _arg: *mut c_void) -> i32 {
+    let event_ = *event;
+    let mut desc = ble_gap_conn_desc::default();
+
+    match event_.type_ as u32 {
+        BLE_GAP_EVENT_CONNECT => {
+            let connect = event_.__bindgen_anon_1.connect;
+            info!(
+                status = connect.status,
+                "connection {}",
+                if connect.status == 0 {
+                    "established"
+                } else {
+                    "failed"
+                }
+            );
+
+            if connect.status == 0 {
+                let rc = ble_gap_conn_find(connect.conn_handle, &mut desc as *mut _);
+                assert_eq!(rc, 0, "ble_gap_conn_find");
+                info!(handle = connect.conn_handle, ?desc, "Conn desc");
+                let mut conn = Connection::new(connect.conn_handle, desc.peer_id_addr.val);
+                conn.encrypted = desc.sec_state.encrypted() != 0;
+                conn.bonded = desc.sec_state.bonded() != 0;
+                conn.interval = desc.conn_itvl;
+                conn.latency = desc.conn_latency;
+                conn.supervision_timeout = desc.supervision_timeout;
+                connections::add(conn);
+
+                // done now, before pairing kicks off any of our other GATT
+                // client procedures
+                let rc =
+                    ble_gattc_exchange_mtu(connect.conn_handle, Some(on_mtu_exchanged), null_mut());
+                if rc != 0 {
+                    error!(rc, "Failed to start the MTU exchange");
+                }
+
+                // phones don't encrypt the link again on their own until they
+                // need to, and we don't look for ANCS or CTS until it is
+                if bonds::is_bonded(desc.peer_id_addr.val) {
+                    let rc = ble_gap_security_initiate(connect.conn_handle);
+                    if rc != 0 {
+                        error!(rc, "Failed to start encrypting the link");
+                    }
+                }
+            }
+
+            if connect.status == 0 {
+                *RECONNECT.lock().unwrap() = None;
+            } else if RECONNECT.lock().unwrap().is_some() {
+                reconnect_advertise();
+            } else {
+                ble_spp_server_advertise();
+            }
+        }
+
+        BLE_GAP_EVENT_DISCONNECT => {
+            let disconnect = event_.__bindgen_anon_1.disconnect;
+            info!(reason = disconnect.reason, "Disconnect");
+            let conn = connections::remove(disconnect.conn.conn_handle);
+            info!(?conn, "Forgot connection");
+            // an update can't finish without the phone that started it
+            let _ = DFU_REQUESTS
+                .0
+                .try_send((disconnect.conn.conn_handle, ota::Request::Abort));
+            let _ = PAIRING_EVENTS.0.send(PairingEvent::Finished {
+                conn_handle: disconnect.conn.conn_handle,
+                success: false,
+            });
+            let mut cts = CTS.lock().unwrap();
+            if matches!(&*cts, Some(client) if client.conn_handle == disconnect.conn.conn_handle) {
+                *cts = None;
+            }
+            std::mem::drop(cts);
+            let mut ancs = ANCS.lock().unwrap();
+            if matches!(&*ancs, Some(client) if client.conn_handle == disconnect.conn.conn_handle) {
+                *ancs = None;
+            }
+            std::mem::drop(ancs);
+            REASSEMBLERS
+                .lock()
+                .unwrap()
+                .remove(&disconnect.conn.conn_handle);
+
+            // go after a bonded phone that dropped off, unless we hung up on
+            // it or the radio is going off
+            let hung_up = disconnect.reason
+                == (BLE_HS_ERR_HCI_BASE + ble_error_codes_BLE_ERR_CONN_TERM_LOCAL) as i32;
+            if disconnect.conn.sec_state.bonded() != 0 && !hung_up && radio_on() {
+                // the identity address, which stays put when the phone's
+                // private address moves on, and which the controller matches
+                // against once it's resolved the private one
+                let addr = disconnect.conn.peer_id_addr;
+                *RECONNECT.lock().unwrap() =
+                    Some(Reconnect::new(addr.type_, addr.val, Instant::now()));
+                reconnect_advertise();
+            }
+        }
+
+        BLE_GAP_EVENT_ENC_CHANGE => {
+            let enc_change = event_.__bindgen_anon_1.enc_change;
+            info!(status = enc_change.status, "Encryption change");
+            let _ = PAIRING_EVENTS.0.send(PairingEvent::Finished {
+                conn_handle: enc_change.conn_handle,
+                success: enc_change.status == 0,
+            });
+            let rc = ble_gap_conn_find(enc_change.conn_handle, &mut desc as *mut _);
+            if rc == 0 {
+                connections::update(enc_change.conn_handle, |conn| {
+                    conn.encrypted = desc.sec_state.encrypted() != 0;
+                    conn.bonded = desc.sec_state.bonded() != 0;
+                });
+                if enc_change.status == 0 && desc.sec_state.bonded() != 0 {
+                    // looks for the Current Time Service once it's done
+                    ancs_discover(enc_change.conn_handle);
+                }
+            }
+        }
+
+        BLE_GAP_EVENT_PASSKEY_ACTION => {
+            let passkey = event_.__bindgen_anon_1.passkey;
+            info!(
+                conn_handle = passkey.conn_handle,
+                action = passkey.params.action,
+                "Passkey action"
+            );
+
+            // we can only show a passkey, so that's all we're ever asked for
+            if passkey.params.action as u32 == BLE_SM_IOACT_DISP {
+                show_passkey(passkey.conn_handle);
+            }
+        }
+
+        BLE_GAP_EVENT_NOTIFY_RX => {
+            let notify_rx = event_.__bindgen_anon_1.notify_rx;
+            cts_notified(notify_rx.conn_handle, notify_rx.attr_handle, notify_rx.om);
+            ancs_notified(notify_rx.conn_handle, notify_rx.attr_handle, notify_rx.om);
+        }
+
+        BLE_GAP_EVENT_CONN_UPDATE => {
+            let conn_update = event_.__bindgen_anon_1.conn_update;
+            let rc = ble_gap_conn_find(conn_update.conn_handle, &mut desc as *mut _);
+            if rc == 0 {
+                info!(
+                    status = conn_update.status,
+                    interval = desc.conn_itvl,
+                    latency = desc.conn_latency,
+                    supervision_timeout = desc.supervision_timeout,
+                    "Connection update"
+                );
+                connections::update(conn_update.conn_handle, |conn| {
+                    conn.interval = desc.conn_itvl;
+                    conn.latency = desc.conn_latency;
+                    conn.supervision_timeout = desc.supervision_timeout;
+                });
+            }
+        }
+
+        BLE_GAP_EVENT_ADV_COMPLETE => {
+            let adv_complete = event_.__bindgen_anon_1.adv_complete;
+            info!(reason = adv_complete.reason, "advertise complete");
+            // directed advertising ends with a reason of 0 rather than a
+            // timeout, either way it's time for the next step
+            reconnect_advertise();
+        }
+
+        BLE_GAP_EVENT_MTU => {
+            let mtu = event_.__bindgen_anon_1.mtu;
+            info!(
+                conn_handle = mtu.conn_handle,
+                channel_id = mtu.channel_id,
+                value = mtu.value,
+                "mtu update"
+            );
+            connections::update(mtu.conn_handle, |conn| conn.mtu = mtu.value);
+        }
+
+        BLE_GAP_EVENT_SUBSCRIBE => {
+            let subscribe = event_.__bindgen_anon_1.subscribe;
+            info!(
+                conn_handle = subscribe.conn_handle,
+                attr_handle = subscribe.attr_handle,
+                notify = subscribe.cur_notify(),
+                indicate = subscribe.cur_indicate(),
+                "subscribe"
+            );
+
+            let notify = subscribe.cur_notify() != 0;
+            let indicate = subscribe.cur_indicate() != 0;
+            connections::set_subscribed(
+                subscribe.conn_handle,
+                subscribe.attr_handle,
+                notify,
+                indicate,
+            );
+            let subscribed = notify || indicate;
+            if let Some(gatt) = gatt() {
+                gatt.subscribed(subscribe.conn_handle, subscribe.attr_handle, subscribed);
+            }
+        }
+
+        BLE_GAP_EVENT_NOTIFY_TX => {
+            let notify_tx = event_.__bindgen_anon_1.notify_tx;
+            // an indication reports once when it goes out, then again when
+            // the phone confirms it or it times out
+            if notify_tx.indication() != 0 && notify_tx.status != 0 {
+                let _ = INDICATIONS
+                    .0
+                    .try_send((notify_tx.conn_handle, notify_tx.status));
+            }
+        }
+
+        _ => {}
+    }
+
+    0
+}
+// sepples moment
+
+unsafe extern "C" fn gatt_svr_register_cb(ctxt: *mut ble_gatt_register_ctxt, _arg: *mut c_void) {
+    let mut buf = [0 as c_char; BLE_UUID_STR_LEN as usize];
+    let ctxt_ = *ctxt;
+
+    match ctxt_.op as u32 {
+        BLE_GATT_REGISTER_OP_SVC => {
+            let svc = ctxt_.__bindgen_anon_1.svc;
+            let uuid = CStr::from_ptr(ble_uuid_to_str((*svc.svc_def).uuid, &mut buf as *mut _));
+            info!(uuid = ?uuid, handle = ?svc.handle, "Registering gatt service");
+        }
+        BLE_GATT_REGISTER_OP_CHR => {
+            let chr = ctxt_.__bindgen_anon_1.chr;
+            let uuid = CStr::from_ptr(ble_uuid_to_str((*chr.chr_def).uuid, &mut buf as *mut _));
+            info!(uuid = ?uuid, def_handle = ?chr.def_handle, val_handle = ?chr.val_handle, "Registering characteristic");
+            if let Some(gatt) = gatt() {
+                gatt.registered((*chr.chr_def).arg, chr.val_handle);
+            }
+        }
+        BLE_GATT_REGISTER_OP_DSC => {
+            let dsc = ctxt_.__bindgen_anon_1.dsc;
+            let uuid = CStr::from_ptr(ble_uuid_to_str((*dsc.dsc_def).uuid, &mut buf as *mut _));
+            info!(uuid = ?uuid, handle = ?dsc.handle, "Registering gatt service");
+        }
+        _ => {}
+    }
+}
+
+/// A passkey the user has to type into the phone to finish pairing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pairing {
+    pub conn_handle: u16,
+    pub passkey: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PairingEvent {
+    Passkey(Pairing),
+    /// Encryption came up or failed, or the peer went away mid-pairing
+    Finished {
+        conn_handle: u16,
+        success: bool,
+    },
+}
+
+unsafe extern "C" fn on_mtu_exchanged(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    mtu: u16,
+    _arg: *mut c_void,
+) -> c_int {
+    match (*error).status {
+        0 => info!(conn_handle, mtu, "Exchanged MTU"),
+        status => error!(conn_handle, status, "MTU exchange failed"),
+    }
+
+    0
+}
+
+/// Connections that just started a transfer or a burst of notifications
+static BUSY: Lazy<(channel::Sender<u16>, channel::Receiver<u16>)> =
+    Lazy::new(|| channel::bounded(8));
+
+/// Asks for fast connection parameters on `conn_handle` for a while. Cheap
+/// enough to call on every write.
+fn busy(conn_handle: u16) {
+    // if the queue's full the thread is about to look at it anyway
+    let _ = BUSY.0.try_send(conn_handle);
+}
+
+/// Keeps each connection's parameters in line with [`conn_params::wanted`]
+fn conn_params_thread() {
+    loop {
+        match BUSY.1.recv_timeout(conn_params::CHECK_INTERVAL) {
+            Ok(conn_handle) => {
+                connections::update(conn_handle, |conn| conn.busy_at = Some(Instant::now()));
+            }
+            Err(channel::RecvTimeoutError::Timeout) => {}
+            Err(channel::RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        for conn in connections::all() {
+            let wanted = conn_params::wanted(conn.connected_at, conn.busy_at, now);
+            if let Some(profile) = wanted.filter(|&profile| conn.profile != Some(profile)) {
+                request_params(conn.handle, profile);
+            }
+        }
+    }
+}
+
+fn request_params(conn_handle: u16, profile: Profile) {
+    let params = profile.params();
+    let upd_params = ble_gap_upd_params {
+        itvl_min: params.interval_min,
+        itvl_max: params.interval_max,
+        latency: params.latency,
+        supervision_timeout: params.supervision_timeout,
+        min_ce_len: 0,
+        max_ce_len: 0,
+    };
+
+    info!(conn_handle, ?profile, "Requesting connection parameters");
+    // only asked for once, if the phone says no we live with what we have
+    connections::update(conn_handle, |conn| conn.profile = Some(profile));
+
+    let rc = unsafe { ble_gap_update_params(conn_handle, &upd_params as *const _) };
+    if rc != 0 {
+        error!(rc, conn_handle, "Failed to request connection parameters");
+    }
+
+    if profile == Profile::Fast {
+        // bigger link layer packets, so each fragment goes in one
+        let rc = unsafe {
+            ble_gap_set_data_len(
+                conn_handle,
+                conn_params::DATA_LEN_OCTETS,
+                conn_params::DATA_LEN_TIME,
+            )
+        };
+        if rc != 0 {
+            error!(rc, conn_handle, "Failed to request a longer data length");
+        }
+    }
+}
+
+static PAIRING_EVENTS: Lazy<(
+    channel::Sender<PairingEvent>,
+    channel::Receiver<PairingEvent>,
+)> = Lazy::new(channel::unbounded);
+
+/// Passkeys to put on screen, and word of when they can come down again
+pub fn pairing_events() -> channel::Receiver<PairingEvent> {
+    PAIRING_EVENTS.1.clone()
+}
+
+/// Picks a passkey for the phone to enter and hands it to the stack
+unsafe fn show_passkey(conn_handle: u16) {
+    let passkey = esp_random() % 1_000_000;
+    let mut io = ble_sm_io {
+        action: BLE_SM_IOACT_DISP as u8,
+        __bindgen_anon_1: ble_sm_io__bindgen_ty_1 { passkey },
+    };
+
+    let rc = ble_sm_inject_io(conn_handle, &mut io as *mut _);
+    if rc != 0 {
+        error!(rc, "Failed to set the pairing passkey");
+        return;
+    }
+
+    let _ = PAIRING_EVENTS.0.send(PairingEvent::Passkey(Pairing {
+        conn_handle,
+        passkey,
+    }));
+}
+
+/// Drops a peer that's part way through pairing, for when the user doesn't
+/// recognise the request
+pub fn reject_pairing(conn_handle: u16) {
+    info!(conn_handle, "Rejecting pairing");
+    let rc = unsafe { ble_gap_terminate(conn_handle, ble_error_codes_BLE_ERR_AUTH_FAIL as u8) };
+    if rc != 0 {
+        error!(rc, "Failed to disconnect a rejected peer");
+    }
+}
+
+/// Times read from the phone's Current Time Service, in UTC
+static TIME_UPDATES: Lazy<(
+    channel::Sender<eos::DateTime>,
+    channel::Receiver<eos::DateTime>,
+)> = Lazy::new(channel::unbounded);
+
+/// Times the phone has told us about over the Current Time Service
+pub fn time_updates() -> channel::Receiver<eos::DateTime> {
+    TIME_UPDATES.1.clone()
+}
+
+/// How many DFU requests can be waiting on the flash before we start turning
+/// them away
+const DFU_WINDOW: usize = 4;
+
+static DFU_REQUESTS: Lazy<(
+    channel::Sender<(u16, ota::Request)>,
+    channel::Receiver<(u16, ota::Request)>,
+)> = Lazy::new(|| channel::bounded(DFU_WINDOW));
+
+/// Firmware update requests, along with the connection they came from
+pub fn dfu_requests() -> channel::Receiver<(u16, ota::Request)> {
+    DFU_REQUESTS.1.clone()
+}
+
+/// Tells the phone how a DFU request went, and how much of the image has been
+/// written
+pub fn dfu_respond(conn_handle: u16, status: ota::Status, written: u32) {
+    let mut link = NotifyLink::new(conn_handle, BLE_DFU_CONTROL_POINT_HANDLE.get());
+    if let Err(err) = link.send_fragment(&ota::encode_response(status, written)) {
+        error!(?err, conn_handle, "Failed to send DFU response");
+    }
+}
+
+/// Where the phone's Current Time Service lives, filled in as discovery goes
+struct CtsClient {
+    conn_handle: u16,
+    svc_start: u16,
+    svc_end: u16,
+    /// Value handle and last handle of Current Time
+    current_time: Option<(u16, u16)>,
+    /// Value handle and last handle of Local Time Information
+    local_time_info: Option<(u16, u16)>,
+    cccd: Option<u16>,
+    local_time_info_cccd: Option<u16>,
+    /// Seconds the phone's clock is ahead of UTC
+    utc_offset: Option<i64>,
+}
+
+static CTS: Mutex<Option<CtsClient>> = Mutex::new(None);
+
+/// Runs `f` on the client for `conn_handle`, if we still have one
+fn with_cts<T>(conn_handle: u16, f: impl FnOnce(&mut CtsClient) -> T) -> Option<T> {
+    CTS.lock()
+        .unwrap()
+        .as_mut()
+        .filter(|client| client.conn_handle == conn_handle)
+        .map(f)
+}
+
+/// Looks for the Current Time Service on a bonded phone, and once found reads
+/// the time and subscribes to changes to it and to the time zone.
+fn cts_discover(conn_handle: u16) {
+    *CTS.lock().unwrap() = Some(CtsClient {
+        conn_handle,
+        svc_start: 0,
+        svc_end: 0,
+        current_time: None,
+        local_time_info: None,
+        cccd: None,
+        local_time_info_cccd: None,
+        utc_offset: None,
+    });
+
+    let rc = unsafe {
+        ble_gattc_disc_svc_by_uuid(
+            conn_handle,
+            &BLE_CTS_SERVICE.u,
+            Some(on_cts_svc),
+            std::ptr::null_mut(),
+        )
+    };
+    if rc != 0 {
+        error!(rc, "Failed to start discovering the Current Time Service");
+    }
+}
+
+unsafe extern "C" fn on_cts_svc(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    service: *const ble_gatt_svc,
+    _arg: *mut c_void,
+) -> c_int {
+    match (*error).status as u32 {
+        0 => {
+            let service = *service;
+            with_cts(conn_handle, |client| {
+                client.svc_start = service.start_handle;
+                client.svc_end = service.end_handle;
+            });
+        }
+        BLE_HS_EDONE => {
+            let range = with_cts(conn_handle, |client| (client.svc_start, client.svc_end));
+            match range {
+                Some((start, end)) if start != 0 => {
+                    let rc = ble_gattc_disc_all_chrs(
+                        conn_handle,
+                        start,
+                        end,
+                        Some(on_cts_chr),
+                        std::ptr::null_mut(),
+                    );
+                    if rc != 0 {
+                        error!(
+                            rc,
+                            "Failed to start discovering Current Time characteristics"
+                        );
+                    }
+                }
+                _ => info!("Phone has no Current Time Service"),
+            }
+        }
+        status => error!(status, "Discovering the Current Time Service failed"),
+    }
+
+    0
+}
+
+unsafe extern "C" fn on_cts_chr(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    chr: *const ble_gatt_chr,
+    _arg: *mut c_void,
+) -> c_int {
+    match (*error).status as u32 {
+        0 => {
+            let chr = *chr;
+            let uuid = ble_uuid_u16(&chr.uuid.u);
+            with_cts(conn_handle, |client| {
+                // each characteristic ends where the one after it starts
+                for (val_handle, end) in [&mut client.current_time, &mut client.local_time_info]
+                    .into_iter()
+                    .flatten()
+                {
+                    if *end == client.svc_end && chr.def_handle > *val_handle {
+                        *end = chr.def_handle - 1;
+                    }
+                }
+
+                match uuid {
+                    BLE_CTS_CURRENT_TIME_CHAR => {
+                        client.current_time = Some((chr.val_handle, client.svc_end))
+                    }
+                    BLE_CTS_LOCAL_TIME_INFO_CHAR => {
+                        client.local_time_info = Some((chr.val_handle, client.svc_end))
+                    }
+                    _ => {}
+                }
+            });
+        }
+        BLE_HS_EDONE => match with_cts(conn_handle, |client| client.local_time_info).flatten() {
+            Some((val_handle, _)) => cts_read(conn_handle, val_handle, on_local_time_info_read),
+            None => cts_read_current_time(conn_handle),
+        },
+        status => error!(status, "Discovering Current Time characteristics failed"),
+    }
+
+    0
+}
+
+fn cts_read(
+    conn_handle: u16,
+    attr_handle: u16,
+    cb: unsafe extern "C" fn(u16, *const ble_gatt_error, *mut ble_gatt_attr, *mut c_void) -> c_int,
+) {
+    let rc = unsafe { ble_gattc_read(conn_handle, attr_handle, Some(cb), std::ptr::null_mut()) };
+    if rc != 0 {
+        error!(
+            rc,
+            attr_handle, "Failed to start reading from the Current Time Service"
+        );
+    }
+}
+
+fn cts_read_current_time(conn_handle: u16) {
+    match with_cts(conn_handle, |client| client.current_time).flatten() {
+        Some((val_handle, _)) => cts_read(conn_handle, val_handle, on_current_time_read),
+        None => error!("Phone's Current Time Service has no Current Time"),
+    }
+}
+
+unsafe fn flatten_attr(attr: *mut ble_gatt_attr) -> Option<Vec<u8>> {
+    let mut buf = [0u8; cts::CURRENT_TIME_LEN];
+    let mut out_len = 0u16;
+    let rc = ble_hs_mbuf_to_flat(
+        (*attr).om,
+        &mut buf as *mut u8 as *mut _,
+        buf.len() as u16,
+        &mut out_len as *mut _,
+    );
+    (rc == 0).then(|| buf[..out_len as usize].to_vec())
+}
+
+unsafe extern "C" fn on_local_time_info_read(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    attr: *mut ble_gatt_attr,
+    _arg: *mut c_void,
+) -> c_int {
+    if (*error).status != 0 {
+        error!(
+            status = (*error).status,
+            "Reading Local Time Information failed"
+        );
+    } else {
+        match flatten_attr(attr).map(|buf| cts::parse_local_time_info(&buf)) {
+            Some(Ok(utc_offset)) => {
+                info!(?utc_offset, "Read Local Time Information");
+                with_cts(conn_handle, |client| client.utc_offset = utc_offset);
+            }
+            Some(Err(err)) => error!(?err, "Bad Local Time Information"),
+            None => error!("Couldn't fetch Local Time Information"),
+        }
+    }
+
+    // without it we'll just assume the phone is on UTC
+    cts_read_current_time(conn_handle);
+
+    0
+}
+
+unsafe extern "C" fn on_current_time_read(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    attr: *mut ble_gatt_attr,
+    _arg: *mut c_void,
+) -> c_int {
+    if (*error).status != 0 {
+        error!(status = (*error).status, "Reading Current Time failed");
+        return 0;
+    }
+
+    match flatten_attr(attr) {
+        Some(buf) => cts_publish(conn_handle, &buf),
+        None => error!("Couldn't fetch Current Time"),
+    }
+
+    if let Some((val_handle, end)) = with_cts(conn_handle, |client| client.current_time).flatten() {
+        let rc = ble_gattc_disc_all_dscs(
+            conn_handle,
+            val_handle,
+            end,
+            Some(on_current_time_dsc),
+            std::ptr::null_mut(),
+        );
+        if rc != 0 {
+            error!(rc, "Failed to start discovering Current Time descriptors");
+        }
+    }
+
+    0
+}
+
+unsafe extern "C" fn on_current_time_dsc(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    _chr_val_handle: u16,
+    dsc: *const ble_gatt_dsc,
+    _arg: *mut c_void,
+) -> c_int {
+    match (*error).status as u32 {
+        0 => {
+            let dsc = *dsc;
+            if ble_uuid_u16(&dsc.uuid.u) == BLE_CCCD {
+                with_cts(conn_handle, |client| client.cccd = Some(dsc.handle));
+            }
+        }
+        BLE_HS_EDONE => match with_cts(conn_handle, |client| client.cccd).flatten() {
+            Some(cccd) => {
+                let enable_notify = 1u16.to_le_bytes();
+                let rc = ble_gattc_write_flat(
+                    conn_handle,
+                    cccd,
+                    enable_notify.as_ptr() as *const _,
+                    enable_notify.len() as u16,
+                    Some(on_cccd_written),
+                    std::ptr::null_mut(),
+                );
+                if rc != 0 {
+                    error!(rc, "Failed to subscribe to Current Time");
+                    cts_discover_local_time_info_dscs(conn_handle);
+                }
+            }
+            None => {
+                info!("Current Time doesn't support notifications");
+                cts_discover_local_time_info_dscs(conn_handle);
+            }
+        },
+        status => error!(status, "Discovering Current Time descriptors failed"),
+    }
+
+    0
+}
+
+unsafe extern "C" fn on_cccd_written(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    _attr: *mut ble_gatt_attr,
+    _arg: *mut c_void,
+) -> c_int {
+    match (*error).status {
+        0 => info!("Subscribed to Current Time"),
+        status => error!(status, "Subscribing to Current Time failed"),
+    }
+
+    // only one procedure at a time, so the time zone comes after
+    cts_discover_local_time_info_dscs(conn_handle);
+
+    0
+}
+
+/// Looks for the CCCD of Local Time Information, which phones that notify
+/// time zone changes have
+fn cts_discover_local_time_info_dscs(conn_handle: u16) {
+    let (val_handle, end) = match with_cts(conn_handle, |client| client.local_time_info).flatten() {
+        Some((val_handle, end)) if end > val_handle => (val_handle, end),
+        _ => {
+            info!("Local Time Information doesn't support notifications");
+            return;
+        }
+    };
+
+    let rc = unsafe {
+        ble_gattc_disc_all_dscs(
+            conn_handle,
+            val_handle,
+            end,
+            Some(on_local_time_info_dsc),
+            std::ptr::null_mut(),
+        )
+    };
+    if rc != 0 {
+        error!(
+            rc,
+            "Failed to start discovering Local Time Information descriptors"
+        );
+    }
+}
+
+unsafe extern "C" fn on_local_time_info_dsc(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    _chr_val_handle: u16,
+    dsc: *const ble_gatt_dsc,
+    _arg: *mut c_void,
+) -> c_int {
+    match (*error).status as u32 {
+        0 => {
+            let dsc = *dsc;
+            if ble_uuid_u16(&dsc.uuid.u) == BLE_CCCD {
+                with_cts(conn_handle, |client| {
+                    client.local_time_info_cccd = Some(dsc.handle)
+                });
+            }
+        }
+        BLE_HS_EDONE => {
+            match with_cts(conn_handle, |client| client.local_time_info_cccd).flatten() {
+                Some(cccd) => {
+                    let enable_notify = 1u16.to_le_bytes();
+                    let rc = ble_gattc_write_flat(
+                        conn_handle,
+                        cccd,
+                        enable_notify.as_ptr() as *const _,
+                        enable_notify.len() as u16,
+                        Some(on_local_time_info_cccd_written),
+                        std::ptr::null_mut(),
+                    );
+                    if rc != 0 {
+                        error!(rc, "Failed to subscribe to Local Time Information");
+                    }
+                }
+                None => info!("Local Time Information doesn't support notifications"),
+            }
+        }
+        status => error!(
+            status,
+            "Discovering Local Time Information descriptors failed"
+        ),
+    }
+
+    0
+}
+
+unsafe extern "C" fn on_local_time_info_cccd_written(
+    _conn_handle: u16,
+    error: *const ble_gatt_error,
+    _attr: *mut ble_gatt_attr,
+    _arg: *mut c_void,
+) -> c_int {
+    match (*error).status {
+        0 => info!("Subscribed to Local Time Information"),
+        status => error!(status, "Subscribing to Local Time Information failed"),
+    }
+
+    0
+}
+
+/// Handles a notification, which is only interesting if it's from the Current
+/// Time Service
+unsafe fn cts_notified(conn_handle: u16, attr_handle: u16, om: *mut os_mbuf) {
+    let handles = with_cts(conn_handle, |client| {
+        (client.current_time, client.local_time_info)
+    });
+    let (current_time, local_time_info) = match handles {
+        Some(handles) => handles,
+        None => return,
+    };
+
+    if matches!(local_time_info, Some((val_handle, _)) if val_handle == attr_handle) {
+        let mut buf = [0u8; cts::LOCAL_TIME_INFO_LEN];
+        let mut out_len = 0u16;
+        let rc = ble_hs_mbuf_to_flat(
+            om,
+            &mut buf as *mut u8 as *mut _,
+            buf.len() as u16,
+            &mut out_len as *mut _,
+        );
+        if rc != 0 {
+            error!("Couldn't fetch Local Time Information notification");
+            return;
+        }
+
+        match cts::parse_local_time_info(&buf[..out_len as usize]) {
+            Ok(utc_offset) => {
+                info!(?utc_offset, "Phone changed time zone");
+                with_cts(conn_handle, |client| client.utc_offset = utc_offset);
+            }
+            Err(err) => error!(?err, "Bad Local Time Information"),
+        }
+        return;
+    }
+
+    if !matches!(current_time, Some((val_handle, _)) if val_handle == attr_handle) {
+        return;
+    }
+
+    let mut buf = [0u8; cts::CURRENT_TIME_LEN];
+    let mut out_len = 0u16;
+    let rc = ble_hs_mbuf_to_flat(
+        om,
+        &mut buf as *mut u8 as *mut _,
+        buf.len() as u16,
+        &mut out_len as *mut _,
+    );
+    if rc != 0 {
+        error!("Couldn't fetch Current Time notification");
+        return;
+    }
+
+    cts_publish(conn_handle, &buf[..out_len as usize]);
+}
+
+fn cts_publish(conn_handle: u16, buf: &[u8]) {
+    let utc_offset = with_cts(conn_handle, |client| client.utc_offset)
+        .flatten()
+        .unwrap_or(0);
+
+    let result = cts::parse_current_time(buf).and_then(|time| time.to_unix(utc_offset));
+    match result {
+        Ok((seconds, nanos)) => {
+            let now = eos::Timestamp::new(seconds, nanos).to_utc();
+            info!(%now, "Phone sent the current time");
+            let _ = TIME_UPDATES.0.send(now);
+        }
+        Err(err) => error!(?err, "Bad Current Time from phone"),
+    }
+}
+
+/// Where the iPhone's Notification Center Service lives, filled in as
+/// discovery goes, and the notifications we're fetching from it
+struct AncsClient {
+    conn_handle: u16,
+    svc_start: u16,
+    svc_end: u16,
+    notification_source: Option<u16>,
+    control_point: Option<u16>,
+    data_source: Option<u16>,
+    /// Every CCCD in the service, sorted by handle
+    cccds: Vec<u16>,
+    /// Notifications waiting for their attributes to be fetched
+    queue: VecDeque<ancs::NotificationSource>,
+    /// The notification being fetched, and as much of the response as has
+    /// arrived
+    fetching: Option<(ancs::NotificationSource, Vec<u8>)>,
+}
+
+impl AncsClient {
+    /// The CCCD belonging to the characteristic with `val_handle`, which is
+    /// the first one after it
+    fn cccd_of(&self, val_handle: Option<u16>) -> Option<u16> {
+        let val_handle = val_handle?;
+        self.cccds.iter().copied().find(|&cccd| cccd > val_handle)
+    }
+}
+
+static ANCS: Mutex<Option<AncsClient>> = Mutex::new(None);
+
+/// At most this many notifications wait for their attributes, after that the
+/// oldest are dropped
+const ANCS_QUEUE_LEN: usize = 8;
+
+/// Runs `f` on the client for `conn_handle`, if we still have one
+fn with_ancs<T>(conn_handle: u16, f: impl FnOnce(&mut AncsClient) -> T) -> Option<T> {
+    ANCS.lock()
+        .unwrap()
+        .as_mut()
+        .filter(|client| client.conn_handle == conn_handle)
+        .map(f)
+}
+
+/// Looks for the Notification Center Service on a bonded iPhone, and once
+/// found subscribes to its notifications. The Current Time Service is looked
+/// for afterwards either way, as only one GATT procedure can run at once.
+fn ancs_discover(conn_handle: u16) {
+    *ANCS.lock().unwrap() = Some(AncsClient {
+        conn_handle,
+        svc_start: 0,
+        svc_end: 0,
+        notification_source: None,
+        control_point: None,
+        data_source: None,
+        cccds: Vec::new(),
+        queue: VecDeque::new(),
+        fetching: None,
+    });
+
+    let rc = unsafe {
+        ble_gattc_disc_svc_by_uuid(
+            conn_handle,
+            &BLE_ANCS_SERVICE.u,
+            Some(on_ancs_svc),
+            std::ptr::null_mut(),
+        )
+    };
+    if rc != 0 {
+        error!(
+            rc,
+            "Failed to start discovering the Notification Center Service"
+        );
+        cts_discover(conn_handle);
+    }
+}
+
+unsafe extern "C" fn on_ancs_svc(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    service: *const ble_gatt_svc,
+    _arg: *mut c_void,
+) -> c_int {
+    match (*error).status as u32 {
+        0 => {
+            let service = *service;
+            with_ancs(conn_handle, |client| {
+                client.svc_start = service.start_handle;
+                client.svc_end = service.end_handle;
+            });
+        }
+        BLE_HS_EDONE => {
+            let range = with_ancs(conn_handle, |client| (client.svc_start, client.svc_end));
+            match range {
+                Some((start, end)) if start != 0 => {
+                    let rc = ble_gattc_disc_all_chrs(
+                        conn_handle,
+                        start,
+                        end,
+                        Some(on_ancs_chr),
+                        std::ptr::null_mut(),
+                    );
+                    if rc != 0 {
+                        error!(rc, "Failed to start discovering ANCS characteristics");
+                        cts_discover(conn_handle);
+                    }
+                }
+                _ => {
+                    info!("Phone has no Notification Center Service");
+                    cts_discover(conn_handle);
+                }
+            }
+        }
+        status => {
+            error!(status, "Discovering the Notification Center Service failed");
+            cts_discover(conn_handle);
+        }
+    }
+
+    0
+}
+
+unsafe extern "C" fn on_ancs_chr(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    chr: *const ble_gatt_chr,
+    _arg: *mut c_void,
+) -> c_int {
+    match (*error).status as u32 {
+        0 => {
+            let chr = *chr;
+            let uuid = &chr.uuid.u;
+            with_ancs(conn_handle, |client| {
+                if ble_uuid_cmp(uuid, &BLE_ANCS_NOTIFICATION_SOURCE_CHAR.u) == 0 {
+                    client.notification_source = Some(chr.val_handle);
+                } else if ble_uuid_cmp(uuid, &BLE_ANCS_CONTROL_POINT_CHAR.u) == 0 {
+                    client.control_point = Some(chr.val_handle);
+                } else if ble_uuid_cmp(uuid, &BLE_ANCS_DATA_SOURCE_CHAR.u) == 0 {
+                    client.data_source = Some(chr.val_handle);
+                }
+            });
+        }
+        BLE_HS_EDONE => {
+            let range = with_ancs(conn_handle, |client| {
+                let found = client.notification_source.is_some()
+                    && client.control_point.is_some()
+                    && client.data_source.is_some();
+                found.then(|| (client.svc_start, client.svc_end))
+            })
+            .flatten();
+            match range {
+                Some((start, end)) => {
+                    let rc = ble_gattc_disc_all_dscs(
+                        conn_handle,
+                        start,
+                        end,
+                        Some(on_ancs_dsc),
+                        std::ptr::null_mut(),
+                    );
+                    if rc != 0 {
+                        error!(rc, "Failed to start discovering ANCS descriptors");
+                        cts_discover(conn_handle);
+                    }
+                }
+                None => {
+                    error!("Phone's Notification Center Service is missing characteristics");
+                    cts_discover(conn_handle);
+                }
+            }
+        }
+        status => {
+            error!(status, "Discovering ANCS characteristics failed");
+            cts_discover(conn_handle);
+        }
+    }
+
+    0
+}
+
+unsafe extern "C" fn on_ancs_dsc(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    _chr_val_handle: u16,
+    dsc: *const ble_gatt_dsc,
+    _arg: *mut c_void,
+) -> c_int {
+    match (*error).status as u32 {
+        0 => {
+            let dsc = *dsc;
+            if ble_uuid_u16(&dsc.uuid.u) == BLE_CCCD {
+                with_ancs(conn_handle, |client| client.cccds.push(dsc.handle));
+            }
+        }
+        // the Data Source first, so we don't miss the answer to anything the
+        // Notification Source makes us ask
+        BLE_HS_EDONE => {
+            match with_ancs(conn_handle, |client| client.cccd_of(client.data_source)).flatten() {
+                Some(cccd) => ancs_subscribe(conn_handle, cccd, on_ancs_data_source_subscribed),
+                None => {
+                    error!("ANCS Data Source has no CCCD");
+                    cts_discover(conn_handle);
+                }
+            }
+        }
+        status => {
+            error!(status, "Discovering ANCS descriptors failed");
+            cts_discover(conn_handle);
+        }
+    }
+
+    0
+}
+
+fn ancs_subscribe(
+    conn_handle: u16,
+    cccd: u16,
+    cb: unsafe extern "C" fn(u16, *const ble_gatt_error, *mut ble_gatt_attr, *mut c_void) -> c_int,
+) {
+    let enable_notify = 1u16.to_le_bytes();
+    let rc = unsafe {
+        ble_gattc_write_flat(
+            conn_handle,
+            cccd,
+            enable_notify.as_ptr() as *const _,
+            enable_notify.len() as u16,
+            Some(cb),
+            std::ptr::null_mut(),
+        )
+    };
+    if rc != 0 {
+        error!(rc, cccd, "Failed to subscribe to ANCS");
+        cts_discover(conn_handle);
+    }
+}
+
+unsafe extern "C" fn on_ancs_data_source_subscribed(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    _attr: *mut ble_gatt_attr,
+    _arg: *mut c_void,
+) -> c_int {
+    if (*error).status != 0 {
+        error!(
+            status = (*error).status,
+            "Subscribing to ANCS Data Source failed"
+        );
+        cts_discover(conn_handle);
+        return 0;
+    }
+
+    match with_ancs(conn_handle, |client| {
+        client.cccd_of(client.notification_source)
+    })
+    .flatten()
+    {
+        Some(cccd) => ancs_subscribe(conn_handle, cccd, on_ancs_subscribed),
+        None => {
+            error!("ANCS Notification Source has no CCCD");
+            cts_discover(conn_handle);
+        }
+    }
 
-unsafe extern "C" fn ble_spp_server_on_reset(reason: c_int) {
-    info!(reason, "Resetting ble");
+    0
 }
 
-static mut OWN_ADDR_TYPE: u8 = 0;
+unsafe extern "C" fn on_ancs_subscribed(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    _attr: *mut ble_gatt_attr,
+    _arg: *mut c_void,
+) -> c_int {
+    match (*error).status {
+        0 => info!("Subscribed to ANCS"),
+        status => error!(status, "Subscribing to ANCS Notification Source failed"),
+    }
+    cts_discover(conn_handle);
 
-unsafe extern "C" fn ble_spp_server_on_sync() {
-    let rc = ble_hs_util_ensure_addr(0);
-    assert_eq!(rc, 0, "ble_hs_util_ensure_addr");
+    0
+}
 
-    let rc = ble_hs_id_infer_auto(0, &mut OWN_ADDR_TYPE as *mut _);
+/// Handles a notification, which is only interesting if it's from the
+/// Notification Source or Data Source
+unsafe fn ancs_notified(conn_handle: u16, attr_handle: u16, om: *mut os_mbuf) {
+    let handles = with_ancs(conn_handle, |client| {
+        (client.notification_source, client.data_source)
+    });
+    let (notification_source, data_source) = match handles {
+        Some(handles) => handles,
+        None => return,
+    };
+    if Some(attr_handle) != notification_source && Some(attr_handle) != data_source {
+        return;
+    }
+
+    let mut buf = [0u8; MAX_ATTR_LEN];
+    let mut out_len = 0u16;
+    let rc = ble_hs_mbuf_to_flat(
+        om,
+        &mut buf as *mut u8 as *mut _,
+        MAX_ATTR_LEN as u16,
+        &mut out_len as *mut _,
+    );
     if rc != 0 {
-        error!(rc, "Failed to determine address type");
+        error!("Couldn't fetch ANCS notification");
+        return;
+    }
+    let buf = &buf[..out_len as usize];
+
+    if Some(attr_handle) == notification_source {
+        match ancs::parse_notification_source(buf) {
+            Ok(source) if source.is_new() => {
+                with_ancs(conn_handle, |client| {
+                    if client.queue.len() >= ANCS_QUEUE_LEN {
+                        client.queue.pop_front();
+                    }
+                    client.queue.push_back(source);
+                });
+                ancs_fetch_next(conn_handle);
+            }
+            Ok(source) => info!(?source, "Ignoring ANCS notification"),
+            Err(err) => error!(?err, "Bad ANCS Notification Source"),
+        }
         return;
     }
 
-    let mut addr_val = [0u8; 6];
-    ble_hs_id_copy_addr(OWN_ADDR_TYPE, &mut addr_val as *mut _, std::ptr::null_mut());
+    let result = with_ancs(conn_handle, |client| {
+        let (source, response) = client.fetching.as_mut()?;
+        response.extend_from_slice(buf);
+        match ancs::parse_attributes(response) {
+            Ok(None) => None,
+            Ok(Some(attrs)) => Some(Ok((*source, attrs))),
+            Err(err) => Some(Err(err)),
+        }
+        .map(|result| {
+            client.fetching = None;
+            result
+        })
+    })
+    .flatten();
+
+    match result {
+        Some(Ok((source, attrs))) => {
+            ancs_publish(&source, attrs);
+            ancs_fetch_next(conn_handle);
+        }
+        Some(Err(err)) => {
+            error!(?err, "Bad ANCS Data Source response");
+            ancs_fetch_next(conn_handle);
+        }
+        None => {}
+    }
+}
 
-    info!(device_address = ?addr_val, "Found device address");
+/// Asks for the attributes of the next queued notification, unless we're
+/// already waiting on some
+fn ancs_fetch_next(conn_handle: u16) {
+    let next = with_ancs(conn_handle, |client| {
+        if client.fetching.is_some() {
+            return None;
+        }
+        let source = client.queue.pop_front()?;
+        client.fetching = Some((source, Vec::new()));
+        Some((source, client.control_point?))
+    })
+    .flatten();
+
+    if let Some((source, control_point)) = next {
+        let cmd = ancs::get_notification_attributes(source.uid);
+        ancs_write_control_point(
+            conn_handle,
+            control_point,
+            &cmd,
+            on_ancs_attributes_requested,
+        );
+    }
+}
 
-    ble_spp_server_advertise();
+fn ancs_write_control_point(
+    conn_handle: u16,
+    control_point: u16,
+    cmd: &[u8],
+    cb: unsafe extern "C" fn(u16, *const ble_gatt_error, *mut ble_gatt_attr, *mut c_void) -> c_int,
+) {
+    let rc = unsafe {
+        ble_gattc_write_flat(
+            conn_handle,
+            control_point,
+            cmd.as_ptr() as *const _,
+            cmd.len() as u16,
+            Some(cb),
+            std::ptr::null_mut(),
+        )
+    };
+    if rc != 0 {
+        error!(rc, "Failed to write to the ANCS Control Point");
+    }
 }
 
-unsafe extern "C" fn ble_batt_handler(
-    _conn_handle: u16,
-    _attr_handle: u16,
-    ctxt: *mut ble_gatt_access_ctxt,
+unsafe extern "C" fn on_ancs_attributes_requested(
+    conn_handle: u16,
+    error: *const ble_gatt_error,
+    _attr: *mut ble_gatt_attr,
     _arg: *mut c_void,
-) -> i32 {
-    let ctxt_ = *ctxt;
+) -> c_int {
+    // usually that the notification has gone already
+    if (*error).status != 0 {
+        error!(
+            status = (*error).status,
+            "Requesting ANCS attributes failed"
+        );
+        with_ancs(conn_handle, |client| client.fetching = None);
+        ancs_fetch_next(conn_handle);
+    }
 
-    let uuid = (*ctxt_.__bindgen_anon_1.chr).uuid;
+    0
+}
 
-    assert_eq!(ble_uuid_cmp(uuid, &BLE_BAT_CHAR.u), 0);
-    assert_eq!(ctxt_.op as u32, BLE_GATT_ACCESS_OP_READ_CHR);
+unsafe extern "C" fn on_ancs_action_performed(
+    _conn_handle: u16,
+    error: *const ble_gatt_error,
+    _attr: *mut ble_gatt_attr,
+    _arg: *mut c_void,
+) -> c_int {
+    match (*error).status {
+        0 => info!("Performed ANCS action"),
+        status => error!(status, "Performing ANCS action failed"),
+    }
 
-    let batt_level = BATTERY_PERCENT.load(std::sync::atomic::Ordering::Relaxed);
-    info!(batt_level, "Reading battery level");
+    0
+}
 
-    let rc = os_mbuf_append(
-        ctxt_.om,
-        &batt_level as *const u8 as *const _,
-        std::mem::size_of::<u8>() as u16,
-    );
-    if rc != 0 {
-        return BLE_ATT_ERR_INSUFFICIENT_RES as i32;
+/// Hands an iPhone notification to whatever shows the app's notifications
+fn ancs_publish(source: &ancs::NotificationSource, attrs: ancs::Attributes) {
+    let notif = ancs::push_notification(source, attrs);
+    info!(id = notif.id, app_id = %notif.app_id, "iPhone sent a notification");
+
+    let msg = message::Message {
+        origin: ancs::ORIGIN,
+        body: Some(message::message::Body::PushNotification(notif)),
+        ..Default::default()
+    };
+    if let Err(err) = message::push_message(msg) {
+        error!(?err, "Failed to hand on an iPhone notification");
     }
+}
 
-    0
+/// Answers an iPhone notification with one of its actions
+pub fn ancs_perform_action(uid: u32, action: ancs::Action) {
+    let client = ANCS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|client| Some((client.conn_handle, client.control_point?)));
+
+    match client {
+        Some((conn_handle, control_point)) => {
+            let cmd = ancs::perform_notification_action(uid, action);
+            ancs_write_control_point(conn_handle, control_point, &cmd, on_ancs_action_performed);
+        }
+        None => error!(uid, "No iPhone to answer a notification on"),
+    }
 }
 
-unsafe extern "C" fn ble_data_in_handler(
+struct NotifyLink {
     conn_handle: u16,
     attr_handle: u16,
-    ctxt: *mut ble_gatt_access_ctxt,
-    _arg: *mut c_void,
-) -> i32 {
-    let ctxt_ = *ctxt;
-    match ctxt_.op as u32 {
-        BLE_GATT_ACCESS_OP_WRITE_CHR => {
-            let len = (*ctxt_.om).om_len;
+    /// Send as an indication, which the phone confirms with an
+    /// [`INDICATIONS`] event
+    indicate: bool,
+    /// Back off and try again when NimBLE is out of buffers. This sleeps, so
+    /// mustn't be used from the host task, which is what frees them.
+    retry: bool,
+}
 
-            info!(
-                conn_handle,
-                attr_handle, len, "Data received in write event"
-            );
+impl NotifyLink {
+    fn new(conn_handle: u16, attr_handle: u16) -> Self {
+        Self {
+            conn_handle,
+            attr_handle,
+            indicate: false,
+            retry: false,
+        }
+    }
 
-            let mut buf = [0u8; 256];
-            let mut out_len = 0u16;
-            let rc = ble_hs_mbuf_to_flat(
-                ctxt_.om,
-                &mut buf as *mut u8 as *mut _,
-                std::mem::size_of::<[u8; 256]>() as u16,
-                &mut out_len as *mut _,
-            );
-            if rc != 0 {
-                error!("Couldn't fetch mbuf in write handler");
-                return 0;
-            }
-            let buf = &buf[..out_len as usize];
-
-            let msg = match message::Message::decode(buf)
-                .map_err(|err| eyre!("Failed to decode message: {:?}", err))
-                .and_then(message::validate_msg)
-            {
-                Ok(msg) => msg,
-                Err(err) => {
-                    error!(?err, "While decoding a message");
-                    return 0;
-                }
-            };
+    /// Hands `fragment` to NimBLE, returning its error code if it refused
+    fn try_send(&self, fragment: &[u8]) -> Result<(), c_int> {
+        let txom: *mut os_mbuf =
+            unsafe { ble_hs_mbuf_from_flat(fragment.as_ptr() as *const _, fragment.len() as u16) };
+        if txom.is_null() {
+            return Err(BLE_HS_ENOMEM as c_int);
+        }
+
+        // NimBLE takes the mbuf whether or not this succeeds
+        let rc = if self.indicate {
+            unsafe { ble_gattc_indicate_custom(self.conn_handle, self.attr_handle, txom) }
+        } else {
+            unsafe { ble_gattc_notify_custom(self.conn_handle, self.attr_handle, txom) }
+        };
+
+        match rc {
+            0 => Ok(()),
+            rc => Err(rc),
+        }
+    }
+}
 
-            info!(?msg, "Got message");
+impl framing::Link for NotifyLink {
+    fn max_fragment_len(&self) -> usize {
+        let mtu = connections::get(self.conn_handle).map_or(DEFAULT_MTU, |conn| conn.mtu);
+        (mtu - ATT_HEADER_LEN) as usize
+    }
 
-            if let Err(err) = message::push_message(msg) {
-                error!(?err, "Failed to push message");
+    fn send_fragment(&mut self, fragment: &[u8]) -> color_eyre::Result<()> {
+        let mut attempt = 0;
+        while let Err(rc) = self.try_send(fragment) {
+            if rc != BLE_HS_ENOMEM as c_int || !self.retry || attempt == delivery::MAX_RETRIES {
+                delivery::failed();
+                return Err(eyre!("Notifying failed with NimBLE error {}", rc));
             }
+
+            delivery::retried();
+            std::thread::sleep(delivery::backoff(attempt));
+            attempt += 1;
         }
-        _ => {}
+
+        delivery::sent();
+
+        Ok(())
     }
+}
 
-    0
+/// Indications that have been confirmed or failed, by connection
+static INDICATIONS: Lazy<(
+    channel::Sender<(u16, c_int)>,
+    channel::Receiver<(u16, c_int)>,
+)> = Lazy::new(|| channel::bounded(16));
+
+/// A batch being indicated to one connection, a fragment at a time since the
+/// phone confirms each before the next can go
+struct InFlight {
+    /// What's left to send once the fragment out now is confirmed
+    fragments: VecDeque<Vec<u8>>,
+    /// Notifications in the batch
+    count: usize,
+    /// When to give up on the fragment out now
+    deadline: Instant,
 }
 
-pub fn ble_spp_server_advertise() {
-    // if we're exposing this we should prevent concurrent usage
-    // even this might not be safe, we should probably be doing a channel
-    static ADV_MUTEX: Mutex<()> = Mutex::new(());
-    let _handle = ADV_MUTEX.lock().unwrap();
-    unsafe {
-        let name = ble_svc_gap_device_name();
-        static UUIDS16: &[ble_uuid16_t] = &[unsafe { BLE_BAT_SERVICE }];
-        // static UUIDS128: &[ble_uuid128_t] = &[unsafe { BLE_LE_NRF_SERVICE }];
+/// Indications waiting to be confirmed, which hold up only the connection
+/// they're going to
+#[derive(Default)]
+struct Indications(HashMap<u16, InFlight>);
 
-        let mut fields = ble_hs_adv_fields {
-            flags: (BLE_HS_ADV_F_DISC_GEN | BLE_HS_ADV_F_BREDR_UNSUP) as u8,
-            tx_pwr_lvl: BLE_HS_ADV_TX_PWR_LVL_AUTO as i8,
-            name: name as *const _,
-            name_len: strlen(name) as u8,
-            uuids16: UUIDS16.as_ptr(),
-            num_uuids16: UUIDS16.len() as u8,
-            adv_itvl: 8000,
-            // uuids128: UUIDS128.as_ptr(),
-            // num_uuids128: UUIDS128.len() as u8,
-            ..Default::default()
+impl Indications {
+    fn busy(&self, conn_handle: u16) -> bool {
+        self.0.contains_key(&conn_handle)
+    }
+
+    /// Starts indicating `payload` to `conn_handle`
+    fn start(
+        &mut self,
+        conn_handle: u16,
+        fragmenter: &mut framing::Fragmenter,
+        payload: &[u8],
+        count: usize,
+    ) -> color_eyre::Result<()> {
+        let mut link = NotifyLink {
+            indicate: true,
+            retry: true,
+            ..NotifyLink::new(conn_handle, BLE_DATA_OUT_HANDLE.get())
         };
+        let mut fragments: VecDeque<_> = fragmenter
+            .fragment(payload, link.max_fragment_len())?
+            .into();
+
+        link.send_fragment(&fragments.pop_front().unwrap())?;
+        self.0.insert(
+            conn_handle,
+            InFlight {
+                fragments,
+                count,
+                deadline: Instant::now() + delivery::INDICATION_TIMEOUT,
+            },
+        );
 
-        fields.set_tx_pwr_lvl_is_present(1);
-        fields.set_name_is_complete(1);
-        fields.set_uuids16_is_complete(1);
-        fields.set_adv_itvl_is_present(1);
+        Ok(())
+    }
 
-        let rc = ble_gap_adv_set_fields(&fields);
-        if rc != 0 {
-            error!(rc, "error setting advertisement data");
+    /// Handles the phone confirming or failing an indication, sending the
+    /// next fragment if there is one. Once the whole batch is confirmed, the
+    /// connection's cursor moves past it.
+    fn done(&mut self, cursors: &mut Cursors, conn_handle: u16, status: c_int) {
+        let mut in_flight = match self.0.remove(&conn_handle) {
+            Some(in_flight) => in_flight,
+            None => return,
+        };
+
+        if status != BLE_HS_EDONE as c_int {
+            delivery::failed();
+            error!(conn_handle, status, "Indication failed, resending later");
             return;
         }
+        delivery::indicated();
 
-        let adv_params = ble_gap_adv_params {
-            conn_mode: BLE_GAP_CONN_MODE_UND as u8,
-            disc_mode: BLE_GAP_DISC_MODE_GEN as u8,
-            ..Default::default()
-        };
-
-        let duration_ms = Duration::from_secs(60).as_millis() as i32;
-        let rc = ble_gap_adv_start(
-            OWN_ADDR_TYPE,
-            std::ptr::null(),
-            duration_ms,
-            &adv_params,
-            Some(ble_spp_server_gap_event),
-            std::ptr::null_mut(),
-        );
-        if rc != 0 {
-            error!(rc, "error enabling advertisement");
+        if let Some(fragment) = in_flight.fragments.pop_front() {
+            let mut link = NotifyLink {
+                indicate: true,
+                retry: true,
+                ..NotifyLink::new(conn_handle, BLE_DATA_OUT_HANDLE.get())
+            };
+            match link.send_fragment(&fragment) {
+                Ok(()) => {
+                    in_flight.deadline = Instant::now() + delivery::INDICATION_TIMEOUT;
+                    self.0.insert(conn_handle, in_flight);
+                }
+                Err(err) => error!(?err, conn_handle, "Error indicating, holding it for later"),
+            }
             return;
         }
 
-        info!("started to advertise");
+        cursors.advance(conn_handle, in_flight.count);
+        if in_flight.count > 1 {
+            delivery::coalesced(in_flight.count);
+        }
+        info!(conn_handle, count = in_flight.count, "Indicated notif");
     }
-}
 
-unsafe extern "C" fn ble_spp_server_gap_event(event: *mut ble_gap_event, _arg: *mut c_void) -> i32 {
-    let event_ = *event;
-    let mut desc = ble_gap_conn_desc::default();
+    /// Follows the outbox throwing away the notification at `index`, before
+    /// `cursors` do. A batch that had it in still moves its connection on by
+    /// what's left of it.
+    fn removed(&mut self, cursors: &Cursors, index: usize) {
+        for (&conn_handle, in_flight) in &mut self.0 {
+            let start = cursors.get(conn_handle);
+            if (start..start + in_flight.count).contains(&index) {
+                in_flight.count -= 1;
+            }
+        }
+    }
 
-    match event_.type_ as u32 {
-        BLE_GAP_EVENT_CONNECT => {
-            let connect = event_.__bindgen_anon_1.connect;
-            info!(
-                status = connect.status,
-                "connection {}",
-                if connect.status == 0 {
-                    "established"
-                } else {
-                    "failed"
-                }
-            );
+    /// When the next indication will have taken too long
+    fn next_deadline(&self) -> Option<Instant> {
+        self.0.values().map(|in_flight| in_flight.deadline).min()
+    }
 
-            if connect.status == 0 {
-                let rc = ble_gap_conn_find(connect.conn_handle, &mut desc as *mut _);
-                assert_eq!(rc, 0, "ble_gap_conn_find");
-                info!(handle = connect.conn_handle, ?desc, "Conn desc");
-                CONNECTION_HANDLES[connect.conn_handle as usize] = connect.conn_handle;
-                CONN_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    /// Gives up on indications that haven't been confirmed in time, and ones
+    /// to connections that are gone. Returns whether any were given up on.
+    fn expire(&mut self, now: Instant, subscribers: &[u16]) -> bool {
+        let before = self.0.len();
+        self.0.retain(|conn_handle, in_flight| {
+            if !subscribers.contains(conn_handle) {
+                return false;
             }
-
-            if connect.status != 0 {
-                ble_spp_server_advertise();
+            if in_flight.deadline <= now {
+                delivery::failed();
+                error!(conn_handle, "The phone didn't confirm an indication");
+                return false;
             }
-        }
+            true
+        });
+        self.0.len() != before
+    }
+}
 
-        BLE_GAP_EVENT_DISCONNECT => {
-            let disconnect = event_.__bindgen_anon_1.disconnect;
-            info!(reason = disconnect.reason, "Disconnect");
-            CONN_COUNT.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+fn tx_thread() {
+    let store = match storage::OutboxStore::open() {
+        Ok(store) => Some(Box::new(store) as Box<dyn outbox::Store>),
+        Err(err) => {
+            error!(
+                ?err,
+                "Couldn't open the outbox in NVS, only keeping it in RAM"
+            );
+            None
         }
+    };
+    let mut outbox = Outbox::new(outbox::current(), store);
+    let mut cursors = Cursors::default();
+    let mut indications = Indications::default();
+    let mut fragmenter = framing::Fragmenter::new();
+    // whether the last flush left something behind, to be tried again
+    let mut held = false;
+
+    loop {
+        let retry = if held {
+            channel::after(delivery::HELD_RETRY_INTERVAL)
+        } else {
+            channel::never()
+        };
+        let timeout = match indications.next_deadline() {
+            Some(deadline) => channel::at(deadline),
+            None => channel::never(),
+        };
 
-        BLE_GAP_EVENT_CONN_UPDATE => {
-            let conn_update = event_.__bindgen_anon_1.conn_update;
-            info!(status = conn_update.status, "Connection update");
-            let rc = ble_gap_conn_find(conn_update.conn_handle, &mut desc as *mut _);
-            assert_eq!(rc, 0, "ble_gap_conn_find");
-            info!(?desc, "Conn desc");
+        channel::select! {
+            recv(OUTBOUND.1) -> buf => match buf {
+                Ok(buf) => {
+                    let removed = outbox.push(buf);
+                    follow_removals(&removed, &mut cursors, &mut indications);
+                }
+                Err(_) => return,
+            },
+            recv(INDICATIONS.1) -> done => {
+                if let Ok((conn_handle, status)) = done {
+                    indications.done(&mut cursors, conn_handle, status);
+                }
+            }
+            recv(FLUSH.1) -> _ => {}
+            recv(retry) -> _ => {}
+            recv(timeout) -> _ => {}
         }
 
-        BLE_GAP_EVENT_ADV_COMPLETE => {
-            let adv_complete = event_.__bindgen_anon_1.adv_complete;
-            info!(reason = adv_complete.reason, "advertise complete");
-        }
+        let removed = outbox.configure(outbox::current());
+        follow_removals(&removed, &mut cursors, &mut indications);
 
-        BLE_GAP_EVENT_MTU => {
-            let mtu = event_.__bindgen_anon_1.mtu;
+        let subscribers = connections::subscribers(BLE_DATA_OUT_HANDLE.get());
+        // whatever was given up on goes again once the phone has had a moment
+        let expired = indications.expire(Instant::now(), &subscribers);
+        if subscribers.is_empty() {
             info!(
-                conn_handle = mtu.conn_handle,
-                channel_id = mtu.channel_id,
-                value = mtu.value,
-                "mtu update"
+                pending = outbox.len(),
+                evicted = outbox.evicted(),
+                "Nobody subscribed, holding notifications"
+            );
+            held = false;
+        } else {
+            let caught_up = flush(
+                &mut outbox,
+                &mut cursors,
+                &mut indications,
+                &mut fragmenter,
+                &subscribers,
             );
+            held = !caught_up || expired;
         }
 
-        _ => {}
+        delivery::set_outbox(outbox.len(), outbox.evicted());
     }
-
-    0
 }
-// sepples moment
 
-unsafe extern "C" fn gatt_svr_register_cb(ctxt: *mut ble_gatt_register_ctxt, _arg: *mut c_void) {
-    let mut buf = [0 as c_char; BLE_UUID_STR_LEN as usize];
-    let ctxt_ = *ctxt;
+/// Keeps everyone's place in the outbox once it's thrown notifications away,
+/// so nobody skips one or gets one twice
+fn follow_removals(removed: &[usize], cursors: &mut Cursors, indications: &mut Indications) {
+    for &index in removed {
+        indications.removed(cursors, index);
+        cursors.removed(index);
+    }
+}
 
-    match ctxt_.op as u32 {
-        BLE_GATT_REGISTER_OP_SVC => {
-            let svc = ctxt_.__bindgen_anon_1.svc;
-            let uuid = CStr::from_ptr(ble_uuid_to_str((*svc.svc_def).uuid, &mut buf as *mut _));
-            info!(uuid = ?uuid, handle = ?svc.handle, "Registering gatt service");
-        }
-        BLE_GATT_REGISTER_OP_CHR => {
-            let chr = ctxt_.__bindgen_anon_1.chr;
-            let uuid = CStr::from_ptr(ble_uuid_to_str((*chr.chr_def).uuid, &mut buf as *mut _));
-            info!(uuid = ?uuid, def_handle = ?chr.def_handle, val_handle = ?chr.val_handle, "Registering characteristic");
+/// Sends each subscriber everything in the outbox it doesn't have yet, oldest
+/// first, batching up whatever fits in one write. A subscriber stops at its
+/// first failure so that nothing is reordered, and picks up from there next
+/// time, and one waiting on an indication picks up once it's confirmed.
+/// Whatever every subscriber has is removed from the outbox.
+/// Returns whether every subscriber got everything it could be sent.
+fn flush(
+    outbox: &mut Outbox,
+    cursors: &mut Cursors,
+    indications: &mut Indications,
+    fragmenter: &mut framing::Fragmenter,
+    subscribers: &[u16],
+) -> bool {
+    let attr_handle = BLE_DATA_OUT_HANDLE.get();
+    let mut caught_up = true;
+
+    cursors.set_subscribers(subscribers);
+
+    for &conn_handle in subscribers {
+        if indications.busy(conn_handle) {
+            continue;
         }
-        BLE_GATT_REGISTER_OP_DSC => {
-            let dsc = ctxt_.__bindgen_anon_1.dsc;
-            let uuid = CStr::from_ptr(ble_uuid_to_str((*dsc.dsc_def).uuid, &mut buf as *mut _));
-            info!(uuid = ?uuid, handle = ?dsc.handle, "Registering gatt service");
+
+        if outbox.len().saturating_sub(cursors.get(conn_handle)) > 1 {
+            busy(conn_handle);
         }
-        _ => {}
-    }
-}
 
-fn tx_thread() {
-    let receiver = QUEUE.1.clone();
+        let max_len = NotifyLink::new(conn_handle, attr_handle)
+            .max_fragment_len()
+            .saturating_sub(framing::FIRST_HEADER_LEN);
+
+        loop {
+            let start = cursors.get(conn_handle);
+            let items = (start..outbox.len()).map_while(|i| outbox.get(i));
+            let batch = match delivery::coalesce(items, max_len) {
+                Some(batch) => batch,
+                // a spilled notification that can't be read, which nobody
+                // will ever get
+                None if start < outbox.len() => {
+                    cursors.advance(conn_handle, 1);
+                    continue;
+                }
+                None => break,
+            };
+
+            if connections::indicate(conn_handle, attr_handle, batch.must_deliver) {
+                if let Err(err) =
+                    indications.start(conn_handle, fragmenter, &batch.payload, batch.count)
+                {
+                    error!(?err, conn_handle, "Error indicating, holding it for later");
+                    caught_up = false;
+                }
+                // the rest waits until the phone confirms this
+                break;
+            }
 
-    for msg in receiver {
-        let att_handle = unsafe { *BLE_DATA_OUT_HANDLE.get() };
-        let buf = msg.encode_to_vec();
-        for &handle in unsafe { &CONNECTION_HANDLES } {
-            let txom: *mut os_mbuf =
-                unsafe { ble_hs_mbuf_from_flat(buf.as_ptr() as *const _, buf.len() as u16) };
+            let mut link = NotifyLink {
+                retry: true,
+                ..NotifyLink::new(conn_handle, attr_handle)
+            };
 
-            let rc = unsafe { ble_gattc_notify_custom(handle, att_handle, txom) };
-            if rc == 0 {
-                info!("Sent notif");
-            } else {
-                info!(rc, "Error sending notif");
+            if let Err(err) = framing::send(&mut link, fragmenter, &batch.payload) {
+                error!(
+                    ?err,
+                    conn_handle,
+                    pending = outbox.len() - start,
+                    "Error sending notif, holding it for later"
+                );
+                caught_up = false;
+                break;
             }
+
+            cursors.advance(conn_handle, batch.count);
+            if batch.count > 1 {
+                delivery::coalesced(batch.count);
+            }
+            info!(conn_handle, count = batch.count, "Sent notif");
         }
     }
-}
 
-static mut CONNECTION_HANDLES: [u16; CONFIG_BT_NIMBLE_MAX_CONNECTIONS as usize] =
-    [0; CONFIG_BT_NIMBLE_MAX_CONNECTIONS as usize];
+    outbox.remove(cursors.take_delivered());
+
+    caught_up
+}
 
 unsafe extern "C" fn ble_spp_server_host_task(_param: *mut c_void) {
     info!("BLE host task started");
 
+    // returns once nimble_port_stop is called, and the task deletes itself
     nimble_port_run();
     nimble_port_freertos_deinit();
 }
 
-unsafe extern "C" fn stop_fn(_status: c_int, arg: *mut c_void) {
-    info!("Bluetooth hs seems to have stopped");
-    // just serves to drop the handle
-    let _h = Arc::from_raw(arg as *mut _ as *const MutexGuard<()>);
+/// Whether the stack is up, cleared before stopping it so nothing else calls
+/// into NimBLE on the way down
+static RADIO_ON: AtomicBool = AtomicBool::new(false);
+
+pub fn radio_on() -> bool {
+    RADIO_ON.load(Ordering::SeqCst)
+}
+
+/// Whether we're advertising at the moment
+pub fn advertising() -> bool {
+    radio_on() && unsafe { ble_gap_adv_active() } != 0
 }
 
+static HOST_STOPPED: Lazy<(channel::Sender<c_int>, channel::Receiver<c_int>)> =
+    Lazy::new(|| channel::bounded(1));
+
+unsafe extern "C" fn stop_fn(status: c_int, _arg: *mut c_void) {
+    info!(status, "Bluetooth hs seems to have stopped");
+    let _ = HOST_STOPPED.0.try_send(status);
+}
+
+/// Held while the stack is being brought up or down, so a restart doesn't
+/// interleave with the radio policy doing either
+static LIFECYCLE: Mutex<()> = Mutex::new(());
+
+/// Shuts the stack down and powers off the controller. The threads started by
+/// [`init_ble`] keep running, and notifications wait in the outbox until
+/// [`start_ble`] brings it back.
 pub fn stop_ble() -> color_eyre::Result<()> {
-    static STOP_LOCK: Mutex<()> = Mutex::new(());
-    static STOPPED_LOCK: Mutex<()> = Mutex::new(());
+    let _lifecycle = LIFECYCLE.lock().unwrap();
+    stop()
+}
 
-    let _h = STOP_LOCK.lock().unwrap();
-    let h = STOPPED_LOCK.lock().unwrap();
-    let h = Arc::into_raw(Arc::new(h));
+fn stop() -> color_eyre::Result<()> {
+    {
+        // lets anything advertising right now finish, and nothing start after
+        let _adv = ADV_LOCK.lock().unwrap();
+        RADIO_ON.store(false, Ordering::SeqCst);
+    }
 
     unsafe {
         let mut listener = ble_hs_stop_listener::default();
-        if let Err(e) = esp!(ble_hs_stop(&mut listener, Some(stop_fn), h as *const _ as *mut _)) {
-            std::mem::drop(Arc::from_raw(h));
-            return Err(e)?;
+        if let Err(err) = esp!(ble_hs_stop(&mut listener, Some(stop_fn), null_mut())) {
+            RADIO_ON.store(true, Ordering::SeqCst);
+            return Err(err)?;
         }
-    }
-
-    info!("Dispatched stop request");
 
-    // ok, now we try to lock the inner lock again, it *should* unlock when stop_fn runs
-    let _h2 = STOPPED_LOCK.lock().unwrap();
+        info!("Dispatched stop request");
 
-    // now we can call the other deinit functions?
+        // the listener has to outlive the stop, which finishes on the host
+        // task
+        HOST_STOPPED.1.recv()?;
 
-    unsafe {
         esp!(nimble_port_stop())?;
         nimble_port_deinit();
         esp!(esp_nimble_hci_and_controller_deinit())?
     }
 
-
+    info!("Bluetooth stopped");
     Ok(())
 }
 
-pub fn init_ble() -> color_eyre::Result<()> {
-    unsafe {
-        if let Err(err) = esp!(esp_idf_sys::nvs_flash_init()) {
-            if err.code() == esp_idf_sys::ESP_ERR_NVS_NO_FREE_PAGES
-                || err.code() == esp_idf_sys::ESP_ERR_NVS_NEW_VERSION_FOUND
-            {
-                esp!(esp_idf_sys::nvs_flash_erase())?;
-                esp!(esp_idf_sys::nvs_flash_init())?;
-            }
-        }
+/// Brings the stack up, at boot and again after [`stop_ble`]. Advertising
+/// starts once the host has synced with the controller.
+pub fn start_ble() -> color_eyre::Result<()> {
+    let _lifecycle = LIFECYCLE.lock().unwrap();
+    start()
+}
+
+/// Brings the stack down and up again if it's on, so the services match the
+/// button mode again. Connected phones are dropped and reconnect.
+pub fn hid_mode_changed() -> color_eyre::Result<()> {
+    let _lifecycle = LIFECYCLE.lock().unwrap();
 
+    let hid = hid::mode() != hid::Mode::Watch;
+    if !radio_on() || hid_registered() == hid {
+        // the advertisement still says whether we're a keyboard
+        restart_advertising();
+        return Ok(());
+    }
+
+    info!(hid, "Restarting bluetooth for the button mode");
+    stop()?;
+    start()
+}
+
+fn start() -> color_eyre::Result<()> {
+    let hid = hid::mode() != hid::Mode::Watch;
+
+    unsafe {
         info!("Initializing bluetooth");
 
         esp!(esp_nimble_hci_and_controller_init())?;
@@ -487,25 +3087,68 @@ pub fn init_ble() -> color_eyre::Result<()> {
         ble_hs_cfg.sync_cb = Some(ble_spp_server_on_sync);
         ble_hs_cfg.gatts_register_cb = Some(gatt_svr_register_cb);
         ble_hs_cfg.store_status_cb = Some(ble_store_util_status_rr);
-        ble_hs_cfg.sm_io_cap = 3;
+        ble_hs_cfg.sm_io_cap = BLE_HS_IO_DISPLAY_ONLY as u8;
         ble_hs_cfg.set_sm_bonding(1);
+        ble_hs_cfg.set_sm_mitm(1);
         ble_hs_cfg.set_sm_sc(1);
-        ble_hs_cfg.sm_our_key_dist = 1;
-        ble_hs_cfg.sm_their_key_dist = 1;
-
-        // gatt_svr_init
+        // the phone's IRK lets the controller resolve its private addresses
+        // back to the identity address we bonded with, so we can find it again
+        // after it changes
+        let key_dist = (BLE_SM_PAIR_KEY_DIST_ENC | BLE_SM_PAIR_KEY_DIST_ID) as u8;
+        ble_hs_cfg.sm_our_key_dist = key_dist;
+        ble_hs_cfg.sm_their_key_dist = key_dist;
+
+        // gatt_svr_init, dropping the services from the last time the stack
+        // was up
+        esp!(ble_gatts_reset())?;
         ble_svc_gap_init();
         ble_svc_gatt_init();
-        esp!(ble_gatts_count_cfg(&GATT_SERVICES as *const _))?;
-        esp!(ble_gatts_add_svcs(&GATT_SERVICES as *const _))?;
-        esp!(ble_svc_gap_device_name_set(
-            b"\xF0\x9F\xA6\x80\0" as *const u8 as *const _
-        ))?;
+        let tables = if hid { &HID_GATT } else { &GATT };
+        let gatt = tables.get_or_init(|| {
+            let builder = gatt_services();
+            let builder = if hid {
+                builder.service(hid_service())
+            } else {
+                builder
+            };
+            #[cfg(feature = "nus")]
+            let builder = builder.service(nus_service());
+            builder.build()
+        });
+        if !hid {
+            // nothing will fill them in, and whatever has their old handles
+            // now is something else
+            BLE_HID_KEYBOARD_HANDLE.set(0);
+            BLE_HID_CONSUMER_HANDLE.set(0);
+        }
+        gatt.register()?;
+        let last = HID_REGISTERED.lock().unwrap().replace(hid);
+        if last.map_or(false, |last| last != hid) {
+            GATT_CHANGED.store(true, Ordering::SeqCst);
+        }
+        let name = CString::new(advertising::current().name)?;
+        esp!(ble_svc_gap_device_name_set(name.as_ptr()))?;
         ble_store_config_init();
+
+        RADIO_ON.store(true, Ordering::SeqCst);
         nimble_port_freertos_init(Some(ble_spp_server_host_task));
     }
 
-    TX_THREAD.get_or_init(|| std::thread::spawn(|| tx_thread()));
+    Ok(())
+}
+
+pub fn init_ble() -> color_eyre::Result<()> {
+    storage::init()?;
+    start_ble()?;
+
+    TX_THREAD.get_or_init(|| {
+        protocol::register(BleTransport);
+        #[cfg(feature = "nus")]
+        protocol::register(NusTransport);
+        std::thread::spawn(|| tx_thread())
+    });
+    BATTERY_THREAD.get_or_init(|| std::thread::spawn(battery_thread));
+    CONN_PARAMS_THREAD.get_or_init(|| std::thread::spawn(conn_params_thread));
 
     Ok(())
 }
@@ -513,7 +3156,7 @@ pub fn init_ble() -> color_eyre::Result<()> {
 extern "C" {
     fn ble_svc_gap_init();
     fn ble_svc_gatt_init();
-    fn ble_svc_gap_device_name() -> *const c_char;
+    fn ble_svc_gatt_changed(start_handle: u16, end_handle: u16);
     fn ble_svc_gap_device_name_set(name: *const c_char) -> c_int;
     fn ble_store_config_init();
 }
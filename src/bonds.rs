@@ -0,0 +1,99 @@
+//! Peers NimBLE has stored long term keys for, so the user can see who the
+//! watch trusts and forget phones they no longer own.
+
+use std::fmt;
+
+use color_eyre::eyre::{ensure, eyre};
+use esp_idf_sys::{
+    ble_addr_t, ble_gap_unpair, ble_store_util_bonded_peers, esp, CONFIG_BT_NIMBLE_MAX_BONDS,
+};
+
+use crate::{bluetooth, connections, message};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bond {
+    pub addr_type: u8,
+    /// Identity address of the peer, least significant byte first
+    pub addr: [u8; 6],
+}
+
+impl Bond {
+    pub fn connected(&self) -> bool {
+        connections::connected_to(self.addr)
+    }
+
+    pub fn from_message(bond: &message::Bond) -> color_eyre::Result<Self> {
+        let addr = bond
+            .address
+            .as_slice()
+            .try_into()
+            .map_err(|_| eyre!("Address is {} bytes, expected 6", bond.address.len()))?;
+        ensure!(
+            bond.address_type <= u8::MAX as u32,
+            "Address type {} is out of range",
+            bond.address_type
+        );
+
+        Ok(Self {
+            addr_type: bond.address_type as u8,
+            addr,
+        })
+    }
+
+    pub fn to_message(&self) -> message::Bond {
+        message::Bond {
+            address: self.addr.to_vec(),
+            address_type: self.addr_type as u32,
+            connected: self.connected(),
+        }
+    }
+}
+
+impl fmt::Display for Bond {
+    /// The address as it's usually written, most significant byte first
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.addr;
+        write!(
+            f,
+            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+            g, e, d, c, b, a
+        )
+    }
+}
+
+pub fn list() -> color_eyre::Result<Vec<Bond>> {
+    ensure!(bluetooth::radio_on(), "Bluetooth is off");
+
+    let mut addrs = [ble_addr_t::default(); CONFIG_BT_NIMBLE_MAX_BONDS as usize];
+    let mut count = 0;
+    esp!(unsafe {
+        ble_store_util_bonded_peers(addrs.as_mut_ptr(), &mut count as *mut _, addrs.len() as i32)
+    })?;
+
+    Ok(addrs[..count as usize]
+        .iter()
+        .map(|addr| Bond {
+            addr_type: addr.type_,
+            addr: addr.val,
+        })
+        .collect())
+}
+
+/// Whether we have keys for the peer with identity address `addr`
+pub fn is_bonded(addr: [u8; 6]) -> bool {
+    list().map_or(false, |bonds| bonds.iter().any(|bond| bond.addr == addr))
+}
+
+/// Deletes the keys for `bond`, disconnecting it if it's connected
+pub fn delete(bond: &Bond) -> color_eyre::Result<()> {
+    ensure!(bluetooth::radio_on(), "Bluetooth is off");
+
+    let addr = ble_addr_t {
+        type_: bond.addr_type,
+        val: bond.addr,
+    };
+    esp!(unsafe { ble_gap_unpair(&addr as *const _) })?;
+    bluetooth::cancel_reconnect(bond.addr);
+
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//! Which connection parameters to ask the phone for.
+//!
+//! Most of the time the link carries nothing but the odd notification, so we
+//! ask for a long interval with some slave latency, which lets the radio sleep
+//! through most connection events. When a transfer or a burst of
+//! notifications starts we ask for a short interval instead, and drop back to
+//! idle once things have been quiet for a while.
+//!
+//! The phone has the final say, so these are only requests. The values stay
+//! inside Apple's accessory guidelines so iPhones don't reject them.
+
+use std::time::{Duration, Instant};
+
+use crate::message;
+
+/// Don't ask for anything right after connecting, the phone is busy with
+/// service discovery and pairing and is likely to refuse
+pub const SETTLE: Duration = Duration::from_secs(5);
+/// How long to stay fast after the last sign of activity
+pub const FAST_HOLD: Duration = Duration::from_secs(5);
+/// How often the policy is looked at when nothing is happening
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// LE data length we ask for when going fast, the most a link layer PDU holds
+pub const DATA_LEN_OCTETS: u16 = 251;
+/// Time to send [`DATA_LEN_OCTETS`] on the 1M PHY, in microseconds
+pub const DATA_LEN_TIME: u16 = 2120;
+
+/// In the units the controller uses: 1.25ms for the interval, connection
+/// events for the latency and 10ms for the supervision timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    pub interval_min: u16,
+    pub interval_max: u16,
+    pub latency: u16,
+    pub supervision_timeout: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Idle,
+    Fast,
+}
+
+impl Profile {
+    pub fn params(self) -> Params {
+        match self {
+            // 360-380ms, answering at least every 1.9s
+            Profile::Idle => Params {
+                interval_min: 288,
+                interval_max: 304,
+                latency: 4,
+                supervision_timeout: 600,
+            },
+            // 15-30ms, answering every event
+            Profile::Fast => Params {
+                interval_min: 12,
+                interval_max: 24,
+                latency: 0,
+                supervision_timeout: 400,
+            },
+        }
+    }
+
+    pub fn to_message(self) -> message::ConnectionProfile {
+        match self {
+            Profile::Idle => message::ConnectionProfile::Idle,
+            Profile::Fast => message::ConnectionProfile::Fast,
+        }
+    }
+}
+
+/// What to ask for now, if anything, given when the connection was made and
+/// when it was last busy
+pub fn wanted(connected_at: Instant, busy_at: Option<Instant>, now: Instant) -> Option<Profile> {
+    if busy_at.map_or(false, |busy_at| now.duration_since(busy_at) < FAST_HOLD) {
+        Some(Profile::Fast)
+    } else if now.duration_since(connected_at) >= SETTLE {
+        Some(Profile::Idle)
+    } else {
+        None
+    }
+}
+
+pub fn interval_us(interval: u16) -> u32 {
+    interval as u32 * 1250
+}
+
+pub fn supervision_timeout_ms(timeout: u16) -> u32 {
+    timeout as u32 * 10
+}
@@ -0,0 +1,153 @@
+//! Everything we know about each peer connected over BLE.
+//!
+//! Entries are added on connect and removed on disconnect, so anything in here
+//! is a live connection.
+
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::conn_params::{self, Profile};
+use crate::message;
+
+/// The ATT MTU every connection starts with until it's negotiated up
+pub const DEFAULT_MTU: u16 = 23;
+
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub handle: u16,
+    /// Identity address of the peer, least significant byte first
+    pub addr: [u8; 6],
+    pub mtu: u16,
+    pub encrypted: bool,
+    pub bonded: bool,
+    /// Attribute handles the peer has enabled notifications or indications on
+    pub subscriptions: Vec<u16>,
+    /// Attribute handles the peer has enabled indications on, and whether it
+    /// takes notifications there too
+    pub indications: Vec<(u16, bool)>,
+    /// Connection parameters as the controller reports them, see
+    /// [`conn_params::Params`] for the units
+    pub interval: u16,
+    pub latency: u16,
+    pub supervision_timeout: u16,
+    /// What we last asked the phone for
+    pub profile: Option<Profile>,
+    pub connected_at: Instant,
+    /// When a transfer or burst of notifications last went over the link
+    pub busy_at: Option<Instant>,
+}
+
+impl Connection {
+    pub fn new(handle: u16, addr: [u8; 6]) -> Self {
+        Self {
+            handle,
+            addr,
+            mtu: DEFAULT_MTU,
+            encrypted: false,
+            bonded: false,
+            subscriptions: Vec::new(),
+            indications: Vec::new(),
+            interval: 0,
+            latency: 0,
+            supervision_timeout: 0,
+            profile: None,
+            connected_at: Instant::now(),
+            busy_at: None,
+        }
+    }
+
+    pub fn to_message(&self) -> message::ConnectionDiagnostics {
+        message::ConnectionDiagnostics {
+            address: self.addr.to_vec(),
+            interval_us: conn_params::interval_us(self.interval),
+            latency: self.latency as u32,
+            supervision_timeout_ms: conn_params::supervision_timeout_ms(self.supervision_timeout),
+            mtu: self.mtu as u32,
+            profile: self
+                .profile
+                .map_or(message::ConnectionProfile::Unset, Profile::to_message)
+                as i32,
+            encrypted: self.encrypted,
+        }
+    }
+}
+
+static CONNECTIONS: Mutex<Vec<Connection>> = Mutex::new(Vec::new());
+
+/// Adds a connection, replacing any stale entry with the same handle
+pub fn add(conn: Connection) {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    connections.retain(|c| c.handle != conn.handle);
+    connections.push(conn);
+}
+
+pub fn remove(handle: u16) -> Option<Connection> {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    let idx = connections.iter().position(|c| c.handle == handle)?;
+    Some(connections.remove(idx))
+}
+
+/// Runs `f` on the connection with `handle`, if it's still connected
+pub fn update<T>(handle: u16, f: impl FnOnce(&mut Connection) -> T) -> Option<T> {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .find(|c| c.handle == handle)
+        .map(f)
+}
+
+pub fn get(handle: u16) -> Option<Connection> {
+    update(handle, |c| c.clone())
+}
+
+pub fn all() -> Vec<Connection> {
+    CONNECTIONS.lock().unwrap().clone()
+}
+
+pub fn count() -> usize {
+    CONNECTIONS.lock().unwrap().len()
+}
+
+/// Whether the peer with identity address `addr` is connected
+pub fn connected_to(addr: [u8; 6]) -> bool {
+    CONNECTIONS.lock().unwrap().iter().any(|c| c.addr == addr)
+}
+
+/// Records whether `handle` wants notifications or indications for
+/// `attr_handle`
+pub fn set_subscribed(handle: u16, attr_handle: u16, notify: bool, indicate: bool) {
+    update(handle, |c| {
+        c.subscriptions.retain(|&attr| attr != attr_handle);
+        c.indications.retain(|&(attr, _)| attr != attr_handle);
+        if notify || indicate {
+            c.subscriptions.push(attr_handle);
+        }
+        if indicate {
+            c.indications.push((attr_handle, notify));
+        }
+    });
+}
+
+/// Whether something for `handle` on `attr_handle` should go as an
+/// indication. With both enabled, only what must get there is indicated.
+pub fn indicate(handle: u16, attr_handle: u16, must_deliver: bool) -> bool {
+    update(handle, |c| {
+        c.indications
+            .iter()
+            .find(|&&(attr, _)| attr == attr_handle)
+            .map_or(false, |&(_, notify)| must_deliver || !notify)
+    })
+    .unwrap_or(false)
+}
+
+/// Handles of the live connections subscribed to `attr_handle`
+pub fn subscribers(attr_handle: u16) -> Vec<u16> {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|c| c.subscriptions.contains(&attr_handle))
+        .map(|c| c.handle)
+        .collect()
+}
@@ -0,0 +1,78 @@
+use std::sync::atomic::Ordering;
+
+use esp_idf_sys::esp;
+use tracing::error;
+
+use crate::axp192::BATTERY_PERCENT;
+use crate::{bluetooth, connections, delivery, message, radio};
+
+pub const BOARD: &str = "m5stickc-plus";
+pub const MANUFACTURER: &str = "M5Stack";
+
+/// Cargo features this firmware was built with
+pub fn enabled_features() -> Vec<String> {
+    [
+        ("experimental", cfg!(feature = "experimental")),
+        ("legacy-origin", cfg!(feature = "legacy-origin")),
+        ("nus", cfg!(feature = "nus")),
+        ("signed-ota", cfg!(feature = "signed-ota")),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| name.to_owned())
+    .collect()
+}
+
+pub fn uptime_ms() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64
+}
+
+/// The factory MAC from eFuse, which is unique to each watch
+pub fn serial_number() -> String {
+    let mut mac = [0u8; 6];
+    if let Err(err) = esp!(unsafe { esp_idf_sys::esp_efuse_mac_get_default(mac.as_mut_ptr()) }) {
+        error!(?err, "Couldn't read the MAC from eFuse");
+    }
+
+    mac.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// The ESP32's silicon revision
+pub fn hardware_revision() -> String {
+    let mut info = esp_idf_sys::esp_chip_info_t::default();
+    unsafe { esp_idf_sys::esp_chip_info(&mut info as *mut _) };
+
+    format!("ESP32 rev {}", info.revision)
+}
+
+pub fn device_info(request_id: u32) -> message::DeviceInfo {
+    message::DeviceInfo {
+        request_id,
+        crate_version: env!("CARGO_PKG_VERSION").to_owned(),
+        git_hash: env!("GIT_HASH").to_owned(),
+        build_profile: env!("BUILD_PROFILE").to_owned(),
+        protocol_version: message::PROTOCOL_VERSION,
+        features: enabled_features(),
+        board: BOARD.to_owned(),
+        uptime_ms: uptime_ms(),
+        battery_percent: BATTERY_PERCENT.load(Ordering::Relaxed) as u32,
+    }
+}
+
+/// How the links are running right now, what the radio has cost and how
+/// notifications have fared
+pub fn diagnostics(request_id: u32) -> message::Diagnostics {
+    message::Diagnostics {
+        request_id,
+        connections: connections::all()
+            .iter()
+            .map(connections::Connection::to_message)
+            .collect(),
+        radio: Some(radio::diagnostics(bluetooth::radio_on())),
+        tx: Some(delivery::diagnostics()),
+        bus: message::stats()
+            .iter()
+            .map(message::QueueStats::to_message)
+            .collect(),
+    }
+}
@@ -23,8 +23,11 @@ use mipidsi::models::{Model, ST7789};
 use mipidsi::Orientation;
 use profont::PROFONT_24_POINT;
 
+use crate::alert::{self, Alert};
 use crate::bluetooth;
+use crate::bonds::Bond;
 use crate::ingerland::INGERLAND;
+use crate::message::Priority;
 
 type DisplayType = mipidsi::Display<
     SPIInterfaceNoCS<
@@ -121,6 +124,323 @@ impl Display {
         Ok(Self { display })
     }
 
+    pub fn clear(&mut self) -> color_eyre::Result<()> {
+        self.cropped_display()
+            .clear(Rgb565::BLACK)
+            .map_err(|e| eyre!("Failed to use display: {:?}", e))
+    }
+
+    /// Draws a notification over the whole screen, the caller should
+    /// [`Display::clear`] first if something else was being shown.
+    pub fn display_alert(&mut self, alert: &Alert) -> color_eyre::Result<()> {
+        let accent = alert_accent(alert.priority);
+
+        let mut header = alert::category_label(alert.category).to_owned();
+        if let Some(ts) = &alert.timestamp {
+            let ts = ts.in_timezone(INGERLAND).format(format_spec!("%H:%M"));
+            header = format!("{header} {ts}");
+        }
+
+        let header_style = MonoTextStyleBuilder::new()
+            .font(&FONT_10X20)
+            .text_color(Rgb565::BLACK)
+            .background_color(accent)
+            .build();
+        let heading_style = MonoTextStyle::new(&FONT_10X20, accent);
+        let body_style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+
+        let textbox_style = TextBoxStyleBuilder::new()
+            .height_mode(embedded_text::style::HeightMode::Exact(
+                embedded_text::style::VerticalOverdraw::Hidden,
+            ))
+            .alignment(HorizontalAlignment::Left)
+            .vertical_alignment(embedded_text::alignment::VerticalAlignment::Top)
+            .build();
+
+        let line_height = FONT_10X20.character_size.height;
+
+        let mut canvas = self.cropped_display();
+
+        Rectangle::new(Point::new(0, 0), Size::new(240, line_height))
+            .into_styled(PrimitiveStyle::with_fill(accent))
+            .draw(&mut canvas)
+            .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        TextBox::with_textbox_style(
+            &header,
+            Rectangle::new(Point::new(2, 0), Size::new(236, line_height)),
+            header_style,
+            textbox_style,
+        )
+        .draw(&mut canvas)
+        .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        TextBox::with_textbox_style(
+            alert.heading(),
+            Rectangle::new(
+                Point::new(0, line_height as i32),
+                Size::new(240, line_height),
+            ),
+            heading_style,
+            textbox_style,
+        )
+        .draw(&mut canvas)
+        .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        TextBox::with_textbox_style(
+            &alert.body,
+            Rectangle::new(
+                Point::new(0, 2 * line_height as i32),
+                Size::new(240, 135 - 3 * line_height),
+            ),
+            body_style,
+            textbox_style,
+        )
+        .draw(&mut canvas)
+        .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        Ok(())
+    }
+
+    /// Draws the currently selected response along the bottom of an alert
+    pub fn display_alert_choice(&mut self, alert: &Alert, label: &str) -> color_eyre::Result<()> {
+        let line_height = FONT_10X20.character_size.height;
+        let bounds = Rectangle::new(
+            Point::new(0, (135 - line_height) as i32),
+            Size::new(240, line_height),
+        );
+
+        let mut canvas = self.cropped_display();
+
+        bounds
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+            .draw(&mut canvas)
+            .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        let character_style = MonoTextStyle::new(&FONT_10X20, alert_accent(alert.priority));
+        let textbox_style = TextBoxStyleBuilder::new()
+            .height_mode(embedded_text::style::HeightMode::Exact(
+                embedded_text::style::VerticalOverdraw::Hidden,
+            ))
+            .alignment(HorizontalAlignment::Center)
+            .build();
+
+        TextBox::with_textbox_style(
+            &format!("< {label} >"),
+            bounds,
+            character_style,
+            textbox_style,
+        )
+        .draw(&mut canvas)
+        .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        Ok(())
+    }
+
+    /// Fills the screen with the passkey the phone is asking the user to type
+    pub fn display_passkey(&mut self, passkey: u32) -> color_eyre::Result<()> {
+        let line_height = FONT_10X20.character_size.height;
+
+        let small_style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+        let passkey_style = MonoTextStyle::new(&PROFONT_24_POINT, Rgb565::CYAN);
+        let textbox_style = TextBoxStyleBuilder::new()
+            .height_mode(embedded_text::style::HeightMode::Exact(
+                embedded_text::style::VerticalOverdraw::Hidden,
+            ))
+            .alignment(HorizontalAlignment::Center)
+            .vertical_alignment(embedded_text::alignment::VerticalAlignment::Middle)
+            .build();
+
+        let mut canvas = self.cropped_display();
+
+        TextBox::with_textbox_style(
+            "Pairing code",
+            Rectangle::new(Point::new(0, 0), Size::new(240, line_height)),
+            small_style,
+            textbox_style,
+        )
+        .draw(&mut canvas)
+        .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        TextBox::with_textbox_style(
+            &format!("{:03} {:03}", passkey / 1000, passkey % 1000),
+            Rectangle::new(
+                Point::new(0, line_height as i32),
+                Size::new(240, 135 - 2 * line_height),
+            ),
+            passkey_style,
+            textbox_style,
+        )
+        .draw(&mut canvas)
+        .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        TextBox::with_textbox_style(
+            "Side button rejects",
+            Rectangle::new(
+                Point::new(0, (135 - line_height) as i32),
+                Size::new(240, line_height),
+            ),
+            small_style,
+            textbox_style,
+        )
+        .draw(&mut canvas)
+        .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        Ok(())
+    }
+
+    /// Fills the screen with how far along a firmware update is
+    pub fn display_update(&mut self, written: u32, size: u32) -> color_eyre::Result<()> {
+        let line_height = FONT_10X20.character_size.height;
+
+        let small_style = MonoTextStyleBuilder::new()
+            .font(&FONT_10X20)
+            .text_color(Rgb565::WHITE)
+            .background_color(Rgb565::BLACK)
+            .build();
+        let percent_style = MonoTextStyleBuilder::new()
+            .font(&PROFONT_24_POINT)
+            .text_color(Rgb565::CYAN)
+            .background_color(Rgb565::BLACK)
+            .build();
+        let textbox_style = TextBoxStyleBuilder::new()
+            .height_mode(embedded_text::style::HeightMode::Exact(
+                embedded_text::style::VerticalOverdraw::Hidden,
+            ))
+            .alignment(HorizontalAlignment::Center)
+            .vertical_alignment(embedded_text::alignment::VerticalAlignment::Middle)
+            .build();
+
+        let percent = (written as u64 * 100 / size.max(1) as u64) as u32;
+
+        let mut canvas = self.cropped_display();
+
+        TextBox::with_textbox_style(
+            "Updating firmware",
+            Rectangle::new(Point::new(0, 0), Size::new(240, line_height)),
+            small_style,
+            textbox_style,
+        )
+        .draw(&mut canvas)
+        .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        // redrawn as it goes, so pad over the last value
+        TextBox::with_textbox_style(
+            &format!(" {}% ", percent),
+            Rectangle::new(
+                Point::new(0, line_height as i32),
+                Size::new(240, 135 - 2 * line_height),
+            ),
+            percent_style,
+            textbox_style,
+        )
+        .draw(&mut canvas)
+        .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        TextBox::with_textbox_style(
+            &format!(" {} of {} KiB ", written / 1024, size / 1024),
+            Rectangle::new(
+                Point::new(0, (135 - line_height) as i32),
+                Size::new(240, line_height),
+            ),
+            small_style,
+            textbox_style,
+        )
+        .draw(&mut canvas)
+        .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        Ok(())
+    }
+
+    /// Draws the bond management screen. `selected` 0 is "Back", anything
+    /// after is the bond before it.
+    pub fn display_bonds(&mut self, bonds: &[Bond], selected: usize) -> color_eyre::Result<()> {
+        let (body, label) = match selected.checked_sub(1).and_then(|idx| bonds.get(idx)) {
+            Some(bond) if bond.connected() => (format!("{bond}\nconnected"), "Forget"),
+            Some(bond) => (bond.to_string(), "Forget"),
+            None => (format!("{} bonded", bonds.len()), "Back"),
+        };
+
+        self.display_page(&format!("Bonds {}/{}", selected, bonds.len()), &body, label)
+    }
+
+    /// The menu, with `body` describing the selected entry
+    pub fn display_menu(
+        &mut self,
+        selected: usize,
+        entries: usize,
+        body: &str,
+    ) -> color_eyre::Result<()> {
+        self.display_page(
+            &format!("Menu {}/{}", selected + 1, entries),
+            body,
+            "Select",
+        )
+    }
+
+    /// A header, a body, and what the side button does along the bottom
+    fn display_page(&mut self, header: &str, body: &str, label: &str) -> color_eyre::Result<()> {
+        let line_height = FONT_10X20.character_size.height;
+
+        let header_style = MonoTextStyleBuilder::new()
+            .font(&FONT_10X20)
+            .text_color(Rgb565::BLACK)
+            .background_color(Rgb565::WHITE)
+            .build();
+        let body_style = MonoTextStyleBuilder::new()
+            .font(&FONT_10X20)
+            .text_color(Rgb565::WHITE)
+            .background_color(Rgb565::BLACK)
+            .build();
+        let textbox_style = TextBoxStyleBuilder::new()
+            .height_mode(embedded_text::style::HeightMode::Exact(
+                embedded_text::style::VerticalOverdraw::Hidden,
+            ))
+            .alignment(HorizontalAlignment::Center)
+            .vertical_alignment(embedded_text::alignment::VerticalAlignment::Middle)
+            .build();
+
+        let mut canvas = self.cropped_display();
+
+        let header_rect = Rectangle::new(Point::new(0, 0), Size::new(240, line_height));
+        header_rect
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+            .draw(&mut canvas)
+            .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        TextBox::with_textbox_style(header, header_rect, header_style, textbox_style)
+            .draw(&mut canvas)
+            .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        let bounds = Rectangle::new(
+            Point::new(0, line_height as i32),
+            Size::new(240, 135 - 2 * line_height),
+        );
+        bounds
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+            .draw(&mut canvas)
+            .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        TextBox::with_textbox_style(body, bounds, body_style, textbox_style)
+            .draw(&mut canvas)
+            .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        let bounds = Rectangle::new(
+            Point::new(0, (135 - line_height) as i32),
+            Size::new(240, line_height),
+        );
+        bounds
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+            .draw(&mut canvas)
+            .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        TextBox::with_textbox_style(&format!("< {label} >"), bounds, body_style, textbox_style)
+            .draw(&mut canvas)
+            .map_err(|e| eyre!("Failed to draw to display: {:?}", e))?;
+
+        Ok(())
+    }
+
     fn cropped_display(
         &mut self,
     ) -> impl DrawTarget<Color = Rgb565, Error = <DisplayType as DrawTarget>::Error> + '_ {
@@ -235,3 +555,12 @@ impl Display {
         Ok(())
     }
 }
+
+fn alert_accent(priority: Priority) -> Rgb565 {
+    match priority {
+        Priority::Low => Rgb565::new(16, 32, 16),
+        Priority::Normal => Rgb565::WHITE,
+        Priority::High => Rgb565::YELLOW,
+        Priority::Urgent => Rgb565::RED,
+    }
+}
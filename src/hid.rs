@@ -0,0 +1,164 @@
+//! Lets the buttons act as a Bluetooth media remote or presentation clicker,
+//! using HID over GATT so no app is needed on the other end.
+//!
+//! The watch shows up as a keyboard with consumer controls. Which keys the
+//! buttons send depends on the [`Mode`], which is kept in NVS.
+
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use tracing::{error, info};
+
+use crate::storage::Nvs;
+
+const NAMESPACE: &str = "hid";
+const MODE: &str = "mode";
+
+pub const KEYBOARD_REPORT_ID: u8 = 1;
+pub const CONSUMER_REPORT_ID: u8 = 2;
+
+/// Modifiers, a reserved byte, then up to six keys
+pub const KEYBOARD_REPORT_LEN: usize = 8;
+/// A single 16 bit usage
+pub const CONSUMER_REPORT_LEN: usize = 2;
+
+/// bcdHID 1.11, no country code, normally connectable
+pub const HID_INFORMATION: [u8; 4] = [0x11, 0x01, 0x00, 0x02];
+
+#[rustfmt::skip]
+pub const REPORT_MAP: &[u8] = &[
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x06,       // Usage (Keyboard)
+    0xA1, 0x01,       // Collection (Application)
+    0x85, KEYBOARD_REPORT_ID,
+    0x05, 0x07,       //   Usage Page (Keyboard)
+    0x19, 0xE0,       //   Usage Minimum (Left Control)
+    0x29, 0xE7,       //   Usage Maximum (Right GUI)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x08,       //   Report Count (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute), modifiers
+    0x95, 0x01,       //   Report Count (1)
+    0x75, 0x08,       //   Report Size (8)
+    0x81, 0x01,       //   Input (Constant), reserved
+    0x95, 0x06,       //   Report Count (6)
+    0x75, 0x08,       //   Report Size (8)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x65,       //   Logical Maximum (101)
+    0x19, 0x00,       //   Usage Minimum (0)
+    0x29, 0x65,       //   Usage Maximum (101)
+    0x81, 0x00,       //   Input (Data, Array, Absolute), keys
+    0xC0,             // End Collection
+    0x05, 0x0C,       // Usage Page (Consumer)
+    0x09, 0x01,       // Usage (Consumer Control)
+    0xA1, 0x01,       // Collection (Application)
+    0x85, CONSUMER_REPORT_ID,
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x03, //   Logical Maximum (1023)
+    0x19, 0x00,       //   Usage Minimum (0)
+    0x2A, 0xFF, 0x03, //   Usage Maximum (1023)
+    0x75, 0x10,       //   Report Size (16)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x00,       //   Input (Data, Array, Absolute)
+    0xC0,             // End Collection
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// A usage from the Keyboard page
+    Keyboard(u8),
+    /// A usage from the Consumer page
+    Consumer(u16),
+}
+
+pub const PLAY_PAUSE: Key = Key::Consumer(0xCD);
+pub const NEXT_TRACK: Key = Key::Consumer(0xB5);
+pub const VOLUME_UP: Key = Key::Consumer(0xE9);
+pub const VOLUME_DOWN: Key = Key::Consumer(0xEA);
+pub const PAGE_UP: Key = Key::Keyboard(0x4B);
+pub const PAGE_DOWN: Key = Key::Keyboard(0x4E);
+
+impl Key {
+    /// The input report with the key held down
+    pub fn pressed(self) -> Vec<u8> {
+        match self {
+            Key::Keyboard(usage) => {
+                let mut report = vec![0; KEYBOARD_REPORT_LEN];
+                report[2] = usage;
+                report
+            }
+            Key::Consumer(usage) => usage.to_le_bytes().to_vec(),
+        }
+    }
+
+    /// The input report with nothing held down
+    pub fn released(self) -> Vec<u8> {
+        match self {
+            Key::Keyboard(_) => vec![0; KEYBOARD_REPORT_LEN],
+            Key::Consumer(_) => vec![0; CONSUMER_REPORT_LEN],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// The buttons just drive the watch
+    Watch,
+    Media,
+    Volume,
+    Presenter,
+}
+
+pub const MODES: [Mode; 4] = [Mode::Watch, Mode::Media, Mode::Volume, Mode::Presenter];
+
+impl Mode {
+    pub fn label(self) -> &'static str {
+        match self {
+            Mode::Watch => "Watch",
+            Mode::Media => "Media",
+            Mode::Volume => "Volume",
+            Mode::Presenter => "Presenter",
+        }
+    }
+
+    /// What the front and side buttons send, if they send anything
+    pub fn keys(self) -> Option<(Key, Key)> {
+        match self {
+            Mode::Watch => None,
+            Mode::Media => Some((PLAY_PAUSE, NEXT_TRACK)),
+            Mode::Volume => Some((VOLUME_UP, VOLUME_DOWN)),
+            Mode::Presenter => Some((PAGE_DOWN, PAGE_UP)),
+        }
+    }
+}
+
+fn load() -> color_eyre::Result<Option<Mode>> {
+    let nvs = Nvs::open(NAMESPACE)?;
+    Ok(nvs
+        .get_u64(MODE)?
+        .and_then(|idx| MODES.get(idx as usize).copied()))
+}
+
+static CURRENT: Lazy<Mutex<Mode>> = Lazy::new(|| {
+    let mode = load()
+        .unwrap_or_else(|err| {
+            error!(?err, "Failed to load the button mode");
+            None
+        })
+        .unwrap_or(Mode::Watch);
+    Mutex::new(mode)
+});
+
+pub fn mode() -> Mode {
+    *CURRENT.lock().unwrap()
+}
+
+pub fn set_mode(mode: Mode) -> color_eyre::Result<()> {
+    let idx = MODES.iter().position(|&m| m == mode).unwrap_or(0);
+    Nvs::open(NAMESPACE)?.set_u64(MODE, idx as u64)?;
+    info!(?mode, "Changed button mode");
+    *CURRENT.lock().unwrap() = mode;
+
+    Ok(())
+}
@@ -8,7 +8,7 @@
 #![feature(const_unsafecell_get_mut)]
 #![feature(const_option)]
 
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
@@ -24,15 +24,37 @@ use esp_idf_sys::{self as _, esp};
 use once_cell::sync::Lazy;
 use tracing::{error, info};
 
+use crate::alert::{Alert, Choice, Source};
+use crate::bluetooth::{Pairing, PairingEvent};
+use crate::bonds::Bond;
+use crate::message::Topic;
 use crate::rtc::EspRtc;
 use crate::utils::I2c0;
 
+// everything that doesn't touch the hardware lives in watch-core, so it can be
+// tested on the host
+#[cfg(feature = "nus")]
+pub use watch_core::nus;
+pub use watch_core::{auth, cts, delivery, framing, message, outbox, protocol};
+
+pub mod advertising;
+pub mod alert;
+pub mod ancs;
 pub mod axp192;
 pub mod bluetooth;
+pub mod bonds;
+pub mod conn_params;
+pub mod connections;
+pub mod device_info;
 pub mod display;
+pub mod hid;
 pub mod ingerland;
-pub mod message;
+pub mod ota;
+pub mod radio;
+pub mod reconnect;
 pub mod rtc;
+pub mod serial;
+pub mod storage;
 pub mod utils;
 
 macro_rules! pin_handler {
@@ -57,35 +79,338 @@ macro_rules! pin_handler {
     }};
 }
 
-static CURRENT_NOTIF: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::new()));
+static CURRENT_NOTIF: Lazy<Mutex<Option<(Alert, Instant)>>> = Lazy::new(|| Mutex::new(None));
+
+/// The latest alert and when it arrived, if it should still be on screen
+fn visible_alert() -> Option<(Alert, Instant)> {
+    CURRENT_NOTIF
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|(alert, received)| received.elapsed() < alert.display_for())
+        .cloned()
+}
+
+/// The passkey of a pairing in progress, shown over everything else
+static PAIRING: Mutex<Option<Pairing>> = Mutex::new(None);
+
+/// Whether the user has the bond management screen up
+static BONDS_OPEN: AtomicBool = AtomicBool::new(false);
+
+/// Whether the user has the menu up
+static MENU_OPEN: AtomicBool = AtomicBool::new(false);
+
+/// The keys the front and side buttons send from the time screen, if they
+/// send any
+fn hid_keys() -> Option<(hid::Key, hid::Key)> {
+    hid::mode().keys().filter(|_| bluetooth::ble_connected())
+}
+
+/// Whether the side button is for whatever's on screen, rather than starting
+/// advertising
+fn side_button_taken() -> bool {
+    visible_alert().is_some()
+        || PAIRING.lock().unwrap().is_some()
+        || BONDS_OPEN.load(Ordering::Relaxed)
+        || MENU_OPEN.load(Ordering::Relaxed)
+        || hid_keys().is_some()
+}
+
+/// How long the front button has to be held to open the menu when it's
+/// sending keys
+const LONG_PRESS: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Button {
+    Front,
+    FrontLong,
+    Side,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MenuEntry {
+    Back,
+    Bonds,
+    Mode(hid::Mode),
+}
+
+const MENU: [MenuEntry; 6] = [
+    MenuEntry::Back,
+    MenuEntry::Bonds,
+    MenuEntry::Mode(hid::Mode::Watch),
+    MenuEntry::Mode(hid::Mode::Media),
+    MenuEntry::Mode(hid::Mode::Volume),
+    MenuEntry::Mode(hid::Mode::Presenter),
+];
+
+impl MenuEntry {
+    fn describe(self) -> String {
+        match self {
+            MenuEntry::Back => "Back".to_owned(),
+            MenuEntry::Bonds => "Bonds".to_owned(),
+            MenuEntry::Mode(mode) if mode == hid::mode() => {
+                format!("Buttons:\n{}\n(current)", mode.label())
+            }
+            MenuEntry::Mode(mode) => format!("Buttons:\n{}", mode.label()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Screen {
+    Time,
+    Alert(Instant),
+    Passkey(u32),
+    Bonds,
+    Menu,
+    Update,
+}
 
 fn waker_thread(wake_tx: Sender<bool>) {
-    let rx = message::get_receiver();
+    let rx = message::subscribe("waker", &[(Topic::PushNotification, 4)]);
 
     for msg in rx {
         if let Some(message::message::Body::PushNotification(notif)) = msg.body {
-            CURRENT_NOTIF.lock().unwrap().replace_range(.., &notif.body);
-            let _ = wake_tx.send(true);
+            let mut alert = Alert::from(notif);
+            let from_ancs = msg.origin == ancs::ORIGIN;
+            if from_ancs {
+                alert.source = Source::Ancs;
+            }
+            let wake = alert.should_wake();
+            *CURRENT_NOTIF.lock().unwrap() = Some((alert, Instant::now()));
+            if wake {
+                let _ = wake_tx.send(true);
+            }
+            // nobody's waiting on an ack for an iPhone notification
+            if !from_ancs {
+                protocol::send_notification(message::Notification::ack(msg.request_id));
+            }
+        }
+    }
+}
+
+fn pairing_thread(wake_tx: Sender<bool>) {
+    for event in bluetooth::pairing_events() {
+        match event {
+            PairingEvent::Passkey(pairing) => {
+                *PAIRING.lock().unwrap() = Some(pairing);
+                let _ = wake_tx.send(true);
+            }
+            PairingEvent::Finished {
+                conn_handle,
+                success,
+            } => {
+                let mut pairing = PAIRING.lock().unwrap();
+                if matches!(&*pairing, Some(p) if p.conn_handle == conn_handle) {
+                    info!(conn_handle, success, "Pairing finished");
+                    *pairing = None;
+                }
+            }
+        }
+    }
+}
+
+fn bonds_thread() {
+    let rx = message::subscribe("bonds", &[(Topic::ListBonds, 2), (Topic::DeleteBond, 2)]);
+
+    for msg in rx {
+        match msg.body {
+            Some(message::message::Body::ListBonds(_)) => match bonds::list() {
+                Ok(bonds) => {
+                    let bonds = message::Bonds {
+                        request_id: msg.request_id,
+                        bonds: bonds.iter().map(Bond::to_message).collect(),
+                    };
+                    protocol::send_notification(message::Notification {
+                        body: Some(message::notification::Body::Bonds(bonds)),
+                    });
+                }
+                Err(err) => {
+                    error!(?err, "Failed to list bonds");
+                    protocol::send_notification(message::Notification::error(
+                        msg.request_id,
+                        message::ErrorCode::HandlerError,
+                        format!("{:?}", err),
+                    ));
+                }
+            },
+            Some(message::message::Body::DeleteBond(delete)) => {
+                let result = delete
+                    .bond
+                    .ok_or_else(|| eyre!("DeleteBond is missing a bond"))
+                    .and_then(|bond| Bond::from_message(&bond))
+                    .and_then(|bond| bonds::delete(&bond));
+                if let Err(err) = &result {
+                    error!(?err, "Failed to delete bond");
+                }
+                protocol::send_notification(message::Notification::reply(msg.request_id, &result));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn advertising_thread() {
+    let rx = message::subscribe("advertising", &[(Topic::SetAdvertisingConfig, 2)]);
+
+    for msg in rx {
+        if let Some(message::message::Body::SetAdvertisingConfig(set)) = msg.body {
+            let result = set
+                .config
+                .ok_or_else(|| eyre!("SetAdvertisingConfig is missing a config"))
+                .and_then(advertising::set);
+            match &result {
+                Ok(()) => bluetooth::restart_advertising(),
+                Err(err) => error!(?err, "Failed to set the advertising config"),
+            }
+            protocol::send_notification(message::Notification::reply(msg.request_id, &result));
+        }
+    }
+}
+
+fn radio_config_thread() {
+    let rx = message::subscribe("radio_config", &[(Topic::SetRadioConfig, 2)]);
+
+    for msg in rx {
+        if let Some(message::message::Body::SetRadioConfig(set)) = msg.body {
+            let result = set
+                .config
+                .ok_or_else(|| eyre!("SetRadioConfig is missing a config"))
+                .and_then(radio::set);
+            if let Err(err) = &result {
+                error!(?err, "Failed to set the radio config");
+            }
+            protocol::send_notification(message::Notification::reply(msg.request_id, &result));
+        }
+    }
+}
+
+fn outbox_config_thread() {
+    let rx = message::subscribe("outbox_config", &[(Topic::SetOutboxConfig, 2)]);
+
+    for msg in rx {
+        if let Some(message::message::Body::SetOutboxConfig(set)) = msg.body {
+            let result = set
+                .config
+                .ok_or_else(|| eyre!("SetOutboxConfig is missing a config"))
+                .and_then(outbox::set);
+            match &result {
+                Ok(()) => bluetooth::poke_outbox(),
+                Err(err) => error!(?err, "Failed to set the outbox config"),
+            }
+            protocol::send_notification(message::Notification::reply(msg.request_id, &result));
+        }
+    }
+}
+
+/// Turns the radio off once nothing has used it for a while, and back on for
+/// the side button or a sync window
+fn radio_thread() {
+    let wakes = radio::wakes();
+    let mut policy = radio::Policy::new(Instant::now());
+    let mut last_check = Instant::now();
+
+    loop {
+        let woken = wakes.recv_timeout(radio::CHECK_INTERVAL).is_ok();
+        let now = Instant::now();
+        let on = bluetooth::radio_on();
+        radio::record(on, now.duration_since(last_check));
+        last_check = now;
+
+        let config = radio::current();
+        let active = on && (connections::count() > 0 || bluetooth::advertising());
+        match policy.poll(&config, now, active, woken) {
+            Some(radio::Action::Stop) => {
+                info!("Nothing is using the radio, turning it off");
+                if let Err(err) = bluetooth::stop_ble() {
+                    error!(?err, "Failed to stop bluetooth");
+                }
+            }
+            Some(radio::Action::Start) => {
+                info!(woken, "Turning the radio back on");
+                match bluetooth::start_ble() {
+                    Ok(()) => radio::restarted(),
+                    Err(err) => error!(?err, "Failed to start bluetooth"),
+                }
+            }
+            None if woken => {
+                info!("Starting advertise");
+                bluetooth::ble_spp_server_advertise();
+            }
+            None => {}
+        }
+        radio::set_next_sync(policy.next_sync(&config));
+    }
+}
+
+fn dfu_thread() {
+    let mut updater = ota::Updater::default();
+
+    for (conn_handle, request) in bluetooth::dfu_requests() {
+        let (status, written, reboot) = updater.handle(conn_handle, request);
+        if status != ota::Status::Ok {
+            error!(?status, written, "DFU request failed");
+        }
+        bluetooth::dfu_respond(conn_handle, status, written);
+
+        if reboot {
+            // give the response a chance to get out first
+            std::thread::sleep(Duration::from_secs(1));
+            unsafe { esp_idf_sys::esp_restart() };
         }
     }
 }
 
 fn syncer_thread(rtc: Arc<Mutex<EspRtc>>) {
-    let rx = message::get_receiver();
+    let rx = message::subscribe("syncer", &[(Topic::SyncClock, 2)]);
 
     for msg in rx {
-        if let Some(message::message::Body::SyncClock(message::SyncClock {
-            timestamp: Some(ts),
-        })) = msg.body
+        if let Some(message::message::Body::SyncClock(message::SyncClock { timestamp })) = msg.body
         {
-            let ts = Timestamp::new(ts.seconds, ts.nanos as u32);
-            if let Err(err) = rtc.lock().unwrap().set(ts.to_utc()) {
+            let result = timestamp
+                .ok_or_else(|| eyre!("SyncClock is missing a timestamp"))
+                .and_then(|ts| {
+                    let ts = Timestamp::new(ts.seconds, ts.nanos as u32);
+                    rtc.lock().unwrap().set(ts.to_utc())
+                });
+            if let Err(err) = &result {
                 error!(?err, "Failed to set RTC");
             }
+            protocol::send_notification(message::Notification::reply(msg.request_id, &result));
+        }
+    }
+}
+
+/// Keeps the RTC in line with the phone's Current Time Service, for phones
+/// without the app
+fn cts_thread(rtc: Arc<Mutex<EspRtc>>) {
+    for now in bluetooth::time_updates() {
+        if let Err(err) = rtc.lock().unwrap().set(now) {
+            error!(?err, "Failed to set RTC from Current Time");
         }
     }
 }
 
+fn device_info_thread() {
+    let rx = message::subscribe(
+        "device_info",
+        &[(Topic::GetDeviceInfo, 2), (Topic::GetDiagnostics, 2)],
+    );
+
+    for msg in rx {
+        let body = match msg.body {
+            Some(message::message::Body::GetDeviceInfo(_)) => {
+                message::notification::Body::DeviceInfo(device_info::device_info(msg.request_id))
+            }
+            Some(message::message::Body::GetDiagnostics(_)) => {
+                message::notification::Body::Diagnostics(device_info::diagnostics(msg.request_id))
+            }
+            _ => continue,
+        };
+        protocol::send_notification(message::Notification { body: Some(body) });
+    }
+}
+
 // me when HKTs
 
 macro_rules! impl_pinstate {
@@ -146,7 +471,7 @@ struct PinStates {
 }
 
 macro_rules! do_pinop {
-    ($set_pin:expr, $state:ident, $pin:ident, $tx:ident, $adc:ident) => {
+    ($set_pin:expr, $request_id:expr, $state:ident, $pin:ident, $adc:ident) => {
         match $set_pin.op() {
             message::PinOperation::SetHigh => $state.$pin = $state.$pin.set_high(),
             message::PinOperation::SetLow => $state.$pin = $state.$pin.set_low(),
@@ -157,11 +482,12 @@ macro_rules! do_pinop {
                 let pinread = message::PinRead {
                     pin: $set_pin.pin,
                     value: val as f32,
+                    request_id: $request_id,
                 };
                 let msg = message::Notification {
                     body: Some(message::notification::Body::PinRead(pinread)),
                 };
-                let _ = $tx.send(msg);
+                protocol::send_notification(msg);
             }
         }
     };
@@ -192,16 +518,18 @@ fn pin_thread(g26: Gpio26<Unknown>, g25: Gpio25<Unknown>, g0: Gpio0<Unknown>, ad
         g26: PinState26 { p: g26 },
     };
 
-    let rx = message::get_receiver();
-
-    let tx = bluetooth::QUEUE.0.clone();
+    let rx = message::subscribe("pin", &[(Topic::SetPin, 8)]);
 
     for msg in rx {
         if let Some(message::message::Body::SetPin(set_pin)) = msg.body {
             match set_pin.pin() {
-                message::Pins::G26 => do_pinop!(set_pin, state, g26, tx, adc),
-                message::Pins::G25 => do_pinop!(set_pin, state, g25, tx, adc),
-                message::Pins::G0 => do_pinop!(set_pin, state, g0, tx, adc),
+                message::Pins::G26 => do_pinop!(set_pin, msg.request_id, state, g26, adc),
+                message::Pins::G25 => do_pinop!(set_pin, msg.request_id, state, g25, adc),
+                message::Pins::G0 => do_pinop!(set_pin, msg.request_id, state, g0, adc),
+            }
+            // a read is answered by its PinRead
+            if set_pin.op() != message::PinOperation::AnalogueRead {
+                protocol::send_notification(message::Notification::ack(msg.request_id));
             }
         }
     }
@@ -216,6 +544,12 @@ fn main() -> Result<()> {
 
     println!("Hello, world!");
 
+    auth::use_store(storage::AuthStore);
+    match storage::OutboxStore::open() {
+        Ok(store) => outbox::use_store(store),
+        Err(err) => error!(?err, "Couldn't open the outbox config, using the default"),
+    }
+
     let pm_config = esp_idf_sys::esp_pm_config_esp32_t {
         max_freq_mhz: 80,
         min_freq_mhz: 40,
@@ -251,10 +585,13 @@ fn main() -> Result<()> {
 
     let button_state = Arc::new(AtomicBool::new(false));
     let (wake_tx, wake_rx) = std::sync::mpsc::channel();
+    let (button_tx, button_rx) = std::sync::mpsc::channel();
 
     let front_btn_callback = {
         let button_state = Arc::clone(&button_state);
         let wake_tx = wake_tx.clone();
+        let button_tx = button_tx.clone();
+        let pressed_at = Mutex::new(Instant::now());
         move |p: &Gpio37<SubscribedInput>| {
             let current = p.is_low().unwrap();
             let prev = button_state.swap(current, std::sync::atomic::Ordering::Relaxed);
@@ -263,6 +600,13 @@ fn main() -> Result<()> {
 
             if prev != current {
                 let _ = wake_tx.send(current);
+                if current {
+                    *pressed_at.lock().unwrap() = Instant::now();
+                } else if pressed_at.lock().unwrap().elapsed() >= LONG_PRESS {
+                    let _ = button_tx.send(Button::FrontLong);
+                } else {
+                    let _ = button_tx.send(Button::Front);
+                }
             }
         }
     };
@@ -279,8 +623,12 @@ fn main() -> Result<()> {
             info!(current, prev, "side button");
 
             if prev != current && current {
-                info!("Starting advertise");
-                bluetooth::ble_spp_server_advertise();
+                // the side button answers alerts and the like while one is up
+                if side_button_taken() {
+                    let _ = button_tx.send(Button::Side);
+                } else {
+                    radio::wake();
+                }
             }
         }
     };
@@ -292,11 +640,45 @@ fn main() -> Result<()> {
         move || waker_thread(wake_tx)
     });
 
+    let _pairing_thread = std::thread::Builder::new().stack_size(4096).spawn({
+        let wake_tx = wake_tx.clone();
+        move || pairing_thread(wake_tx)
+    });
+
+    let _bonds_thread = std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(bonds_thread);
+
+    let _advertising_thread = std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(advertising_thread);
+
+    let _radio_config_thread = std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(radio_config_thread);
+
+    let _outbox_config_thread = std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(outbox_config_thread);
+
+    let _dfu_thread = std::thread::Builder::new()
+        .stack_size(8192)
+        .spawn(dfu_thread);
+
     let _syncer_thread = std::thread::Builder::new().stack_size(4096).spawn({
         let rtc = Arc::clone(&rtc);
         move || syncer_thread(rtc)
     });
 
+    let _cts_thread = std::thread::Builder::new().stack_size(4096).spawn({
+        let rtc = Arc::clone(&rtc);
+        move || cts_thread(rtc)
+    });
+
+    let _device_info_thread = std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(device_info_thread);
+
     let _pin_thread = std::thread::Builder::new().stack_size(4096).spawn({
         let g26 = pins.gpio26;
         let g25 = pins.gpio25;
@@ -309,6 +691,20 @@ fn main() -> Result<()> {
 
     bluetooth::init_ble()?;
 
+    let _radio_thread = std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(radio_thread);
+
+    serial::init()?;
+
+    // we got far enough to take another update, so there's no need to roll
+    // back
+    ota::confirm_running_image();
+
+    let mut screen = Screen::Time;
+    let mut selected = 0;
+    let mut bonds = Vec::new();
+
     loop {
         let mut end_time = Instant::now() + Duration::from_secs(20);
 
@@ -324,7 +720,52 @@ fn main() -> Result<()> {
             let now = rtc.lock().unwrap().read()?;
             info!(%now, "Current utc time");
 
-            display.display_time(now, batt_vol)?;
+            let pairing = *PAIRING.lock().unwrap();
+            let alert = visible_alert();
+            let update = ota::progress();
+            let next_screen = match (&pairing, &alert) {
+                (Some(pairing), _) => Screen::Passkey(pairing.passkey),
+                _ if update.is_some() => Screen::Update,
+                (None, Some((_, received))) => Screen::Alert(*received),
+                (None, None) if BONDS_OPEN.load(Ordering::Relaxed) => Screen::Bonds,
+                (None, None) if MENU_OPEN.load(Ordering::Relaxed) => Screen::Menu,
+                (None, None) => Screen::Time,
+            };
+
+            if next_screen != screen {
+                display.clear()?;
+                selected = 0;
+                match next_screen {
+                    Screen::Passkey(passkey) => display.display_passkey(passkey)?,
+                    Screen::Alert(_) => {
+                        if let Some((alert, _)) = &alert {
+                            display.display_alert(alert)?;
+                            display
+                                .display_alert_choice(alert, alert.choice_label(Choice::Open))?;
+                        }
+                    }
+                    Screen::Bonds => {
+                        bonds = bonds::list().unwrap_or_else(|err| {
+                            error!(?err, "Failed to list bonds");
+                            Vec::new()
+                        });
+                        display.display_bonds(&bonds, selected)?;
+                    }
+                    Screen::Menu => {
+                        display.display_menu(selected, MENU.len(), &MENU[selected].describe())?
+                    }
+                    Screen::Update | Screen::Time => {}
+                }
+                screen = next_screen;
+            }
+
+            if let (Screen::Update, Some((written, size))) = (screen, update) {
+                display.display_update(written, size)?;
+            }
+
+            if screen == Screen::Time {
+                display.display_time(now, batt_vol)?;
+            }
 
             while let Ok(v) = wake_rx.try_recv() {
                 info!("Button press: {v}");
@@ -335,11 +776,101 @@ fn main() -> Result<()> {
 
             if !bluetooth::ble_connected() && Instant::now() > end_time {
                 info!("Disabling backlight");
+                BONDS_OPEN.store(false, Ordering::Relaxed);
+                MENU_OPEN.store(false, Ordering::Relaxed);
                 pwr.set_backlight(false)?;
                 break 'inner;
             }
 
-            std::thread::sleep(Duration::from_secs(1));
+            let button = match button_rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(button) => button,
+                Err(_) => continue,
+            };
+
+            match screen {
+                Screen::Update => {}
+                Screen::Passkey(_) => {
+                    if button == Button::Side {
+                        if let Some(pairing) = PAIRING.lock().unwrap().take() {
+                            bluetooth::reject_pairing(pairing.conn_handle);
+                        }
+                    }
+                }
+                Screen::Alert(_) => {
+                    if let Some((alert, _)) = &alert {
+                        let choices = alert.choices();
+                        match button {
+                            Button::Front | Button::FrontLong => {
+                                selected = (selected + 1) % choices.len();
+                                let label = alert.choice_label(choices[selected]);
+                                display.display_alert_choice(alert, label)?;
+                            }
+                            Button::Side => {
+                                info!(choice = ?choices[selected], id = alert.id, "Responding to alert");
+                                match alert.source {
+                                    Source::App => protocol::send_notification(
+                                        alert.respond(choices[selected]),
+                                    ),
+                                    Source::Ancs => {
+                                        let action = match choices[selected] {
+                                            Choice::Custom(_) => ancs::Action::Positive,
+                                            _ => ancs::Action::Negative,
+                                        };
+                                        bluetooth::ancs_perform_action(alert.id, action);
+                                    }
+                                }
+                                *CURRENT_NOTIF.lock().unwrap() = None;
+                            }
+                        }
+                    }
+                }
+                Screen::Bonds => match button {
+                    Button::Front | Button::FrontLong => {
+                        selected = (selected + 1) % (bonds.len() + 1);
+                        display.display_bonds(&bonds, selected)?;
+                    }
+                    Button::Side if selected == 0 => BONDS_OPEN.store(false, Ordering::Relaxed),
+                    Button::Side => {
+                        let bond = bonds.remove(selected - 1);
+                        info!(%bond, "Forgetting bond");
+                        if let Err(err) = bonds::delete(&bond) {
+                            error!(?err, "Failed to delete bond");
+                        }
+                        selected = 0;
+                        display.display_bonds(&bonds, selected)?;
+                    }
+                },
+                Screen::Menu => match button {
+                    Button::Front | Button::FrontLong => {
+                        selected = (selected + 1) % MENU.len();
+                        display.display_menu(selected, MENU.len(), &MENU[selected].describe())?;
+                    }
+                    Button::Side => {
+                        MENU_OPEN.store(false, Ordering::Relaxed);
+                        match MENU[selected] {
+                            MenuEntry::Back => {}
+                            MenuEntry::Bonds => BONDS_OPEN.store(true, Ordering::Relaxed),
+                            MenuEntry::Mode(mode) => {
+                                // the services and advertisement say whether
+                                // we're a keyboard
+                                let result = hid::set_mode(mode)
+                                    .and_then(|()| bluetooth::hid_mode_changed());
+                                if let Err(err) = result {
+                                    error!(?err, "Failed to set the button mode");
+                                }
+                            }
+                        }
+                    }
+                },
+                Screen::Time => match (button, hid_keys()) {
+                    (Button::Front, Some((front, _))) => bluetooth::send_hid_key(front),
+                    (Button::Side, Some((_, side))) => bluetooth::send_hid_key(side),
+                    (Button::Front | Button::FrontLong, _) => {
+                        MENU_OPEN.store(true, Ordering::Relaxed)
+                    }
+                    (Button::Side, None) => {}
+                },
+            }
         }
 
         // seems to work?
@@ -347,11 +878,48 @@ fn main() -> Result<()> {
             esp_idf_sys::esp_sleep_enable_ext0_wakeup(esp_idf_sys::gpio_num_t_GPIO_NUM_37, 0)
         })?;
 
-        unsafe {
-            esp!(esp_idf_sys::esp_light_sleep_start())?;
+        'sleep: loop {
+            // wake up for the next sync window, if the radio is off
+            let sync_in = radio::next_sync().map(|at| at.saturating_duration_since(Instant::now()));
+            unsafe {
+                match sync_in {
+                    Some(sync_in) => esp!(esp_idf_sys::esp_sleep_enable_timer_wakeup(
+                        sync_in.as_micros() as u64
+                    ))?,
+                    None => {
+                        esp_idf_sys::esp_sleep_disable_wakeup_source(
+                            esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER,
+                        );
+                    }
+                }
+
+                esp!(esp_idf_sys::esp_light_sleep_start())?;
+            }
+
+            let synced = unsafe { esp_idf_sys::esp_sleep_get_wakeup_cause() }
+                == esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER;
+
+            // a sync window keeps the screen off, and goes back to sleep once
+            // the radio has been and gone
+            loop {
+                match wake_rx.recv_timeout(radio::CHECK_INTERVAL) {
+                    Ok(true) => break 'sleep,
+                    Err(_)
+                        if synced
+                            && !bluetooth::radio_on()
+                            && radio::next_sync().map_or(true, |at| at > Instant::now()) =>
+                    {
+                        continue 'sleep
+                    }
+                    _ => {}
+                }
+            }
         }
 
-        while wake_rx.recv().unwrap() == false {}
+        // presses that woke us up shouldn't also act on whatever is on screen,
+        // and the front button only acts once it's let go
+        while wake_rx.recv_timeout(LONG_PRESS).unwrap_or(false) {}
+        while button_rx.try_recv().is_ok() {}
 
         pwr.set_backlight(true)?;
     }
@@ -0,0 +1,338 @@
+//! Firmware updates over BLE. An image arrives in chunks through the DFU
+//! service and is written to whichever OTA partition we aren't running from.
+//! It only becomes the boot partition once its SHA-256 matches, and when built
+//! with `signed-ota`, once its Ed25519 signature checks out too.
+//!
+//! A new image boots pending verification. If it reboots before calling
+//! [`confirm_running_image`], the bootloader rolls back to the old one.
+
+use std::ffi::c_void;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use color_eyre::eyre::{bail, ensure};
+use esp_idf_sys::{
+    esp, esp_ota_abort, esp_ota_begin, esp_ota_end, esp_ota_get_next_update_partition,
+    esp_ota_get_running_partition, esp_ota_get_state_partition, esp_ota_handle_t,
+    esp_ota_img_states_t, esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY,
+    esp_ota_mark_app_valid_cancel_rollback, esp_ota_set_boot_partition, esp_ota_write,
+    esp_partition_t,
+};
+use sha2::{Digest, Sha256};
+use tracing::{error, info};
+
+use crate::axp192::{BATTERY_PERCENT, POWER_STATE};
+
+/// Below this, an update is refused unless the watch is plugged in
+pub const MIN_BATTERY_PERCENT: u8 = 30;
+
+const COMMAND_START: u8 = 1;
+const COMMAND_FINISH: u8 = 2;
+const COMMAND_ABORT: u8 = 3;
+
+const SHA256_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+/// Size, then the SHA-256 of the image, then optionally its signature
+const START_LEN: usize = 1 + 4 + SHA256_LEN;
+/// The offset a chunk of image goes at, before the chunk itself
+const OFFSET_LEN: usize = 4;
+
+#[cfg(feature = "signed-ota")]
+const PUBLIC_KEY: &[u8; 32] = include_bytes!("../ota-public-key.bin");
+
+/// Sent back on the control point after every command and chunk, along with
+/// how much of the image has been written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    BadCommand = 1,
+    NotStarted = 2,
+    AlreadyStarted = 3,
+    LowBattery = 4,
+    TooLarge = 5,
+    BadOffset = 6,
+    Incomplete = 7,
+    BadDigest = 8,
+    BadSignature = 9,
+    FlashError = 10,
+    /// Too many requests in flight, the phone should wait for responses
+    Busy = 11,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Start {
+    pub size: u32,
+    pub sha256: [u8; SHA256_LEN],
+    pub signature: Option<[u8; SIGNATURE_LEN]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Start(Start),
+    Data { offset: u32, data: Vec<u8> },
+    Finish,
+    Abort,
+}
+
+/// Parses a write to the control point
+pub fn parse_command(buf: &[u8]) -> color_eyre::Result<Request> {
+    match buf.first() {
+        Some(&COMMAND_START) => {
+            ensure!(
+                buf.len() == START_LEN || buf.len() == START_LEN + SIGNATURE_LEN,
+                "Start is {} bytes, expected {} or {}",
+                buf.len(),
+                START_LEN,
+                START_LEN + SIGNATURE_LEN
+            );
+            Ok(Request::Start(Start {
+                size: u32::from_le_bytes(buf[1..5].try_into().unwrap()),
+                sha256: buf[5..START_LEN].try_into().unwrap(),
+                signature: buf[START_LEN..].try_into().ok(),
+            }))
+        }
+        Some(&COMMAND_FINISH) => Ok(Request::Finish),
+        Some(&COMMAND_ABORT) => Ok(Request::Abort),
+        Some(cmd) => bail!("Unknown DFU command {}", cmd),
+        None => bail!("Empty DFU command"),
+    }
+}
+
+/// Parses a write to the data characteristic
+pub fn parse_data(buf: &[u8]) -> color_eyre::Result<Request> {
+    ensure!(
+        buf.len() > OFFSET_LEN,
+        "DFU data is {} bytes, which doesn't leave room for any image",
+        buf.len()
+    );
+
+    Ok(Request::Data {
+        offset: u32::from_le_bytes(buf[..OFFSET_LEN].try_into().unwrap()),
+        data: buf[OFFSET_LEN..].to_vec(),
+    })
+}
+
+/// The response to a request: the status, then how many bytes of the image
+/// have been written
+pub fn encode_response(status: Status, written: u32) -> [u8; 5] {
+    let [a, b, c, d] = written.to_le_bytes();
+    [status as u8, a, b, c, d]
+}
+
+/// Bytes written and the size of the image, while an update is going
+static PROGRESS: Mutex<Option<(u32, u32)>> = Mutex::new(None);
+
+pub fn progress() -> Option<(u32, u32)> {
+    *PROGRESS.lock().unwrap()
+}
+
+struct Update {
+    /// The phone sending the update
+    conn_handle: u16,
+    handle: esp_ota_handle_t,
+    partition: *const esp_partition_t,
+    size: u32,
+    written: u32,
+    hasher: Sha256,
+    sha256: [u8; SHA256_LEN],
+    signature: Option<[u8; SIGNATURE_LEN]>,
+}
+
+/// Takes requests from the DFU service, one at a time
+#[derive(Default)]
+pub struct Updater {
+    update: Option<Update>,
+}
+
+impl Updater {
+    /// Handles a request, giving the status to send back and whether it's
+    /// time to reboot into the new image
+    pub fn handle(&mut self, conn_handle: u16, request: Request) -> (Status, u32, bool) {
+        if let Some(update) = &self.update {
+            if update.conn_handle != conn_handle {
+                return (Status::AlreadyStarted, update.written, false);
+            }
+        }
+
+        let result = match request {
+            Request::Start(start) => self.start(conn_handle, start),
+            Request::Data { offset, data } => self.write(offset, &data),
+            Request::Finish => self.finish().map(|()| true),
+            Request::Abort => {
+                self.abort();
+                Ok(false)
+            }
+        };
+
+        let written = self.update.as_ref().map_or(0, |update| update.written);
+        *PROGRESS.lock().unwrap() = self
+            .update
+            .as_ref()
+            .map(|update| (update.written, update.size));
+
+        match result {
+            Ok(reboot) => (Status::Ok, written, reboot),
+            Err(status) => (status, written, false),
+        }
+    }
+
+    fn start(&mut self, conn_handle: u16, start: Start) -> Result<bool, Status> {
+        if self.update.is_some() {
+            return Err(Status::AlreadyStarted);
+        }
+
+        let battery = BATTERY_PERCENT.load(Ordering::Relaxed);
+        if battery < MIN_BATTERY_PERCENT && !POWER_STATE.lock().unwrap().vbus_present {
+            error!(battery, "Refusing to update on a low battery");
+            return Err(Status::LowBattery);
+        }
+
+        #[cfg(feature = "signed-ota")]
+        if start.signature.is_none() {
+            error!("Refusing an unsigned update");
+            return Err(Status::BadSignature);
+        }
+
+        let partition = unsafe { esp_ota_get_next_update_partition(std::ptr::null()) };
+        if partition.is_null() {
+            error!("No OTA partition to update");
+            return Err(Status::FlashError);
+        }
+        if start.size == 0 || start.size > unsafe { (*partition).size } {
+            return Err(Status::TooLarge);
+        }
+
+        // erases as much of the partition as the image needs, which takes a
+        // while
+        let mut handle = 0;
+        if let Err(err) =
+            esp!(unsafe { esp_ota_begin(partition, start.size as usize, &mut handle) })
+        {
+            error!(?err, "Failed to start writing the update");
+            return Err(Status::FlashError);
+        }
+
+        info!(size = start.size, "Starting firmware update");
+        self.update = Some(Update {
+            conn_handle,
+            handle,
+            partition,
+            size: start.size,
+            written: 0,
+            hasher: Sha256::new(),
+            sha256: start.sha256,
+            signature: start.signature,
+        });
+
+        Ok(false)
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<bool, Status> {
+        let update = self.update.as_mut().ok_or(Status::NotStarted)?;
+
+        // chunks have to come in order, a lost one means starting again
+        if offset != update.written {
+            error!(offset, written = update.written, "Out of order DFU data");
+            self.abort();
+            return Err(Status::BadOffset);
+        }
+        if update.written as usize + data.len() > update.size as usize {
+            self.abort();
+            return Err(Status::TooLarge);
+        }
+
+        if let Err(err) = esp!(unsafe {
+            esp_ota_write(update.handle, data.as_ptr() as *const c_void, data.len())
+        }) {
+            error!(?err, "Failed to write the update");
+            self.abort();
+            return Err(Status::FlashError);
+        }
+
+        update.hasher.update(data);
+        update.written += data.len() as u32;
+
+        Ok(false)
+    }
+
+    fn finish(&mut self) -> Result<(), Status> {
+        let update = self.update.take().ok_or(Status::NotStarted)?;
+        let result = Self::verify(&update);
+
+        if let Err(status) = result {
+            unsafe { esp_ota_abort(update.handle) };
+            return Err(status);
+        }
+
+        // also checks the image is one the bootloader can run
+        if let Err(err) = esp!(unsafe { esp_ota_end(update.handle) }) {
+            error!(?err, "Update isn't a valid image");
+            return Err(Status::BadDigest);
+        }
+        if let Err(err) = esp!(unsafe { esp_ota_set_boot_partition(update.partition) }) {
+            error!(?err, "Failed to switch to the update");
+            return Err(Status::FlashError);
+        }
+
+        info!("Firmware update written, rebooting into it");
+        Ok(())
+    }
+
+    fn verify(update: &Update) -> Result<(), Status> {
+        if update.written != update.size {
+            return Err(Status::Incomplete);
+        }
+
+        let digest = update.hasher.clone().finalize();
+        if digest.as_slice() != update.sha256 {
+            error!("Update doesn't match its SHA-256");
+            return Err(Status::BadDigest);
+        }
+
+        #[cfg(feature = "signed-ota")]
+        {
+            let valid = update.signature.map_or(false, |signature| {
+                let key = ed25519_compact::PublicKey::new(*PUBLIC_KEY);
+                let signature = ed25519_compact::Signature::new(signature);
+                key.verify(digest, &signature).is_ok()
+            });
+            if !valid {
+                error!("Update isn't signed by our key");
+                return Err(Status::BadSignature);
+            }
+        }
+        #[cfg(not(feature = "signed-ota"))]
+        if update.signature.is_some() {
+            info!("Ignoring the update's signature, built without signed-ota");
+        }
+
+        Ok(())
+    }
+
+    fn abort(&mut self) {
+        if let Some(update) = self.update.take() {
+            info!(written = update.written, "Abandoning firmware update");
+            unsafe { esp_ota_abort(update.handle) };
+        }
+    }
+}
+
+/// Tells the bootloader this image works, so it won't roll back to the last
+/// one. Does nothing if we weren't booted fresh from an update.
+pub fn confirm_running_image() {
+    unsafe {
+        let mut state: esp_ota_img_states_t = 0;
+        let running = esp_ota_get_running_partition();
+        if esp!(esp_ota_get_state_partition(running, &mut state)).is_err()
+            || state != esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+        {
+            return;
+        }
+
+        match esp!(esp_ota_mark_app_valid_cancel_rollback()) {
+            Ok(()) => info!("Confirmed the updated firmware"),
+            Err(err) => error!(?err, "Failed to confirm the updated firmware"),
+        }
+    }
+}
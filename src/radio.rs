@@ -0,0 +1,318 @@
+//! Turning the radio off while nothing is using it, since an idle stack
+//! still costs more than the rest of the watch put together.
+//!
+//! The stack is shut down once there has been no connection or advertising
+//! for the idle timeout, and comes back when the side button is pressed or at
+//! the next sync window, when it advertises for the phone to catch up. Time
+//! with the radio on and off is kept along with the battery draw measured in
+//! each, which gives an estimate of what the policy saves.
+//!
+//! The config is the protobuf message the phone sets it with, and is kept in
+//! NVS encoded the same way.
+
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::ensure;
+use crossbeam::channel;
+use once_cell::sync::Lazy;
+use prost::Message as _;
+use tracing::{error, info};
+
+use crate::axp192::{BATTERY_PERCENT, BATTERY_POWER_MW, POWER_STATE};
+use crate::message::{RadioConfig, RadioDiagnostics};
+use crate::storage::Nvs;
+
+const NAMESPACE: &str = "radio";
+const CONFIG: &str = "config";
+
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u32 = 5 * 60;
+pub const DEFAULT_SYNC_INTERVAL_SECS: u32 = 30 * 60;
+
+/// Any shorter and bringing the stack back up costs more than was saved
+const MIN_IDLE_TIMEOUT_SECS: u32 = 30;
+const MIN_SYNC_INTERVAL_SECS: u32 = 60;
+
+/// How often the policy is looked at
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The M5StickC Plus's 120mAh cell at its nominal 3.7V
+const BATTERY_CAPACITY_MWH: f64 = 120.0 * 3.7;
+
+pub fn default_config() -> RadioConfig {
+    RadioConfig {
+        idle_timeout_secs: DEFAULT_IDLE_TIMEOUT_SECS,
+        sync_interval_secs: DEFAULT_SYNC_INTERVAL_SECS,
+    }
+}
+
+fn load() -> color_eyre::Result<Option<RadioConfig>> {
+    let nvs = Nvs::open(NAMESPACE)?;
+    let config = match nvs.get_blob(CONFIG)? {
+        Some(buf) => buf,
+        None => return Ok(None),
+    };
+
+    Ok(Some(RadioConfig::decode(config.as_slice())?))
+}
+
+static CURRENT: Lazy<Mutex<RadioConfig>> = Lazy::new(|| {
+    let config = load()
+        .unwrap_or_else(|err| {
+            error!(?err, "Failed to load the radio config, using the default");
+            None
+        })
+        .unwrap_or_else(default_config);
+    Mutex::new(config)
+});
+
+pub fn current() -> RadioConfig {
+    CURRENT.lock().unwrap().clone()
+}
+
+fn validate(config: &RadioConfig) -> color_eyre::Result<()> {
+    ensure!(
+        config.idle_timeout_secs == 0 || config.idle_timeout_secs >= MIN_IDLE_TIMEOUT_SECS,
+        "Idle timeout must be 0 or at least {}s, got {}s",
+        MIN_IDLE_TIMEOUT_SECS,
+        config.idle_timeout_secs
+    );
+    ensure!(
+        config.sync_interval_secs == 0 || config.sync_interval_secs >= MIN_SYNC_INTERVAL_SECS,
+        "Sync interval must be 0 or at least {}s, got {}s",
+        MIN_SYNC_INTERVAL_SECS,
+        config.sync_interval_secs
+    );
+
+    Ok(())
+}
+
+/// Replaces the config, persisting it for the next boot
+pub fn set(config: RadioConfig) -> color_eyre::Result<()> {
+    validate(&config)?;
+
+    Nvs::open(NAMESPACE)?.set_blob(CONFIG, &config.encode_to_vec())?;
+    info!(?config, "Updated the radio config");
+    *CURRENT.lock().unwrap() = config;
+
+    Ok(())
+}
+
+fn secs(secs: u32) -> Option<Duration> {
+    (secs != 0).then(|| Duration::from_secs(secs as u64))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Stop,
+    Start,
+}
+
+/// Decides when the radio goes off and comes back
+#[derive(Debug)]
+pub struct Policy {
+    on: bool,
+    /// When the radio was last in use, or came on
+    active_at: Instant,
+    off_at: Instant,
+}
+
+impl Policy {
+    /// The radio starts out on
+    pub fn new(now: Instant) -> Self {
+        Self {
+            on: true,
+            active_at: now,
+            off_at: now,
+        }
+    }
+
+    /// What to do with the radio, given whether anything is using it and
+    /// whether the side button asked for it since the last look
+    pub fn poll(
+        &mut self,
+        config: &RadioConfig,
+        now: Instant,
+        active: bool,
+        woken: bool,
+    ) -> Option<Action> {
+        if self.on {
+            if active || woken {
+                self.active_at = now;
+                return None;
+            }
+
+            let idle_timeout = secs(config.idle_timeout_secs)?;
+            if now.duration_since(self.active_at) < idle_timeout {
+                return None;
+            }
+
+            self.on = false;
+            self.off_at = now;
+            Some(Action::Stop)
+        } else if woken || self.next_sync(config).map_or(false, |at| now >= at) {
+            self.on = true;
+            self.active_at = now;
+            Some(Action::Start)
+        } else {
+            None
+        }
+    }
+
+    /// When the radio comes back by itself, while it's off
+    pub fn next_sync(&self, config: &RadioConfig) -> Option<Instant> {
+        if self.on {
+            return None;
+        }
+
+        secs(config.sync_interval_secs).map(|interval| self.off_at + interval)
+    }
+}
+
+/// Time spent with the radio on and off, and the battery energy used in each
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    on: Duration,
+    off: Duration,
+    restarts: u32,
+    /// Time spent unplugged, and the energy drawn over it in mJ
+    on_measured: Duration,
+    on_energy: f64,
+    off_measured: Duration,
+    off_energy: f64,
+}
+
+impl Usage {
+    pub const fn new() -> Self {
+        Self {
+            on: Duration::ZERO,
+            off: Duration::ZERO,
+            restarts: 0,
+            on_measured: Duration::ZERO,
+            on_energy: 0.0,
+            off_measured: Duration::ZERO,
+            off_energy: 0.0,
+        }
+    }
+
+    /// Adds `elapsed` to the radio's on or off time, along with the battery
+    /// draw over it if it was measured
+    pub fn record(&mut self, on: bool, elapsed: Duration, power_mw: Option<u32>) {
+        let (time, measured, energy) = if on {
+            (&mut self.on, &mut self.on_measured, &mut self.on_energy)
+        } else {
+            (&mut self.off, &mut self.off_measured, &mut self.off_energy)
+        };
+
+        *time += elapsed;
+        if let Some(power_mw) = power_mw {
+            *measured += elapsed;
+            *energy += power_mw as f64 * elapsed.as_secs_f64();
+        }
+    }
+
+    pub fn restarted(&mut self) {
+        self.restarts += 1;
+    }
+
+    fn on_power_mw(&self) -> Option<f64> {
+        (!self.on_measured.is_zero()).then(|| self.on_energy / self.on_measured.as_secs_f64())
+    }
+
+    fn off_power_mw(&self) -> Option<f64> {
+        (!self.off_measured.is_zero()).then(|| self.off_energy / self.off_measured.as_secs_f64())
+    }
+
+    /// Hours of battery left at `battery_percent`, with the radio going on
+    /// and off as it has been, and with it left on
+    pub fn hours_left(&self, battery_percent: u8) -> (Option<f64>, Option<f64>) {
+        let remaining_mwh = BATTERY_CAPACITY_MWH * battery_percent as f64 / 100.0;
+        let on_power = match self.on_power_mw() {
+            Some(power) => power,
+            None => return (None, None),
+        };
+
+        let average_power = if self.off.is_zero() {
+            Some(on_power)
+        } else {
+            let on_share = self.on.as_secs_f64() / (self.on + self.off).as_secs_f64();
+            self.off_power_mw()
+                .map(|off_power| on_share * on_power + (1.0 - on_share) * off_power)
+        };
+
+        (
+            average_power.map(|power| remaining_mwh / power),
+            Some(remaining_mwh / on_power),
+        )
+    }
+
+    pub fn to_message(&self, on: bool, battery_percent: u8) -> RadioDiagnostics {
+        let (hours_left, hours_left_always_on) = self.hours_left(battery_percent);
+
+        RadioDiagnostics {
+            on,
+            on_ms: self.on.as_millis() as u64,
+            off_ms: self.off.as_millis() as u64,
+            restarts: self.restarts,
+            on_power_mw: self.on_power_mw().unwrap_or(0.0) as u32,
+            off_power_mw: self.off_power_mw().unwrap_or(0.0) as u32,
+            hours_left: hours_left.unwrap_or(0.0) as f32,
+            hours_left_always_on: hours_left_always_on.unwrap_or(0.0) as f32,
+        }
+    }
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static USAGE: Mutex<Usage> = Mutex::new(Usage::new());
+
+/// Adds `elapsed` to the radio's on or off time, with the battery draw from
+/// the last reading if the watch is unplugged
+pub fn record(on: bool, elapsed: Duration) {
+    let power_mw = match BATTERY_POWER_MW.load(Ordering::Relaxed) {
+        0 => None,
+        _ if POWER_STATE.lock().unwrap().vbus_present => None,
+        power_mw => Some(power_mw),
+    };
+
+    USAGE.lock().unwrap().record(on, elapsed, power_mw);
+}
+
+pub fn restarted() {
+    USAGE.lock().unwrap().restarted();
+}
+
+pub fn diagnostics(on: bool) -> RadioDiagnostics {
+    USAGE
+        .lock()
+        .unwrap()
+        .to_message(on, BATTERY_PERCENT.load(Ordering::Relaxed))
+}
+
+/// When the radio next comes back by itself, so sleep can wake up for it
+static NEXT_SYNC: Mutex<Option<Instant>> = Mutex::new(None);
+
+pub fn next_sync() -> Option<Instant> {
+    *NEXT_SYNC.lock().unwrap()
+}
+
+pub fn set_next_sync(at: Option<Instant>) {
+    *NEXT_SYNC.lock().unwrap() = at;
+}
+
+/// Poked by the side button, to bring the radio back or advertise again
+static WAKES: Lazy<(channel::Sender<()>, channel::Receiver<()>)> =
+    Lazy::new(|| channel::bounded(1));
+
+pub fn wake() {
+    let _ = WAKES.0.try_send(());
+}
+
+pub fn wakes() -> channel::Receiver<()> {
+    WAKES.1.clone()
+}
@@ -0,0 +1,68 @@
+//! Getting a bonded phone back after the link drops, usually from walking out
+//! of range.
+//!
+//! Straight after the disconnect the watch sends high duty cycle advertising
+//! addressed to the phone, which finds it again within a second or so if it's
+//! still about. After that it advertises so only that phone can connect,
+//! starting fast and doubling the interval every step down to a low duty
+//! cycle, until it gives up.
+
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_GIVE_UP_SECS: u32 = 10 * 60;
+
+/// The intervals Apple suggests accessories start and end up at
+const FAST_INTERVAL_MS: u32 = 20;
+const SLOW_INTERVAL_MS: u32 = 1285;
+/// How long each interval is tried before backing off
+const STEP_DURATION: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Addressed to the phone, which the controller stops after 1.28s
+    Directed,
+    /// Connectable only by the phone
+    Whitelisted {
+        interval_ms: u32,
+        duration: Duration,
+    },
+}
+
+/// A phone we're trying to get back
+#[derive(Debug, Clone, Copy)]
+pub struct Reconnect {
+    pub addr_type: u8,
+    /// Identity address of the phone, least significant byte first
+    pub addr: [u8; 6],
+    started_at: Instant,
+    attempts: u32,
+}
+
+impl Reconnect {
+    pub fn new(addr_type: u8, addr: [u8; 6], now: Instant) -> Self {
+        Self {
+            addr_type,
+            addr,
+            started_at: now,
+            attempts: 0,
+        }
+    }
+
+    /// How to advertise next, or `None` once it's time to give up
+    pub fn next(&mut self, give_up: Duration, now: Instant) -> Option<Step> {
+        let remaining = give_up
+            .checked_sub(now.duration_since(self.started_at))
+            .filter(|remaining| !remaining.is_zero())?;
+
+        let step = match self.attempts {
+            0 => Step::Directed,
+            n => Step::Whitelisted {
+                interval_ms: (FAST_INTERVAL_MS << (n - 1).min(16)).min(SLOW_INTERVAL_MS),
+                duration: STEP_DURATION.min(remaining),
+            },
+        };
+        self.attempts += 1;
+
+        Some(step)
+    }
+}
@@ -0,0 +1,136 @@
+//! Carries messages over the USB serial port, so watches can be provisioned
+//! and debugged on the bench.
+//!
+//! The port is UART0, the only one wired to USB, which the console shares.
+//! Each payload is COBS encoded and wrapped in zero bytes on both sides, and
+//! once the first frame arrives the console stops logging, so nothing gets
+//! written into the middle of a frame.
+
+use std::sync::Once;
+use std::thread::JoinHandle;
+
+use color_eyre::eyre::eyre;
+use esp_idf_sys::{
+    esp, esp_log_level_set, esp_log_level_t_ESP_LOG_NONE, uart_driver_install, uart_port_t,
+    uart_read_bytes, uart_write_bytes, TickType_t, UART_NUM_0,
+};
+use once_cell::sync::OnceCell;
+use tracing::{error, info};
+
+use crate::protocol;
+
+const PORT: uart_port_t = UART_NUM_0 as uart_port_t;
+const UART_BUF_LEN: usize = 2048;
+
+/// Same limit as BLE puts on reassembled messages
+const MAX_FRAME_LEN: usize = 16 * 1024;
+
+static RX_THREAD: OnceCell<JoinHandle<()>> = OnceCell::new();
+
+struct SerialTransport;
+
+impl SerialTransport {
+    const NAME: &'static str = "serial";
+}
+
+impl protocol::Transport for SerialTransport {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn send(&mut self, payload: &[u8]) -> color_eyre::Result<()> {
+        let mut frame = vec![0u8; cobs::max_encoding_length(payload.len()) + 2];
+        let len = cobs::encode(payload, &mut frame[1..]);
+        frame.truncate(len + 2);
+
+        let written = unsafe { uart_write_bytes(PORT, frame.as_ptr() as *const _, frame.len()) };
+        if written < 0 {
+            return Err(eyre!("Failed to write to UART"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Turns off logging to the console for good, ours and ESP-IDF's, as a log
+/// line can land in the middle of a frame and corrupt it
+fn quiet_console() {
+    static QUIET: Once = Once::new();
+    QUIET.call_once(|| {
+        info!("Serial frames arriving, turning console logging off");
+        log::set_max_level(log::LevelFilter::Off);
+        unsafe { esp_log_level_set(b"*\0".as_ptr() as *const _, esp_log_level_t_ESP_LOG_NONE) };
+    });
+}
+
+fn rx_thread() {
+    let mut buf = [0u8; 128];
+    let mut frame = Vec::new();
+    let mut overflowed = false;
+
+    loop {
+        let read = unsafe {
+            uart_read_bytes(
+                PORT,
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as u32,
+                TickType_t::MAX,
+            )
+        };
+        if read < 0 {
+            error!(read, "Failed to read from UART");
+            continue;
+        }
+
+        for &byte in &buf[..read as usize] {
+            if byte != 0 {
+                if frame.len() < MAX_FRAME_LEN {
+                    frame.push(byte);
+                } else {
+                    overflowed = true;
+                }
+                continue;
+            }
+
+            if overflowed {
+                error!("Dropping serial frame over {} bytes", MAX_FRAME_LEN);
+            } else if !frame.is_empty() {
+                match cobs::decode_vec(&frame) {
+                    // only someone with the watch in hand can send over the
+                    // cable, so it's as good as an encrypted link
+                    Ok(payload) => {
+                        quiet_console();
+                        protocol::receive(SerialTransport::NAME, &payload, true)
+                    }
+                    Err(()) => error!(len = frame.len(), "Dropping malformed serial frame"),
+                }
+            }
+
+            frame.clear();
+            overflowed = false;
+        }
+    }
+}
+
+pub fn init() -> color_eyre::Result<()> {
+    esp!(unsafe {
+        uart_driver_install(
+            PORT,
+            UART_BUF_LEN as i32,
+            UART_BUF_LEN as i32,
+            0,
+            std::ptr::null_mut(),
+            0,
+        )
+    })?;
+
+    RX_THREAD.get_or_init(|| {
+        protocol::register(SerialTransport);
+        std::thread::Builder::new()
+            .stack_size(8192)
+            .spawn(rx_thread)
+            .unwrap()
+    });
+
+    Ok(())
+}
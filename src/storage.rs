@@ -0,0 +1,227 @@
+use std::ffi::CString;
+
+use esp_idf_sys::{
+    esp, esp_err_t, nvs_close, nvs_commit, nvs_erase_key, nvs_flash_erase, nvs_flash_init,
+    nvs_get_blob, nvs_get_u64, nvs_handle_t, nvs_open, nvs_open_mode_t_NVS_READWRITE, nvs_set_blob,
+    nvs_set_u64, EspError, ESP_ERR_NVS_NEW_VERSION_FOUND, ESP_ERR_NVS_NOT_FOUND,
+    ESP_ERR_NVS_NO_FREE_PAGES,
+};
+use once_cell::sync::OnceCell;
+use prost::Message as _;
+
+use crate::message::OutboxConfig;
+use crate::{auth, outbox};
+
+/// Initialises the default NVS partition, wiping it if it's full or was
+/// written by a newer IDF. Safe to call more than once, and every call gets
+/// the result of the first.
+pub fn init() -> color_eyre::Result<()> {
+    static INIT: OnceCell<Result<(), EspError>> = OnceCell::new();
+
+    let result = *INIT.get_or_init(|| unsafe {
+        match esp!(nvs_flash_init()) {
+            Err(err)
+                if err.code() == ESP_ERR_NVS_NO_FREE_PAGES
+                    || err.code() == ESP_ERR_NVS_NEW_VERSION_FOUND =>
+            {
+                esp!(nvs_flash_erase()).and_then(|_| esp!(nvs_flash_init()))
+            }
+            result => result,
+        }
+    });
+
+    Ok(result?)
+}
+
+/// A namespace in the default NVS partition
+pub struct Nvs {
+    handle: nvs_handle_t,
+}
+
+impl Nvs {
+    pub fn open(namespace: &str) -> color_eyre::Result<Self> {
+        init()?;
+
+        let namespace = CString::new(namespace)?;
+        let mut handle = 0;
+        esp!(unsafe {
+            nvs_open(
+                namespace.as_ptr(),
+                nvs_open_mode_t_NVS_READWRITE,
+                &mut handle as *mut _,
+            )
+        })?;
+
+        Ok(Self { handle })
+    }
+
+    pub fn get_blob(&self, key: &str) -> color_eyre::Result<Option<Vec<u8>>> {
+        let key = CString::new(key)?;
+
+        let mut len = 0usize;
+        let rc = unsafe {
+            nvs_get_blob(
+                self.handle,
+                key.as_ptr(),
+                std::ptr::null_mut(),
+                &mut len as *mut _,
+            )
+        };
+        if !found(rc)? {
+            return Ok(None);
+        }
+
+        let mut buf = vec![0u8; len];
+        esp!(unsafe {
+            nvs_get_blob(
+                self.handle,
+                key.as_ptr(),
+                buf.as_mut_ptr() as *mut _,
+                &mut len as *mut _,
+            )
+        })?;
+        buf.truncate(len);
+
+        Ok(Some(buf))
+    }
+
+    pub fn set_blob(&self, key: &str, val: &[u8]) -> color_eyre::Result<()> {
+        let key = CString::new(key)?;
+        esp!(unsafe {
+            nvs_set_blob(
+                self.handle,
+                key.as_ptr(),
+                val.as_ptr() as *const _,
+                val.len(),
+            )
+        })?;
+        esp!(unsafe { nvs_commit(self.handle) })?;
+        Ok(())
+    }
+
+    pub fn get_u64(&self, key: &str) -> color_eyre::Result<Option<u64>> {
+        let key = CString::new(key)?;
+        let mut val = 0u64;
+        let rc = unsafe { nvs_get_u64(self.handle, key.as_ptr(), &mut val as *mut _) };
+        if !found(rc)? {
+            return Ok(None);
+        }
+        Ok(Some(val))
+    }
+
+    pub fn set_u64(&self, key: &str, val: u64) -> color_eyre::Result<()> {
+        let key = CString::new(key)?;
+        esp!(unsafe { nvs_set_u64(self.handle, key.as_ptr(), val) })?;
+        esp!(unsafe { nvs_commit(self.handle) })?;
+        Ok(())
+    }
+
+    /// Removes `key`, doing nothing if it doesn't exist
+    pub fn erase(&self, key: &str) -> color_eyre::Result<()> {
+        let key = CString::new(key)?;
+        let rc = unsafe { nvs_erase_key(self.handle, key.as_ptr()) };
+        if found(rc)? {
+            esp!(unsafe { nvs_commit(self.handle) })?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Nvs {
+    fn drop(&mut self) {
+        unsafe { nvs_close(self.handle) };
+    }
+}
+
+/// Turns the error for a missing key into `Ok(false)`
+fn found(rc: esp_err_t) -> Result<bool, EspError> {
+    if rc == ESP_ERR_NVS_NOT_FOUND {
+        return Ok(false);
+    }
+    esp!(rc)?;
+    Ok(true)
+}
+
+/// Keeps the message key and counter in the `auth` namespace
+pub struct AuthStore;
+
+impl AuthStore {
+    const NAMESPACE: &'static str = "auth";
+    const KEY: &'static str = "key";
+    const COUNTER: &'static str = "counter";
+}
+
+impl auth::Store for AuthStore {
+    fn key(&self) -> color_eyre::Result<Option<Vec<u8>>> {
+        Nvs::open(Self::NAMESPACE)?.get_blob(Self::KEY)
+    }
+
+    fn counter(&self) -> color_eyre::Result<Option<u64>> {
+        Nvs::open(Self::NAMESPACE)?.get_u64(Self::COUNTER)
+    }
+
+    fn set_key(&mut self, key: &[u8]) -> color_eyre::Result<()> {
+        Nvs::open(Self::NAMESPACE)?.set_blob(Self::KEY, key)
+    }
+
+    fn set_counter(&mut self, counter: u64) -> color_eyre::Result<()> {
+        Nvs::open(Self::NAMESPACE)?.set_u64(Self::COUNTER, counter)
+    }
+}
+
+/// Keeps the outbox config and spilled notifications in the `outbox`
+/// namespace
+pub struct OutboxStore(Nvs);
+
+impl OutboxStore {
+    const NAMESPACE: &'static str = "outbox";
+    const CONFIG: &'static str = "config";
+    const HEAD: &'static str = "head";
+    const TAIL: &'static str = "tail";
+
+    pub fn open() -> color_eyre::Result<Self> {
+        Ok(Self(Nvs::open(Self::NAMESPACE)?))
+    }
+
+    fn item_key(index: u32) -> String {
+        format!("n{index}")
+    }
+}
+
+impl outbox::Store for OutboxStore {
+    fn config(&self) -> color_eyre::Result<Option<OutboxConfig>> {
+        match self.0.get_blob(Self::CONFIG)? {
+            Some(buf) => Ok(Some(OutboxConfig::decode(buf.as_slice())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_config(&mut self, config: &OutboxConfig) -> color_eyre::Result<()> {
+        self.0.set_blob(Self::CONFIG, &config.encode_to_vec())
+    }
+
+    fn bounds(&self) -> color_eyre::Result<Option<(u32, u32)>> {
+        let head = self.0.get_u64(Self::HEAD)?;
+        let tail = self.0.get_u64(Self::TAIL)?;
+        Ok(head
+            .zip(tail)
+            .map(|(head, tail)| (head as u32, tail as u32)))
+    }
+
+    fn set_bounds(&mut self, head: u32, tail: u32) -> color_eyre::Result<()> {
+        self.0.set_u64(Self::HEAD, head as u64)?;
+        self.0.set_u64(Self::TAIL, tail as u64)
+    }
+
+    fn item(&self, index: u32) -> color_eyre::Result<Option<Vec<u8>>> {
+        self.0.get_blob(&Self::item_key(index))
+    }
+
+    fn set_item(&mut self, index: u32, item: &[u8]) -> color_eyre::Result<()> {
+        self.0.set_blob(&Self::item_key(index), item)
+    }
+
+    fn erase_item(&mut self, index: u32) -> color_eyre::Result<()> {
+        self.0.erase(&Self::item_key(index))
+    }
+}
@@ -0,0 +1,6 @@
+fn main() -> anyhow::Result<()> {
+    prost_build::compile_protos(&["src/messages.proto"], &["src/"])?;
+    println!("cargo:rerun-if-changed=src/messages.proto");
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! Authenticates messages from the phone.
+//!
+//! The phone sends a 32 byte key in a `ProvisionKey` message over the
+//! encrypted link once it has bonded, and the watch keeps it in the [`Store`]
+//! given to [`use_store`], which on the watch is NVS. Every
+//! other message must then come in a `SignedMessage`, with a HMAC-SHA256 of
+//! its counter and the encoded message exactly as sent, and a counter larger
+//! than that of the last accepted message, so captured messages can't be
+//! replayed. The MAC never depends on how the message would be re-encoded.
+
+use std::sync::Mutex;
+
+use color_eyre::eyre::{ensure, eyre};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{error, info};
+
+use crate::message::{ErrorCode, Rejected, SignedMessage};
+
+pub const KEY_LEN: usize = 32;
+
+/// How far past an accepted counter is written to the store at once, so flash only
+/// wears once per window rather than on every message. After a restart
+/// everything up to the end of the last window counts as used, so the phone has
+/// to skip ahead when it gets [`ErrorCode::Replayed`].
+const COUNTER_WINDOW: u64 = 1 << 16;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Where the key and the replay counter are kept
+pub trait Store: Send {
+    fn key(&self) -> color_eyre::Result<Option<Vec<u8>>>;
+
+    fn counter(&self) -> color_eyre::Result<Option<u64>>;
+
+    fn set_key(&mut self, key: &[u8]) -> color_eyre::Result<()>;
+
+    fn set_counter(&mut self, counter: u64) -> color_eyre::Result<()>;
+}
+
+/// Keeps everything in RAM, for tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    pub key: Option<Vec<u8>>,
+    pub counter: Option<u64>,
+}
+
+#[cfg(test)]
+impl Store for MemoryStore {
+    fn key(&self) -> color_eyre::Result<Option<Vec<u8>>> {
+        Ok(self.key.clone())
+    }
+
+    fn counter(&self) -> color_eyre::Result<Option<u64>> {
+        Ok(self.counter)
+    }
+
+    fn set_key(&mut self, key: &[u8]) -> color_eyre::Result<()> {
+        self.key = Some(key.to_vec());
+        Ok(())
+    }
+
+    fn set_counter(&mut self, counter: u64) -> color_eyre::Result<()> {
+        self.counter = Some(counter);
+        Ok(())
+    }
+}
+
+struct State {
+    store: Box<dyn Store>,
+    key: Option<Vec<u8>>,
+    last_counter: u64,
+    /// The counter in the store, which nothing may reuse
+    reserved: u64,
+}
+
+impl State {
+    fn load(store: Box<dyn Store>) -> Self {
+        let loaded = store
+            .key()
+            .and_then(|key| Ok((key, store.counter()?.unwrap_or(0))));
+        let (key, reserved) = loaded.unwrap_or_else(|err| {
+            error!(?err, "Failed to load auth state, treating as unprovisioned");
+            (None, 0)
+        });
+
+        Self {
+            store,
+            key,
+            last_counter: reserved,
+            reserved,
+        }
+    }
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+fn with_state<T>(f: impl FnOnce(&mut State) -> T) -> T {
+    let mut state = STATE.lock().unwrap();
+    f(state
+        .as_mut()
+        .expect("auth::use_store must be called before any message arrives"))
+}
+
+/// Keeps the key and counter in `store` from now on, loading whatever it
+/// already has
+pub fn use_store(store: impl Store + 'static) {
+    *STATE.lock().unwrap() = Some(State::load(Box::new(store)));
+}
+
+/// Checks the MAC and counter of `signed`. Returns `Ok(false)` if no key has
+/// been provisioned yet, so there's nothing to check it against.
+#[cfg_attr(feature = "legacy-origin", allow(dead_code))]
+pub fn verify(signed: &SignedMessage) -> Result<bool, Rejected> {
+    with_state(|state| {
+        let key = match &state.key {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+        mac.update(&signed.counter.to_le_bytes());
+        mac.update(&signed.payload);
+        mac.verify_slice(&signed.mac)
+            .map_err(|_| Rejected::new(0, ErrorCode::Unauthenticated, eyre!("Bad MAC")))?;
+
+        if signed.counter <= state.last_counter {
+            return Err(Rejected::new(
+                0,
+                ErrorCode::Replayed,
+                eyre!(
+                    "Counter {} is not after the last accepted counter {}",
+                    signed.counter,
+                    state.last_counter
+                ),
+            ));
+        }
+
+        state.last_counter = signed.counter;
+        if signed.counter > state.reserved {
+            let reserved = signed.counter.saturating_add(COUNTER_WINDOW);
+            match state.store.set_counter(reserved) {
+                Ok(()) => state.reserved = reserved,
+                Err(err) => error!(?err, "Failed to persist message counter"),
+            }
+        }
+
+        Ok(true)
+    })
+}
+
+/// Replaces the key, restarting the counter from zero.
+pub fn provision(key: &[u8]) -> color_eyre::Result<()> {
+    ensure!(
+        key.len() == KEY_LEN,
+        "Keys must be {} bytes, got {}",
+        KEY_LEN,
+        key.len()
+    );
+
+    with_state(|state| {
+        state.store.set_key(key)?;
+        state.store.set_counter(0)?;
+
+        state.key = Some(key.to_vec());
+        state.last_counter = 0;
+        state.reserved = 0;
+
+        info!("Provisioned a new message key");
+
+        Ok(())
+    })
+}
@@ -0,0 +1,214 @@
+//! Parses the characteristics of the Bluetooth Current Time Service, which
+//! phones expose so accessories can keep their clocks right.
+
+use color_eyre::eyre::{ensure, eyre};
+
+/// Current Time, an Exact Time 256 followed by the reason for any adjustment
+pub const CURRENT_TIME_LEN: usize = 10;
+pub const LOCAL_TIME_INFO_LEN: usize = 2;
+
+/// The time zone value the spec uses for "unknown"
+const TIME_ZONE_UNKNOWN: i8 = -128;
+/// The DST offset value the spec uses for "unknown"
+const DST_OFFSET_UNKNOWN: u8 = 255;
+
+/// The phone's wall clock time, with no idea of what time zone it's in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    /// In 1/256ths of a second
+    pub fractions256: u8,
+}
+
+pub fn parse_current_time(buf: &[u8]) -> color_eyre::Result<LocalTime> {
+    ensure!(
+        buf.len() >= CURRENT_TIME_LEN,
+        "Current Time is {} bytes, expected {}",
+        buf.len(),
+        CURRENT_TIME_LEN
+    );
+
+    let time = LocalTime {
+        year: u16::from_le_bytes([buf[0], buf[1]]),
+        month: buf[2],
+        day: buf[3],
+        hours: buf[4],
+        minutes: buf[5],
+        seconds: buf[6],
+        // buf[7] is the day of the week, which we can work out ourselves
+        fractions256: buf[8],
+    };
+
+    // a zero year, month or day means the phone doesn't know
+    ensure!(
+        time.year >= 1582
+            && (1..=12).contains(&time.month)
+            && (1..=31).contains(&time.day)
+            && time.hours < 24
+            && time.minutes < 60
+            && time.seconds < 60,
+        "Current Time {:?} is unknown or out of range",
+        time
+    );
+
+    Ok(time)
+}
+
+/// How many seconds the phone's local time is ahead of UTC, if it knows
+pub fn parse_local_time_info(buf: &[u8]) -> color_eyre::Result<Option<i64>> {
+    ensure!(
+        buf.len() >= LOCAL_TIME_INFO_LEN,
+        "Local Time Information is {} bytes, expected {}",
+        buf.len(),
+        LOCAL_TIME_INFO_LEN
+    );
+
+    // both are in units of 15 minutes
+    let time_zone = buf[0] as i8;
+    let dst_offset = buf[1];
+    if time_zone == TIME_ZONE_UNKNOWN {
+        return Ok(None);
+    }
+
+    let dst_offset = if dst_offset == DST_OFFSET_UNKNOWN {
+        0
+    } else {
+        dst_offset as i64
+    };
+
+    Ok(Some((time_zone as i64 + dst_offset) * 15 * 60))
+}
+
+impl LocalTime {
+    /// Seconds and nanoseconds since the Unix epoch, given how far ahead of
+    /// UTC the phone's clock is
+    pub fn to_unix(self, utc_offset: i64) -> color_eyre::Result<(i64, u32)> {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64)
+            .ok_or_else(|| eyre!("{:?} isn't a real date", self))?;
+        let seconds = days * 86400
+            + self.hours as i64 * 3600
+            + self.minutes as i64 * 60
+            + self.seconds as i64
+            - utc_offset;
+        let nanos = (self.fractions256 as u64 * 1_000_000_000 / 256) as u32;
+
+        Ok((seconds, nanos))
+    }
+}
+
+/// Days between the Unix epoch and a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> Option<i64> {
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_len = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if day > month_len {
+        return None;
+    }
+
+    // from http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    Some(era * 146097 + day_of_era - 719468)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_days_from_the_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), Some(0));
+        assert_eq!(days_from_civil(1969, 12, 31), Some(-1));
+        assert_eq!(days_from_civil(2000, 3, 1), Some(11017));
+        assert_eq!(days_from_civil(2024, 2, 29), Some(19782));
+        assert_eq!(days_from_civil(1600, 1, 1), Some(-135140));
+    }
+
+    #[test]
+    fn rejects_days_past_the_end_of_the_month() {
+        assert_eq!(days_from_civil(2023, 2, 29), None);
+        assert_eq!(days_from_civil(1900, 2, 29), None);
+        assert_eq!(days_from_civil(2024, 4, 31), None);
+    }
+
+    #[test]
+    fn parses_current_time() {
+        // 2024-03-31 01:30:00 and a half, a Sunday, no adjustment
+        let time = parse_current_time(&[0xe8, 0x07, 3, 31, 1, 30, 0, 7, 128, 0]).unwrap();
+        assert_eq!(
+            time,
+            LocalTime {
+                year: 2024,
+                month: 3,
+                day: 31,
+                hours: 1,
+                minutes: 30,
+                seconds: 0,
+                fractions256: 128,
+            }
+        );
+        assert_eq!(time.to_unix(0).unwrap(), (1711848600, 500_000_000));
+    }
+
+    #[test]
+    fn rejects_short_or_unknown_current_time() {
+        assert!(parse_current_time(&[0xe8, 0x07, 3, 31, 1, 30, 0, 7, 128]).is_err());
+        assert!(parse_current_time(&[]).is_err());
+        // zero year means unknown
+        assert!(parse_current_time(&[0, 0, 3, 31, 1, 30, 0, 7, 0, 0]).is_err());
+        assert!(parse_current_time(&[0xe8, 0x07, 13, 1, 0, 0, 0, 1, 0, 0]).is_err());
+        assert!(parse_current_time(&[0xe8, 0x07, 3, 31, 24, 0, 0, 7, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn rejects_dates_that_dont_exist() {
+        let time = parse_current_time(&[0xe7, 0x07, 2, 29, 0, 0, 0, 3, 0, 0]).unwrap();
+        assert!(time.to_unix(0).is_err());
+    }
+
+    #[test]
+    fn parses_local_time_info() {
+        // UTC+1 with an hour of DST
+        assert_eq!(parse_local_time_info(&[4, 4]).unwrap(), Some(7200));
+        // UTC-5, no DST
+        assert_eq!(parse_local_time_info(&[0xec, 0]).unwrap(), Some(-18000));
+        // UTC+5:30
+        assert_eq!(parse_local_time_info(&[22, 0]).unwrap(), Some(19800));
+        // unknown DST counts as none
+        assert_eq!(parse_local_time_info(&[8, 0xff]).unwrap(), Some(7200));
+        // unknown time zone
+        assert_eq!(parse_local_time_info(&[0x80, 0]).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_short_local_time_info() {
+        assert!(parse_local_time_info(&[4]).is_err());
+        assert!(parse_local_time_info(&[]).is_err());
+    }
+
+    #[test]
+    fn applies_the_offset() {
+        // 01:30 in Paris, UTC+1 before the clocks go forward
+        let time = parse_current_time(&[0xe8, 0x07, 3, 31, 1, 30, 0, 7, 0, 0]).unwrap();
+        let utc_offset = parse_local_time_info(&[4, 0]).unwrap().unwrap();
+        assert_eq!(time.to_unix(utc_offset).unwrap(), (1711845000, 0));
+
+        // 00:59:59 in New York, UTC-5 with an hour of DST
+        let time = parse_current_time(&[0xe7, 0x07, 10, 29, 0, 59, 59, 7, 0, 0]).unwrap();
+        let utc_offset = parse_local_time_info(&[0xec, 4]).unwrap().unwrap();
+        assert_eq!(time.to_unix(utc_offset).unwrap(), (1698555599, 0));
+    }
+}
@@ -0,0 +1,303 @@
+//! Getting notifications to the phone when NimBLE is short on buffers.
+//!
+//! NimBLE only has a dozen mbufs to go around, and a notification that can't
+//! get one is refused rather than queued. Sends are tried again with backoff
+//! before giving up and leaving the notification in the outbox. While there's a
+//! backlog, queued notifications that fit in one write together go as a
+//! [`NotificationBatch`], and anything the phone must not miss can go as an
+//! indication, which the phone has to confirm.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+use prost::Message as _;
+
+use crate::message::{notification, Notification, NotificationBatch, TxDiagnostics};
+
+/// Tries after the first before a send is given up on, about 600ms in all
+pub const MAX_RETRIES: u32 = 6;
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// How long the phone has to confirm an indication, a little over the ATT
+/// transaction timeout
+pub const INDICATION_TIMEOUT: Duration = Duration::from_secs(32);
+
+/// How long a notification that couldn't be sent waits in the outbox before
+/// it's tried again, if nothing else comes along first
+pub const HELD_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait before retry number `attempt`, counting from 0
+pub fn backoff(attempt: u32) -> Duration {
+    FIRST_RETRY_DELAY * (1 << attempt.min(MAX_RETRIES))
+}
+
+/// Whether losing `notif` would leave the phone out of step with the watch:
+/// the user's answer to a notification, or the reply to a request
+pub fn must_deliver(notif: &Notification) -> bool {
+    matches!(
+        notif.body,
+        Some(notification::Body::NotificationAction(_))
+            | Some(notification::Body::Ack(_))
+            | Some(notification::Body::Error(_))
+    )
+}
+
+/// What goes out in the next write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Batch {
+    pub payload: Vec<u8>,
+    /// How many notifications from the front of the outbox are in it
+    pub count: usize,
+    pub must_deliver: bool,
+}
+
+/// Batches up as many of `items`, oldest first, as fit in `max_len` bytes.
+/// The first item goes out by itself, fragmented if need be, when nothing else
+/// fits with it.
+pub fn coalesce(mut items: impl Iterator<Item = Vec<u8>>, max_len: usize) -> Option<Batch> {
+    let first = items.next()?;
+    let decoded = match Notification::decode(first.as_slice()) {
+        Ok(notif) => notif,
+        Err(_) => {
+            return Some(Batch {
+                payload: first,
+                count: 1,
+                must_deliver: false,
+            })
+        }
+    };
+
+    let mut must_deliver = self::must_deliver(&decoded);
+    let mut notifications = vec![decoded];
+    let mut inner_len = prost::encoding::message::encoded_len(1, &notifications[0]);
+
+    for item in items {
+        let notif = match Notification::decode(item.as_slice()) {
+            Ok(notif) => notif,
+            Err(_) => break,
+        };
+
+        let len = inner_len + prost::encoding::message::encoded_len(1, &notif);
+        let batch_len =
+            prost::encoding::key_len(8) + prost::encoding::encoded_len_varint(len as u64) + len;
+        if batch_len > max_len {
+            break;
+        }
+
+        must_deliver |= self::must_deliver(&notif);
+        notifications.push(notif);
+        inner_len = len;
+    }
+
+    if notifications.len() == 1 {
+        return Some(Batch {
+            payload: first,
+            count: 1,
+            must_deliver,
+        });
+    }
+
+    let count = notifications.len();
+    let batch = Notification {
+        body: Some(notification::Body::Batch(NotificationBatch {
+            notifications,
+        })),
+    };
+
+    Some(Batch {
+        payload: batch.encode_to_vec(),
+        count,
+        must_deliver,
+    })
+}
+
+static SENT: AtomicU64 = AtomicU64::new(0);
+static RETRIED: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+static INDICATED: AtomicU64 = AtomicU64::new(0);
+static COALESCED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static EVICTED: AtomicU64 = AtomicU64::new(0);
+static PENDING: AtomicU32 = AtomicU32::new(0);
+
+pub fn sent() {
+    SENT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn retried() {
+    RETRIED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn failed() {
+    FAILED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn indicated() {
+    INDICATED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn coalesced(count: usize) {
+    COALESCED.fetch_add(count as u64, Ordering::Relaxed);
+}
+
+pub fn dropped() {
+    DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Keeps the outbox's numbers for [`diagnostics`]
+pub fn set_outbox(pending: usize, evicted: u64) {
+    PENDING.store(pending as u32, Ordering::Relaxed);
+    EVICTED.store(evicted, Ordering::Relaxed);
+}
+
+pub fn diagnostics() -> TxDiagnostics {
+    TxDiagnostics {
+        sent: SENT.load(Ordering::Relaxed),
+        retried: RETRIED.load(Ordering::Relaxed),
+        failed: FAILED.load(Ordering::Relaxed),
+        indicated: INDICATED.load(Ordering::Relaxed),
+        coalesced: COALESCED.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        evicted: EVICTED.load(Ordering::Relaxed),
+        pending: PENDING.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::FIRST_HEADER_LEN;
+    use crate::message::PinRead;
+
+    /// What a phone negotiating a 185 byte MTU gets in each notification
+    const MTU: usize = 182;
+
+    fn ack(request_id: u32) -> Vec<u8> {
+        Notification::ack(request_id).encode_to_vec()
+    }
+
+    /// A reply the phone can do without
+    fn pin_read(request_id: u32) -> Vec<u8> {
+        Notification {
+            body: Some(notification::Body::PinRead(PinRead {
+                request_id,
+                ..Default::default()
+            })),
+        }
+        .encode_to_vec()
+    }
+
+    /// How long `items` are batched up together
+    fn batch_len(items: &[Vec<u8>]) -> usize {
+        let notifications = items
+            .iter()
+            .map(|item| Notification::decode(item.as_slice()).unwrap())
+            .collect();
+        Notification {
+            body: Some(notification::Body::Batch(NotificationBatch {
+                notifications,
+            })),
+        }
+        .encoded_len()
+    }
+
+    #[test]
+    fn backoff_doubles_until_the_last_retry() {
+        let delays: Vec<_> = (0..=MAX_RETRIES + 1).map(backoff).collect();
+        assert_eq!(
+            delays,
+            [10, 20, 40, 80, 160, 320, 640, 640].map(Duration::from_millis)
+        );
+        assert_eq!(
+            (0..MAX_RETRIES).map(backoff).sum::<Duration>(),
+            Duration::from_millis(630)
+        );
+    }
+
+    #[test]
+    fn nothing_to_batch() {
+        assert_eq!(coalesce(std::iter::empty(), MTU), None);
+    }
+
+    #[test]
+    fn one_item_goes_as_it_is() {
+        let batch = coalesce([ack(1)].into_iter(), MTU).unwrap();
+        assert_eq!(batch.payload, ack(1));
+        assert_eq!(batch.count, 1);
+    }
+
+    #[test]
+    fn batches_what_fits_after_the_header() {
+        let items = vec![ack(1), ack(2), ack(3)];
+        let max_len = MTU - FIRST_HEADER_LEN;
+        let batch = coalesce(items.clone().into_iter(), max_len).unwrap();
+        assert_eq!(batch.count, 3);
+        assert_eq!(batch.payload.len(), batch_len(&items));
+        assert!(batch.payload.len() + FIRST_HEADER_LEN <= MTU);
+
+        let decoded = Notification::decode(batch.payload.as_slice()).unwrap();
+        match decoded.body {
+            Some(notification::Body::Batch(batch)) => {
+                assert_eq!(batch.notifications, [1, 2, 3].map(Notification::ack))
+            }
+            body => panic!("Expected a batch, got {:?}", body),
+        }
+    }
+
+    #[test]
+    fn stops_at_the_boundary() {
+        let items = vec![ack(1), ack(2), ack(3)];
+        let two = batch_len(&items[..2]);
+
+        // exactly enough room for two
+        let batch = coalesce(items.clone().into_iter(), two).unwrap();
+        assert_eq!(batch.count, 2);
+        assert_eq!(batch.payload.len(), two);
+
+        // a byte short, so the first goes by itself
+        let batch = coalesce(items.into_iter(), two - 1).unwrap();
+        assert_eq!(batch.count, 1);
+        assert_eq!(batch.payload, ack(1));
+    }
+
+    #[test]
+    fn oversized_first_items_go_alone() {
+        let batch = coalesce([ack(1), ack(2)].into_iter(), 1).unwrap();
+        assert_eq!(batch.count, 1);
+        assert_eq!(batch.payload, ack(1));
+    }
+
+    #[test]
+    fn stops_at_undecodable_items() {
+        let batch = coalesce([ack(1), vec![0xff], ack(2)].into_iter(), MTU).unwrap();
+        assert_eq!(batch.count, 1);
+        assert_eq!(batch.payload, ack(1));
+
+        let batch = coalesce([vec![0xff], ack(1)].into_iter(), MTU).unwrap();
+        assert_eq!(batch.count, 1);
+        assert_eq!(batch.payload, [0xff]);
+        assert!(!batch.must_deliver);
+    }
+
+    #[test]
+    fn must_deliver_if_anything_in_it_must() {
+        let batch = coalesce([pin_read(1), pin_read(2)].into_iter(), MTU).unwrap();
+        assert_eq!(batch.count, 2);
+        assert!(!batch.must_deliver);
+
+        let batch = coalesce([pin_read(1), ack(2)].into_iter(), MTU).unwrap();
+        assert_eq!(batch.count, 2);
+        assert!(batch.must_deliver);
+
+        let batch = coalesce([ack(1)].into_iter(), MTU).unwrap();
+        assert!(batch.must_deliver);
+    }
+
+    #[test]
+    fn must_deliver_ignores_what_was_left_out() {
+        let items = vec![pin_read(1), ack(2)];
+        let batch = coalesce(items.into_iter(), batch_len(&[pin_read(1)])).unwrap();
+        assert_eq!(batch.count, 1);
+        assert!(!batch.must_deliver);
+    }
+}
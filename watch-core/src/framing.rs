@@ -0,0 +1,438 @@
+//! Splits payloads that don't fit into a single GATT write or notification
+//! into fragments, and glues them back together on the other end.
+//!
+//! Every fragment starts with a three byte header: the sequence number of the
+//! payload it belongs to, followed by the little endian `u16` index of the
+//! fragment. The first fragment (index 0) additionally carries the little
+//! endian `u32` length of the whole payload and its CRC-32.
+//!
+//! ```text
+//! index 0: | seq | 0 | 0 | len (4) | crc (4) | data ... |
+//! index n: | seq | n (2) | data ... |
+//! ```
+
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{ensure, eyre};
+use tracing::warn;
+
+pub const HEADER_LEN: usize = 3;
+pub const FIRST_HEADER_LEN: usize = HEADER_LEN + 8;
+
+/// Anything that can carry fragments of at most [`Link::max_fragment_len`]
+/// bytes, such as a GATT characteristic.
+pub trait Link {
+    fn max_fragment_len(&self) -> usize;
+
+    fn send_fragment(&mut self, fragment: &[u8]) -> color_eyre::Result<()>;
+}
+
+/// Fragments `payload` and sends each fragment over `link` in order.
+pub fn send<L: Link>(
+    link: &mut L,
+    fragmenter: &mut Fragmenter,
+    payload: &[u8],
+) -> color_eyre::Result<()> {
+    for fragment in fragmenter.fragment(payload, link.max_fragment_len())? {
+        link.send_fragment(&fragment)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct Fragmenter {
+    seq: u8,
+}
+
+impl Fragmenter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn fragment(
+        &mut self,
+        payload: &[u8],
+        max_fragment_len: usize,
+    ) -> color_eyre::Result<Vec<Vec<u8>>> {
+        ensure!(
+            max_fragment_len > FIRST_HEADER_LEN,
+            "Fragments of {} bytes can't carry any data",
+            max_fragment_len
+        );
+
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+
+        let first_len = (max_fragment_len - FIRST_HEADER_LEN).min(payload.len());
+        let (first, rest) = payload.split_at(first_len);
+        let rest = rest.chunks(max_fragment_len - HEADER_LEN);
+
+        ensure!(
+            rest.len() < u16::MAX as usize,
+            "Payload of {} bytes needs too many fragments",
+            payload.len()
+        );
+
+        let mut fragments = Vec::with_capacity(1 + rest.len());
+
+        let mut fragment = Vec::with_capacity(FIRST_HEADER_LEN + first.len());
+        fragment.push(seq);
+        fragment.extend_from_slice(&0u16.to_le_bytes());
+        fragment.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        fragment.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+        fragment.extend_from_slice(first);
+        fragments.push(fragment);
+
+        for (index, chunk) in rest.enumerate() {
+            let mut fragment = Vec::with_capacity(HEADER_LEN + chunk.len());
+            fragment.push(seq);
+            fragment.extend_from_slice(&(index as u16 + 1).to_le_bytes());
+            fragment.extend_from_slice(chunk);
+            fragments.push(fragment);
+        }
+
+        Ok(fragments)
+    }
+}
+
+struct Partial {
+    seq: u8,
+    next_index: u16,
+    len: usize,
+    crc: u32,
+    buf: Vec<u8>,
+    started: Instant,
+}
+
+pub struct Reassembler {
+    max_len: usize,
+    timeout: Duration,
+    partial: Option<Partial>,
+}
+
+impl Reassembler {
+    pub fn new(max_len: usize, timeout: Duration) -> Self {
+        Self {
+            max_len,
+            timeout,
+            partial: None,
+        }
+    }
+
+    /// Feeds in a fragment, returning the payload once the last fragment of it
+    /// has arrived. Fragments of the current payload that already arrived are
+    /// ignored, so a retried write doesn't cost the whole payload.
+    ///
+    /// Any other error discards the payload currently being reassembled.
+    pub fn push(&mut self, fragment: &[u8], now: Instant) -> color_eyre::Result<Option<Vec<u8>>> {
+        if let Some(partial) = &self.partial {
+            if now.duration_since(partial.started) > self.timeout {
+                warn!(seq = partial.seq, "Timed out reassembling payload");
+                self.partial = None;
+            }
+        }
+
+        let result = self.push_inner(fragment, now);
+        if result.is_err() {
+            self.partial = None;
+        }
+
+        result
+    }
+
+    fn push_inner(&mut self, fragment: &[u8], now: Instant) -> color_eyre::Result<Option<Vec<u8>>> {
+        ensure!(fragment.len() >= HEADER_LEN, "Fragment is too short");
+
+        let seq = fragment[0];
+        let index = u16::from_le_bytes([fragment[1], fragment[2]]);
+
+        if let Some(partial) = &self.partial {
+            if partial.seq == seq && index != 0 && index < partial.next_index {
+                warn!(seq, index, "Ignoring duplicate fragment");
+                return Ok(None);
+            }
+        }
+
+        if index == 0 {
+            ensure!(
+                fragment.len() >= FIRST_HEADER_LEN,
+                "First fragment is too short"
+            );
+
+            let len = u32::from_le_bytes(fragment[3..7].try_into().unwrap()) as usize;
+            let crc = u32::from_le_bytes(fragment[7..11].try_into().unwrap());
+
+            if let Some(partial) = &self.partial {
+                // the sequence number wraps after 256 payloads, so it takes
+                // the length and CRC matching too to be sure it's the same
+                // payload starting over rather than a new one
+                if partial.seq == seq && partial.len == len && partial.crc == crc {
+                    warn!(seq, index, "Ignoring duplicate fragment");
+                    return Ok(None);
+                }
+                warn!(seq = partial.seq, "Discarding incomplete payload");
+            }
+
+            ensure!(
+                len <= self.max_len,
+                "Payload of {} bytes is larger than the limit of {}",
+                len,
+                self.max_len
+            );
+
+            self.partial = Some(Partial {
+                seq,
+                next_index: 1,
+                len,
+                crc,
+                buf: Vec::with_capacity(len),
+                started: now,
+            });
+
+            return self.append(&fragment[FIRST_HEADER_LEN..]);
+        }
+
+        let partial = self
+            .partial
+            .as_mut()
+            .ok_or_else(|| eyre!("Got fragment {} of {} without a start", index, seq))?;
+
+        ensure!(
+            partial.seq == seq && partial.next_index == index,
+            "Expected fragment {} of {}, got {} of {}",
+            partial.next_index,
+            partial.seq,
+            index,
+            seq
+        );
+
+        partial.next_index = partial.next_index.wrapping_add(1);
+
+        self.append(&fragment[HEADER_LEN..])
+    }
+
+    fn append(&mut self, data: &[u8]) -> color_eyre::Result<Option<Vec<u8>>> {
+        let partial = self.partial.as_mut().unwrap();
+
+        ensure!(
+            partial.buf.len() + data.len() <= partial.len,
+            "Payload {} overflowed its length of {}",
+            partial.seq,
+            partial.len
+        );
+
+        partial.buf.extend_from_slice(data);
+
+        if partial.buf.len() < partial.len {
+            return Ok(None);
+        }
+
+        let partial = self.partial.take().unwrap();
+
+        ensure!(
+            crc32fast::hash(&partial.buf) == partial.crc,
+            "CRC mismatch on payload {}",
+            partial.seq
+        );
+
+        Ok(Some(partial.buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MTU: usize = 20;
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Keeps every fragment sent over it
+    struct FakeLink(Vec<Vec<u8>>);
+
+    impl Link for FakeLink {
+        fn max_fragment_len(&self) -> usize {
+            MTU
+        }
+
+        fn send_fragment(&mut self, fragment: &[u8]) -> color_eyre::Result<()> {
+            assert!(
+                fragment.len() <= MTU,
+                "Fragment of {} bytes",
+                fragment.len()
+            );
+            self.0.push(fragment.to_vec());
+            Ok(())
+        }
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn fragments(payload: &[u8]) -> Vec<Vec<u8>> {
+        let mut link = FakeLink(Vec::new());
+        send(&mut link, &mut Fragmenter::new(), payload).unwrap();
+        link.0
+    }
+
+    fn reassembler() -> Reassembler {
+        Reassembler::new(1024, TIMEOUT)
+    }
+
+    /// Pushes `fragments` in order, returning what the last one completed
+    fn try_reassemble(
+        reassembler: &mut Reassembler,
+        fragments: &[Vec<u8>],
+    ) -> color_eyre::Result<Option<Vec<u8>>> {
+        let now = Instant::now();
+        let (last, rest) = fragments.split_last().unwrap();
+        for fragment in rest {
+            assert_eq!(reassembler.push(fragment, now).unwrap(), None);
+        }
+        reassembler.push(last, now)
+    }
+
+    fn reassemble(reassembler: &mut Reassembler, fragments: &[Vec<u8>]) -> Option<Vec<u8>> {
+        try_reassemble(reassembler, fragments).unwrap()
+    }
+
+    #[test]
+    fn splits_at_the_mtu() {
+        // 9 bytes in the first fragment, then 17 in each after it
+        let payload = payload(9 + 17 * 2 + 1);
+        let fragments = fragments(&payload);
+
+        let lens: Vec<_> = fragments.iter().map(Vec::len).collect();
+        assert_eq!(lens, [MTU, MTU, MTU, HEADER_LEN + 1]);
+        assert_eq!(&fragments[3][..HEADER_LEN], &[0, 3, 0]);
+
+        assert_eq!(reassemble(&mut reassembler(), &fragments), Some(payload));
+    }
+
+    #[test]
+    fn small_payloads_fit_in_one_fragment() {
+        let fragments = fragments(&[1, 2, 3]);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(
+            reassemble(&mut reassembler(), &fragments),
+            Some(vec![1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn ignores_duplicate_fragments() {
+        let payload = payload(60);
+        let fragments = fragments(&payload);
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+
+        assert_eq!(reassembler.push(&fragments[0], now).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[0], now).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[1], now).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[1], now).unwrap(), None);
+        assert_eq!(reassemble(&mut reassembler, &fragments[2..]), Some(payload));
+    }
+
+    #[test]
+    fn new_payloads_reusing_a_seq_start_afresh() {
+        let mut fragmenter = Fragmenter::new();
+        let abandoned = fragmenter.fragment(&payload(60), MTU).unwrap();
+        for _ in 1..=u8::MAX {
+            fragmenter.fragment(&[], MTU).unwrap();
+        }
+        let payload = vec![0xaa; 60];
+        let wrapped = fragmenter.fragment(&payload, MTU).unwrap();
+        assert_eq!(wrapped[0][0], abandoned[0][0]);
+
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+        assert_eq!(reassembler.push(&abandoned[0], now).unwrap(), None);
+        assert_eq!(reassembler.push(&abandoned[1], now).unwrap(), None);
+        assert_eq!(reassemble(&mut reassembler, &wrapped), Some(payload));
+    }
+
+    #[test]
+    fn stale_fragments_after_wrapping_go_nowhere() {
+        let mut fragmenter = Fragmenter::new();
+        let mut reassembler = reassembler();
+        let first = fragmenter.fragment(&payload(60), MTU).unwrap();
+        assert_eq!(reassemble(&mut reassembler, &first), Some(payload(60)));
+        for i in 1..=u8::MAX {
+            let fragments = fragmenter.fragment(&[i; 30], MTU).unwrap();
+            assert_eq!(reassemble(&mut reassembler, &fragments), Some(vec![i; 30]));
+        }
+
+        // a late retry of the first payload's tail, with the same seq as the
+        // next payload, isn't the start of anything
+        assert!(reassembler.push(&first[1], Instant::now()).is_err());
+        let next = fragmenter.fragment(&[1, 2, 3], MTU).unwrap();
+        assert_eq!(next[0][0], first[0][0]);
+        assert_eq!(reassemble(&mut reassembler, &next), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn rejects_out_of_order_fragments() {
+        let payload = payload(60);
+        let fragments = fragments(&payload);
+        let mut reassembler = reassembler();
+        let now = Instant::now();
+
+        assert_eq!(reassembler.push(&fragments[0], now).unwrap(), None);
+        assert!(reassembler.push(&fragments[2], now).is_err());
+        // the payload was dropped, so the rest of it goes nowhere
+        assert!(reassembler.push(&fragments[1], now).is_err());
+
+        // and the next one starts afresh
+        assert_eq!(reassemble(&mut reassembler, &fragments), Some(payload));
+    }
+
+    #[test]
+    fn rejects_crc_mismatch() {
+        let mut fragments = fragments(&payload(30));
+        *fragments[1].last_mut().unwrap() ^= 0xff;
+
+        let err = try_reassemble(&mut reassembler(), &fragments).unwrap_err();
+        assert!(err.to_string().contains("CRC"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_length_mismatch() {
+        let mut fragments = fragments(&payload(30));
+        // claim one byte fewer than is sent
+        fragments[0][3..7].copy_from_slice(&29u32.to_le_bytes());
+
+        let err = try_reassemble(&mut reassembler(), &fragments).unwrap_err();
+        assert!(err.to_string().contains("overflowed"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_payloads_over_the_limit() {
+        let fragments = fragments(&payload(30));
+        let mut reassembler = Reassembler::new(29, TIMEOUT);
+        assert!(reassembler.push(&fragments[0], Instant::now()).is_err());
+    }
+
+    #[test]
+    fn times_out_partial_payloads() {
+        let payload = payload(60);
+        let fragments = fragments(&payload);
+        let mut reassembler = reassembler();
+        let start = Instant::now();
+
+        assert_eq!(reassembler.push(&fragments[0], start).unwrap(), None);
+        let late = start + TIMEOUT + Duration::from_millis(1);
+        assert!(reassembler.push(&fragments[1], late).is_err());
+
+        // still in time, counting from its own first fragment
+        assert_eq!(reassembler.push(&fragments[0], late).unwrap(), None);
+        let now = late + TIMEOUT;
+        for fragment in &fragments[1..fragments.len() - 1] {
+            assert_eq!(reassembler.push(fragment, now).unwrap(), None);
+        }
+        assert_eq!(
+            reassembler.push(fragments.last().unwrap(), now).unwrap(),
+            Some(payload)
+        );
+    }
+}
@@ -0,0 +1,16 @@
+//! The parts of the watch that don't touch the hardware: the protocol spoken
+//! with the phone, the bus that hands messages to their handlers, and how
+//! notifications are held and batched on their way back.
+//!
+//! Everything here builds for the host as well as the watch, so `cargo test`
+//! in this directory runs the tests on Linux. Anything kept in flash goes
+//! through a `Store` trait, which the firmware implements on NVS.
+
+pub mod auth;
+pub mod cts;
+pub mod delivery;
+pub mod framing;
+pub mod message;
+pub mod nus;
+pub mod outbox;
+pub mod protocol;
@@ -0,0 +1,547 @@
+//! The protobuf messages exchanged with the phone, and the bus that hands
+//! inbound messages to whichever threads handle them.
+//!
+//! Handlers [`subscribe`] to the [`Topic`]s they care about, each with its own
+//! queue depth. A handler that falls behind only loses messages of the topic
+//! it's behind on, and every drop is counted in [`stats`], which is reported
+//! in the diagnostics.
+
+// prost nests the `Message` oneof in a module called `message`
+#![allow(clippy::module_inception)]
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crossbeam::channel;
+use once_cell::sync::Lazy;
+
+include!(concat!(env!("OUT_DIR"), "/messages.items.rs"));
+
+include!("protocol_version.rs");
+
+#[derive(Debug)]
+pub struct Rejected {
+    /// The request to answer, or 0 if it was turned away before the message
+    /// could be trusted enough to look inside
+    pub request_id: u32,
+    pub code: ErrorCode,
+    pub reason: color_eyre::Report,
+}
+
+impl Rejected {
+    pub fn new(request_id: u32, code: ErrorCode, reason: color_eyre::Report) -> Self {
+        Self {
+            request_id,
+            code,
+            reason,
+        }
+    }
+}
+
+fn decode<M: prost::Message + Default>(buf: &[u8]) -> Result<M, Rejected> {
+    M::decode(buf).map_err(|err| Rejected::new(0, ErrorCode::DecodeFailure, err.into()))
+}
+
+/// Unwraps a [`SignedMessage`] from the phone, or takes a bare [`Message`] as
+/// the old test rigs send, checking where it came from
+#[cfg(feature = "legacy-origin")]
+pub fn validate_msg(buf: &[u8], _link_encrypted: bool) -> Result<Message, Rejected> {
+    const SECURITY_BY_OBSCURITY: u32 = 3387062;
+
+    // a bare message never decodes as an envelope, its first field is a
+    // varint where the envelope's is bytes
+    let msg: Message = match decode::<SignedMessage>(buf) {
+        Ok(signed) => decode(&signed.payload)?,
+        Err(_) => decode(buf)?,
+    };
+
+    if msg.origin != SECURITY_BY_OBSCURITY {
+        return Err(Rejected::new(
+            msg.request_id,
+            ErrorCode::BadOrigin,
+            color_eyre::eyre::eyre!("Bad origin, should be {}", SECURITY_BY_OBSCURITY),
+        ));
+    }
+
+    Ok(msg)
+}
+
+/// Unwraps a [`SignedMessage`] from the phone, checking its MAC and counter
+/// before the message inside is decoded
+#[cfg(not(feature = "legacy-origin"))]
+pub fn validate_msg(buf: &[u8], link_encrypted: bool) -> Result<Message, Rejected> {
+    let signed: SignedMessage = decode(buf)?;
+    if crate::auth::verify(&signed)? {
+        return decode(&signed.payload);
+    }
+
+    // with no key there's nothing to check, and all we'll take is a key over
+    // an encrypted link
+    let msg: Message = decode(&signed.payload)?;
+    let provisioning = matches!(msg.body, Some(message::Body::ProvisionKey(_)));
+    match (provisioning, link_encrypted) {
+        (true, true) => Ok(msg),
+        (true, false) => Err(Rejected::new(
+            msg.request_id,
+            ErrorCode::Unauthenticated,
+            color_eyre::eyre::eyre!("Keys can only be provisioned over an encrypted link"),
+        )),
+        (false, _) => Err(Rejected::new(
+            msg.request_id,
+            ErrorCode::Unauthenticated,
+            color_eyre::eyre::eyre!("No key has been provisioned"),
+        )),
+    }
+}
+
+/// One per variant of [`message::Body`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Topic {
+    SyncClock,
+    PushNotification,
+    SetPin,
+    ProvisionKey,
+    GetDeviceInfo,
+    ListBonds,
+    DeleteBond,
+    SetAdvertisingConfig,
+    GetDiagnostics,
+    SetRadioConfig,
+    SetOutboxConfig,
+}
+
+impl Topic {
+    pub fn of(body: &message::Body) -> Self {
+        match body {
+            message::Body::SyncClock(_) => Topic::SyncClock,
+            message::Body::PushNotification(_) => Topic::PushNotification,
+            message::Body::SetPin(_) => Topic::SetPin,
+            message::Body::ProvisionKey(_) => Topic::ProvisionKey,
+            message::Body::GetDeviceInfo(_) => Topic::GetDeviceInfo,
+            message::Body::ListBonds(_) => Topic::ListBonds,
+            message::Body::DeleteBond(_) => Topic::DeleteBond,
+            message::Body::SetAdvertisingConfig(_) => Topic::SetAdvertisingConfig,
+            message::Body::GetDiagnostics(_) => Topic::GetDiagnostics,
+            message::Body::SetRadioConfig(_) => Topic::SetRadioConfig,
+            message::Body::SetOutboxConfig(_) => Topic::SetOutboxConfig,
+        }
+    }
+}
+
+struct TopicQueue {
+    topic: Topic,
+    depth: usize,
+    queued: AtomicUsize,
+    high_water: AtomicUsize,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+}
+
+struct Subscription {
+    name: &'static str,
+    queues: Vec<TopicQueue>,
+    tx: channel::Sender<Message>,
+}
+
+impl Subscription {
+    fn queue(&self, topic: Topic) -> Option<&TopicQueue> {
+        self.queues.iter().find(|q| q.topic == topic)
+    }
+}
+
+static SUBSCRIPTIONS: Lazy<Mutex<Vec<Arc<Subscription>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Receiving end of a [`subscribe`], unsubscribes when dropped
+pub struct Subscriber {
+    subscription: Arc<Subscription>,
+    rx: channel::Receiver<Message>,
+}
+
+impl Subscriber {
+    /// Blocks until the next message arrives
+    pub fn recv(&self) -> Option<Message> {
+        let msg = self.rx.recv().ok()?;
+
+        if let Some(queue) = msg
+            .body
+            .as_ref()
+            .and_then(|body| self.subscription.queue(Topic::of(body)))
+        {
+            queue.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        Some(msg)
+    }
+}
+
+impl Iterator for Subscriber {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        self.recv()
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        SUBSCRIPTIONS
+            .lock()
+            .unwrap()
+            .retain(|s| !Arc::ptr_eq(s, &self.subscription));
+    }
+}
+
+/// Registers `name` for each of `topics`, holding at most `depth` unhandled
+/// messages of that topic before dropping new ones.
+pub fn subscribe(name: &'static str, topics: &[(Topic, usize)]) -> Subscriber {
+    let (tx, rx) = channel::unbounded();
+
+    let queues = topics
+        .iter()
+        .map(|&(topic, depth)| TopicQueue {
+            topic,
+            depth,
+            queued: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+            delivered: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        })
+        .collect();
+
+    let subscription = Arc::new(Subscription { name, queues, tx });
+    SUBSCRIPTIONS
+        .lock()
+        .unwrap()
+        .push(Arc::clone(&subscription));
+
+    Subscriber { subscription, rx }
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    Empty,
+    /// Nothing is subscribed to the topic
+    Unhandled(Topic),
+    /// These subscribers had full queues and dropped the message
+    Full {
+        topic: Topic,
+        subscribers: Vec<&'static str>,
+    },
+}
+
+impl std::fmt::Display for PushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushError::Empty => write!(f, "Message has no body"),
+            PushError::Unhandled(topic) => write!(f, "Nothing handles {:?} messages", topic),
+            PushError::Full { topic, subscribers } => {
+                write!(f, "Queues for {:?} are full in {:?}", topic, subscribers)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PushError {}
+
+/// Hands `msg` to every subscriber of its topic that has room for it.
+pub fn push_message(msg: Message) -> Result<(), PushError> {
+    let topic = Topic::of(msg.body.as_ref().ok_or(PushError::Empty)?);
+
+    let subscriptions = SUBSCRIPTIONS.lock().unwrap();
+    let mut handled = false;
+    let mut full = Vec::new();
+
+    for subscription in subscriptions.iter() {
+        let queue = match subscription.queue(topic) {
+            Some(queue) => queue,
+            None => continue,
+        };
+        handled = true;
+
+        // publishers are serialised by the lock, so only the receiver can
+        // change `queued` under us, and it only ever goes down
+        let queued = queue.queued.load(Ordering::Relaxed);
+        if queued >= queue.depth {
+            queue.dropped.fetch_add(1, Ordering::Relaxed);
+            full.push(subscription.name);
+            continue;
+        }
+
+        // count it before sending so the receiver never sees it uncounted
+        queue.queued.fetch_add(1, Ordering::Relaxed);
+        if subscription.tx.send(msg.clone()).is_err() {
+            queue.queued.fetch_sub(1, Ordering::Relaxed);
+            queue.dropped.fetch_add(1, Ordering::Relaxed);
+            full.push(subscription.name);
+            continue;
+        }
+
+        queue.high_water.fetch_max(queued + 1, Ordering::Relaxed);
+        queue.delivered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if !handled {
+        return Err(PushError::Unhandled(topic));
+    }
+
+    if !full.is_empty() {
+        return Err(PushError::Full {
+            topic,
+            subscribers: full,
+        });
+    }
+
+    Ok(())
+}
+
+/// A snapshot of one subscriber's queue for one topic
+#[derive(Debug, Clone)]
+pub struct QueueStats {
+    pub subscriber: &'static str,
+    pub topic: Topic,
+    pub depth: usize,
+    pub queued: usize,
+    /// The most messages that have been waiting at once
+    pub high_water: usize,
+    pub delivered: u64,
+    pub dropped: u64,
+}
+
+impl QueueStats {
+    pub fn to_message(&self) -> BusQueueDiagnostics {
+        BusQueueDiagnostics {
+            subscriber: self.subscriber.to_owned(),
+            topic: format!("{:?}", self.topic),
+            depth: self.depth as u32,
+            queued: self.queued as u32,
+            high_water: self.high_water as u32,
+            delivered: self.delivered,
+            dropped: self.dropped,
+        }
+    }
+}
+
+pub fn stats() -> Vec<QueueStats> {
+    SUBSCRIPTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|subscription| {
+            subscription.queues.iter().map(|queue| QueueStats {
+                subscriber: subscription.name,
+                topic: queue.topic,
+                depth: queue.depth,
+                queued: queue.queued.load(Ordering::Relaxed),
+                high_water: queue.high_water.load(Ordering::Relaxed),
+                delivered: queue.delivered.load(Ordering::Relaxed),
+                dropped: queue.dropped.load(Ordering::Relaxed),
+            })
+        })
+        .collect()
+}
+
+impl Notification {
+    pub fn ack(request_id: u32) -> Self {
+        Self {
+            body: Some(notification::Body::Ack(Ack { request_id })),
+        }
+    }
+
+    pub fn error(request_id: u32, code: ErrorCode, detail: impl Into<String>) -> Self {
+        Self {
+            body: Some(notification::Body::Error(Error {
+                request_id,
+                code: code as i32,
+                detail: detail.into(),
+            })),
+        }
+    }
+
+    /// Ack on success, or an [`ErrorCode::HandlerError`] carrying the error
+    pub fn reply<T, E: std::fmt::Debug>(request_id: u32, result: &Result<T, E>) -> Self {
+        match result {
+            Ok(_) => Self::ack(request_id),
+            Err(err) => Self::error(request_id, ErrorCode::HandlerError, format!("{:?}", err)),
+        }
+    }
+
+    /// The request this notification answers, if it answers one
+    pub fn request_id_mut(&mut self) -> Option<&mut u32> {
+        match &mut self.body {
+            Some(notification::Body::PinRead(read)) => Some(&mut read.request_id),
+            Some(notification::Body::Ack(ack)) => Some(&mut ack.request_id),
+            Some(notification::Body::Error(error)) => Some(&mut error.request_id),
+            Some(notification::Body::DeviceInfo(info)) => Some(&mut info.request_id),
+            Some(notification::Body::Bonds(bonds)) => Some(&mut bonds.request_id),
+            Some(notification::Body::Diagnostics(diag)) => Some(&mut diag.request_id),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the bus is shared with every other test, so each test here keeps to
+    // topics nothing else subscribes to
+
+    fn msg(request_id: u32, body: message::Body) -> Message {
+        Message {
+            request_id,
+            body: Some(body),
+            ..Default::default()
+        }
+    }
+
+    fn stats_of(subscriber: &str, topic: Topic) -> QueueStats {
+        stats()
+            .into_iter()
+            .find(|stats| stats.subscriber == subscriber && stats.topic == topic)
+            .expect("No such queue")
+    }
+
+    #[test]
+    fn only_subscribers_of_a_topic_get_it() {
+        let bonds = subscribe("bus-bonds", &[(Topic::ListBonds, 4)]);
+        let delete = subscribe("bus-delete", &[(Topic::DeleteBond, 4)]);
+
+        push_message(msg(1, message::Body::ListBonds(ListBonds {}))).unwrap();
+
+        assert_eq!(bonds.rx.try_recv().unwrap().request_id, 1);
+        assert!(delete.rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn rejects_messages_nothing_handles() {
+        assert!(matches!(
+            push_message(Message::default()),
+            Err(PushError::Empty)
+        ));
+
+        let body = message::Body::SetAdvertisingConfig(Default::default());
+        assert!(matches!(
+            push_message(msg(2, body)),
+            Err(PushError::Unhandled(Topic::SetAdvertisingConfig))
+        ));
+    }
+
+    #[test]
+    fn counts_drops_when_a_queue_is_full() {
+        let sub = subscribe("bus-full", &[(Topic::SyncClock, 2)]);
+        let sync = |request_id| msg(request_id, message::Body::SyncClock(Default::default()));
+
+        push_message(sync(1)).unwrap();
+        push_message(sync(2)).unwrap();
+        match push_message(sync(3)) {
+            Err(PushError::Full { topic, subscribers }) => {
+                assert_eq!(topic, Topic::SyncClock);
+                assert_eq!(subscribers, ["bus-full"]);
+            }
+            result => panic!("Expected a full queue, got {:?}", result),
+        }
+
+        let stats = stats_of("bus-full", Topic::SyncClock);
+        assert_eq!(
+            (
+                stats.queued,
+                stats.high_water,
+                stats.delivered,
+                stats.dropped
+            ),
+            (2, 2, 2, 1)
+        );
+
+        // handling one makes room for another
+        assert_eq!(sub.recv().unwrap().request_id, 1);
+        assert_eq!(stats_of("bus-full", Topic::SyncClock).queued, 1);
+        push_message(sync(4)).unwrap();
+    }
+
+    #[test]
+    fn depth_is_per_topic() {
+        let _sub = subscribe(
+            "bus-depth",
+            &[(Topic::SetRadioConfig, 1), (Topic::PushNotification, 3)],
+        );
+        let radio = |request_id| {
+            msg(
+                request_id,
+                message::Body::SetRadioConfig(Default::default()),
+            )
+        };
+        let push = |request_id| {
+            msg(
+                request_id,
+                message::Body::PushNotification(Default::default()),
+            )
+        };
+
+        push_message(radio(1)).unwrap();
+        assert!(push_message(radio(2)).is_err());
+
+        for request_id in 3..6 {
+            push_message(push(request_id)).unwrap();
+        }
+        assert!(push_message(push(6)).is_err());
+
+        assert_eq!(stats_of("bus-depth", Topic::SetRadioConfig).dropped, 1);
+        assert_eq!(stats_of("bus-depth", Topic::PushNotification).depth, 3);
+    }
+
+    #[test]
+    fn dropping_a_subscriber_unsubscribes_it() {
+        let sub = subscribe("bus-dropped", &[(Topic::GetDeviceInfo, 1)]);
+        drop(sub);
+
+        assert!(stats()
+            .iter()
+            .all(|stats| stats.subscriber != "bus-dropped"));
+        assert!(matches!(
+            push_message(msg(1, message::Body::GetDeviceInfo(GetDeviceInfo {}))),
+            Err(PushError::Unhandled(Topic::GetDeviceInfo))
+        ));
+    }
+
+    #[cfg(feature = "legacy-origin")]
+    fn from_rig(origin: u32) -> Message {
+        Message {
+            origin,
+            ..msg(5, message::Body::ListBonds(ListBonds {}))
+        }
+    }
+
+    #[cfg(feature = "legacy-origin")]
+    #[test]
+    fn accepts_bare_messages_from_old_rigs() {
+        let msg = from_rig(3387062);
+        let buf = prost::Message::encode_to_vec(&msg);
+
+        assert_eq!(validate_msg(&buf, false).unwrap(), msg);
+    }
+
+    #[cfg(feature = "legacy-origin")]
+    #[test]
+    fn accepts_enveloped_messages_with_the_origin() {
+        let msg = from_rig(3387062);
+        let signed = SignedMessage {
+            payload: prost::Message::encode_to_vec(&msg),
+            ..Default::default()
+        };
+        let buf = prost::Message::encode_to_vec(&signed);
+
+        assert_eq!(validate_msg(&buf, false).unwrap(), msg);
+    }
+
+    #[cfg(feature = "legacy-origin")]
+    #[test]
+    fn rejects_bare_messages_from_elsewhere() {
+        let buf = prost::Message::encode_to_vec(&from_rig(1));
+
+        let rejected = validate_msg(&buf, false).unwrap_err();
+        assert_eq!(
+            (rejected.request_id, rejected.code),
+            (5, ErrorCode::BadOrigin)
+        );
+    }
+}
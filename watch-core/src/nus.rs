@@ -0,0 +1,341 @@
+//! A line based text language spoken over the Nordic UART Service, so the
+//! watch can be poked from generic tools like nRF Connect or a Web Bluetooth
+//! serial terminal without the app.
+//!
+//! Each command is turned into the same [`message::Message`] body the app
+//! would send, and every notification is written back as a line of text.
+
+use std::fmt::Write;
+
+use color_eyre::eyre::{bail, ensure, eyre};
+
+use crate::message::{self, message::Body, notification, Notification};
+
+pub const HELP: &str = "commands: time <unix secs>, notify <text>, \
+                        pin <g26|g25|g0> <high|low|read>, batt, info, diag, help";
+
+/// Longer lines are turned down rather than shown cut short
+pub const MAX_LINE_LEN: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Help,
+    Send(Body),
+}
+
+pub fn parse(line: &str) -> color_eyre::Result<Command> {
+    let line = line.trim();
+    ensure!(
+        line.len() <= MAX_LINE_LEN,
+        "line is {} bytes, over the limit of {}",
+        line.len(),
+        MAX_LINE_LEN
+    );
+    let (cmd, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+
+    let body = match cmd.to_ascii_lowercase().as_str() {
+        "help" | "?" => return Ok(Command::Help),
+        "time" => {
+            let seconds = rest.parse().map_err(|_| eyre!("usage: time <unix secs>"))?;
+            Body::SyncClock(message::SyncClock {
+                timestamp: Some(prost_types::Timestamp { seconds, nanos: 0 }),
+            })
+        }
+        "notify" => {
+            if rest.is_empty() {
+                bail!("usage: notify <text>");
+            }
+            Body::PushNotification(message::PushNotification {
+                body: rest.to_owned(),
+                ..Default::default()
+            })
+        }
+        "pin" => {
+            let (pin, op) = rest
+                .split_once(' ')
+                .ok_or_else(|| eyre!("usage: pin <g26|g25|g0> <high|low|read>"))?;
+            let pin = match pin.to_ascii_lowercase().as_str() {
+                "g26" => message::Pins::G26,
+                "g25" => message::Pins::G25,
+                "g0" => message::Pins::G0,
+                _ => bail!("unknown pin {:?}", pin),
+            };
+            let op = match op.trim().to_ascii_lowercase().as_str() {
+                "high" => message::PinOperation::SetHigh,
+                "low" => message::PinOperation::SetLow,
+                "read" => message::PinOperation::AnalogueRead,
+                _ => bail!("unknown pin operation {:?}", op),
+            };
+            Body::SetPin(message::SetPin {
+                pin: pin as i32,
+                op: op as i32,
+            })
+        }
+        "batt" | "info" => Body::GetDeviceInfo(message::GetDeviceInfo {}),
+        "diag" => Body::GetDiagnostics(message::GetDiagnostics {}),
+        "" => bail!("empty command, try help"),
+        _ => bail!("unknown command {:?}, try help", cmd),
+    };
+
+    Ok(Command::Send(body))
+}
+
+/// A notification as a line of text, without the trailing newline
+pub fn describe(notif: &Notification) -> String {
+    let mut out = String::new();
+
+    match &notif.body {
+        None => out.push_str("empty"),
+        Some(notification::Body::Ack(_)) => out.push_str("ok"),
+        Some(notification::Body::Error(error)) => {
+            write!(out, "error {:?}: {}", error.code(), error.detail).unwrap();
+        }
+        Some(notification::Body::PinRead(read)) => {
+            write!(out, "pin {:?} = {}", read.pin(), read.value).unwrap();
+        }
+        Some(notification::Body::DeviceInfo(info)) => {
+            write!(
+                out,
+                "{} {} ({}), up {}s, batt {}%",
+                info.board,
+                info.crate_version,
+                info.git_hash,
+                info.uptime_ms / 1000,
+                info.battery_percent
+            )
+            .unwrap();
+        }
+        Some(notification::Body::NotificationAction(action)) => {
+            write!(out, "action on {}: {}", action.id, action.label).unwrap();
+        }
+        Some(notification::Body::Bonds(bonds)) => {
+            write!(out, "{} bonds", bonds.bonds.len()).unwrap();
+        }
+        Some(notification::Body::Diagnostics(diag)) => {
+            write!(out, "{} connections", diag.connections.len()).unwrap();
+            for conn in &diag.connections {
+                write!(
+                    out,
+                    ", {}ms/{} mtu {}",
+                    conn.interval_us / 1000,
+                    conn.latency,
+                    conn.mtu
+                )
+                .unwrap();
+            }
+            if let Some(radio) = &diag.radio {
+                write!(
+                    out,
+                    ", radio {} for {}s/{}s, {:.0}h left",
+                    if radio.on { "on" } else { "off" },
+                    radio.on_ms / 1000,
+                    (radio.on_ms + radio.off_ms) / 1000,
+                    radio.hours_left
+                )
+                .unwrap();
+            }
+        }
+        Some(notification::Body::Batch(batch)) => {
+            let lines: Vec<_> = batch.notifications.iter().map(describe).collect();
+            out.push_str(&lines.join("; "));
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{ErrorCode, PinOperation, Pins};
+
+    fn set_pin(pin: Pins, op: PinOperation) -> Command {
+        Command::Send(Body::SetPin(message::SetPin {
+            pin: pin as i32,
+            op: op as i32,
+        }))
+    }
+
+    fn notify(body: &str) -> Command {
+        Command::Send(Body::PushNotification(message::PushNotification {
+            body: body.to_owned(),
+            ..Default::default()
+        }))
+    }
+
+    /// Checks each line fails with an error mentioning what's paired with it
+    fn assert_rejected(cases: &[(&str, &str)]) {
+        for (line, expected) in cases {
+            let err = parse(line).unwrap_err().to_string();
+            assert!(err.contains(expected), "{:?}: {}", line, err);
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        let cases = [
+            ("help", Command::Help),
+            ("?", Command::Help),
+            (
+                "time 1700000000",
+                Command::Send(Body::SyncClock(message::SyncClock {
+                    timestamp: Some(prost_types::Timestamp {
+                        seconds: 1_700_000_000,
+                        nanos: 0,
+                    }),
+                })),
+            ),
+            ("notify hello", notify("hello")),
+            ("  NOTIFY  hello there \r", notify("hello there")),
+            ("pin g26 high", set_pin(Pins::G26, PinOperation::SetHigh)),
+            ("pin G25 Low", set_pin(Pins::G25, PinOperation::SetLow)),
+            (
+                "pin g0  read",
+                set_pin(Pins::G0, PinOperation::AnalogueRead),
+            ),
+            (
+                "batt",
+                Command::Send(Body::GetDeviceInfo(message::GetDeviceInfo {})),
+            ),
+            (
+                "info",
+                Command::Send(Body::GetDeviceInfo(message::GetDeviceInfo {})),
+            ),
+            (
+                "diag",
+                Command::Send(Body::GetDiagnostics(message::GetDiagnostics {})),
+            ),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(parse(line).unwrap(), expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_verbs() {
+        assert_rejected(&[
+            ("", "empty command"),
+            ("   ", "empty command"),
+            ("reboot", "unknown command \"reboot\""),
+            ("times 5", "unknown command \"times\""),
+        ]);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_rejected(&[
+            ("time", "usage: time"),
+            ("time soon", "usage: time"),
+            ("time -", "usage: time"),
+            ("notify", "usage: notify"),
+            ("notify   ", "usage: notify"),
+            ("pin", "usage: pin"),
+            ("pin g26", "usage: pin"),
+            ("pin g1 high", "unknown pin \"g1\""),
+            ("pin g26 up", "unknown pin operation"),
+        ]);
+    }
+
+    #[test]
+    fn rejects_long_lines() {
+        let text = "a".repeat(MAX_LINE_LEN - "notify ".len());
+        let line = format!("notify {}", text);
+        assert_eq!(parse(&line).unwrap(), notify(&text));
+        // surrounding whitespace doesn't count
+        assert_eq!(parse(&format!(" {} ", line)).unwrap(), notify(&text));
+
+        assert_rejected(&[(&format!("{}a", line), "over the limit")]);
+    }
+
+    #[test]
+    fn describes_notifications() {
+        let cases = [
+            (Notification { body: None }, "empty"),
+            (Notification::ack(1), "ok"),
+            (
+                Notification::error(1, ErrorCode::Unhandled, "nope"),
+                "error Unhandled: nope",
+            ),
+            (
+                Notification {
+                    body: Some(notification::Body::PinRead(message::PinRead {
+                        pin: Pins::G25 as i32,
+                        value: 1.5,
+                        request_id: 1,
+                    })),
+                },
+                "pin G25 = 1.5",
+            ),
+            (
+                Notification {
+                    body: Some(notification::Body::DeviceInfo(message::DeviceInfo {
+                        board: "m5stickc-plus".to_owned(),
+                        crate_version: "0.1.0".to_owned(),
+                        git_hash: "abc123".to_owned(),
+                        uptime_ms: 61_500,
+                        battery_percent: 80,
+                        ..Default::default()
+                    })),
+                },
+                "m5stickc-plus 0.1.0 (abc123), up 61s, batt 80%",
+            ),
+            (
+                Notification {
+                    body: Some(notification::Body::NotificationAction(
+                        message::NotificationAction {
+                            id: 4,
+                            label: "Answer".to_owned(),
+                            ..Default::default()
+                        },
+                    )),
+                },
+                "action on 4: Answer",
+            ),
+            (
+                Notification {
+                    body: Some(notification::Body::Bonds(message::Bonds {
+                        request_id: 1,
+                        bonds: vec![Default::default(); 2],
+                    })),
+                },
+                "2 bonds",
+            ),
+            (
+                Notification {
+                    body: Some(notification::Body::Diagnostics(message::Diagnostics {
+                        connections: vec![message::ConnectionDiagnostics {
+                            interval_us: 30_000,
+                            latency: 4,
+                            mtu: 185,
+                            ..Default::default()
+                        }],
+                        radio: Some(message::RadioDiagnostics {
+                            on: true,
+                            on_ms: 30_000,
+                            off_ms: 90_000,
+                            hours_left: 41.6,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    })),
+                },
+                "1 connections, 30ms/4 mtu 185, radio on for 30s/120s, 42h left",
+            ),
+            (
+                Notification {
+                    body: Some(notification::Body::Batch(message::NotificationBatch {
+                        notifications: vec![
+                            Notification::ack(1),
+                            Notification::error(2, ErrorCode::BusFull, "full"),
+                        ],
+                    })),
+                },
+                "ok; error BusFull: full",
+            ),
+        ];
+        for (notif, expected) in cases {
+            assert_eq!(describe(&notif), expected);
+        }
+    }
+}
@@ -0,0 +1,651 @@
+//! Holds outbound notifications while nobody is listening for them.
+//!
+//! New notifications go into a ring buffer in RAM. Once that fills up the
+//! oldest ones are spilled to a [`Store`], which on the watch is NVS, and once
+//! the space set aside there fills up too the oldest notification is thrown
+//! away. Spilled notifications survive a reboot.
+//!
+//! The capacities are the protobuf message the phone sets them with, and are
+//! kept in the store given to [`use_store`].
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use color_eyre::eyre::{ensure, eyre};
+use tracing::{error, info, warn};
+
+use crate::message::OutboxConfig;
+
+pub const DEFAULT_RAM_CAPACITY: u32 = 16;
+pub const DEFAULT_FLASH_CAPACITY: u32 = 64;
+
+/// Notifications can be a few hundred bytes, and there's little RAM and only
+/// 24K of NVS to go around
+const MAX_RAM_CAPACITY: u32 = 64;
+const MAX_FLASH_CAPACITY: u32 = 128;
+
+pub fn default_config() -> OutboxConfig {
+    OutboxConfig {
+        ram_capacity: DEFAULT_RAM_CAPACITY,
+        flash_capacity: DEFAULT_FLASH_CAPACITY,
+    }
+}
+
+/// Where the config and spilled notifications are kept
+pub trait Store: Send {
+    fn config(&self) -> color_eyre::Result<Option<OutboxConfig>>;
+
+    fn set_config(&mut self, config: &OutboxConfig) -> color_eyre::Result<()>;
+
+    /// The index of the oldest spilled notification and the index the next
+    /// one will get
+    fn bounds(&self) -> color_eyre::Result<Option<(u32, u32)>>;
+
+    fn set_bounds(&mut self, head: u32, tail: u32) -> color_eyre::Result<()>;
+
+    fn item(&self, index: u32) -> color_eyre::Result<Option<Vec<u8>>>;
+
+    fn set_item(&mut self, index: u32, item: &[u8]) -> color_eyre::Result<()>;
+
+    fn erase_item(&mut self, index: u32) -> color_eyre::Result<()>;
+}
+
+/// The config, and the store it's kept in once there is one
+static CURRENT: Mutex<Option<(Box<dyn Store>, OutboxConfig)>> = Mutex::new(None);
+
+/// Keeps the config in `store` from now on, loading whatever it already has
+pub fn use_store(store: impl Store + 'static) {
+    let config = store
+        .config()
+        .unwrap_or_else(|err| {
+            error!(?err, "Failed to load the outbox config, using the default");
+            None
+        })
+        .unwrap_or_else(default_config);
+    *CURRENT.lock().unwrap() = Some((Box::new(store), config));
+}
+
+pub fn current() -> OutboxConfig {
+    CURRENT
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map_or_else(default_config, |(_, config)| config.clone())
+}
+
+fn validate(config: &OutboxConfig) -> color_eyre::Result<()> {
+    ensure!(
+        (1..=MAX_RAM_CAPACITY).contains(&config.ram_capacity),
+        "RAM capacity must be between 1 and {}, got {}",
+        MAX_RAM_CAPACITY,
+        config.ram_capacity
+    );
+    ensure!(
+        config.flash_capacity <= MAX_FLASH_CAPACITY,
+        "Flash capacity must be at most {}, got {}",
+        MAX_FLASH_CAPACITY,
+        config.flash_capacity
+    );
+
+    Ok(())
+}
+
+/// Replaces the config, persisting it for the next boot. The outbox picks it
+/// up the next time it's looked at.
+pub fn set(config: OutboxConfig) -> color_eyre::Result<()> {
+    validate(&config)?;
+
+    let mut current = CURRENT.lock().unwrap();
+    let (store, current) = current
+        .as_mut()
+        .ok_or_else(|| eyre!("The outbox has nowhere to keep its config"))?;
+    store.set_config(&config)?;
+    info!(?config, "Updated the outbox config");
+    *current = config;
+
+    Ok(())
+}
+
+pub struct Outbox {
+    config: OutboxConfig,
+    ram: VecDeque<Vec<u8>>,
+    /// `None` if the store couldn't be opened, in which case nothing is
+    /// spilled
+    store: Option<Box<dyn Store>>,
+    /// Index of the oldest notification in flash
+    head: u32,
+    /// Index the next notification spilled to flash will get
+    tail: u32,
+    /// Whether `head` has moved on since it was last saved
+    dirty: bool,
+    evicted: u64,
+}
+
+impl Outbox {
+    /// Picks up whatever was spilled to `store` before a reboot
+    pub fn new(config: OutboxConfig, store: Option<Box<dyn Store>>) -> Self {
+        let (head, tail) = store
+            .as_ref()
+            .and_then(|store| match store.bounds() {
+                Ok(bounds) => bounds,
+                Err(err) => {
+                    error!(?err, "Couldn't load outbox bounds, starting afresh");
+                    None
+                }
+            })
+            .unwrap_or((0, 0));
+
+        Self {
+            ram: VecDeque::with_capacity(config.ram_capacity as usize),
+            config,
+            store,
+            head,
+            tail,
+            dirty: false,
+            evicted: 0,
+        }
+    }
+
+    /// Switches to `config`, spilling or evicting whatever no longer fits.
+    /// Returns where notifications were thrown away from, as [`push`] does.
+    ///
+    /// [`push`]: Outbox::push
+    pub fn configure(&mut self, config: OutboxConfig) -> Vec<usize> {
+        let mut removed = Vec::new();
+        if config == self.config {
+            return removed;
+        }
+
+        self.config = config;
+        self.spill_excess(&mut removed);
+        while self.flash_len() > self.config.flash_capacity {
+            self.evict(&mut removed);
+        }
+        self.save_bounds();
+
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.flash_len() as usize + self.ram.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many notifications have been thrown away to make room
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    /// Adds `item` at the back, throwing away whatever has to go to make room.
+    /// Returns the index of each notification thrown away, in the order they
+    /// went, so anything keeping its place in the outbox can follow along with
+    /// [`Cursors::removed`].
+    pub fn push(&mut self, item: Vec<u8>) -> Vec<usize> {
+        let mut removed = Vec::new();
+        self.ram.push_back(item);
+        self.spill_excess(&mut removed);
+        removed
+    }
+
+    fn spill_excess(&mut self, removed: &mut Vec<usize>) {
+        while self.ram.len() > self.config.ram_capacity as usize {
+            // the oldest in RAM comes after everything in flash
+            let index = self.flash_len() as usize;
+            let oldest = self.ram.pop_front().unwrap();
+
+            if self.store.is_none() || self.config.flash_capacity == 0 {
+                self.evicted += 1;
+                removed.push(index);
+            } else if let Err(err) = self.spill(&oldest, removed) {
+                error!(?err, "Failed to spill notification to flash, dropping it");
+                self.evicted += 1;
+                removed.push(index);
+            }
+        }
+    }
+
+    /// The notification `index` places from the front, if it can be read
+    pub fn get(&self, index: usize) -> Option<Vec<u8>> {
+        let flash_len = self.flash_len() as usize;
+        if index >= flash_len {
+            return self.ram.get(index - flash_len).cloned();
+        }
+
+        let store = self.store.as_ref()?;
+        match store.item(self.head.wrapping_add(index as u32)) {
+            Ok(Some(item)) => Some(item),
+            Ok(None) => {
+                warn!(index, "Spilled notification is missing");
+                None
+            }
+            Err(err) => {
+                error!(?err, index, "Couldn't read spilled notification");
+                None
+            }
+        }
+    }
+
+    /// Removes the `count` oldest notifications, saving where the outbox
+    /// starts once rather than after each of them
+    pub fn remove(&mut self, count: usize) {
+        for _ in 0..count {
+            self.pop_front();
+        }
+        self.save_bounds();
+    }
+
+    fn pop_front(&mut self) {
+        if self.flash_len() == 0 {
+            self.ram.pop_front();
+            return;
+        }
+
+        let store = self.store.as_mut().unwrap();
+        if let Err(err) = store.erase_item(self.head) {
+            error!(
+                ?err,
+                index = self.head,
+                "Couldn't erase spilled notification"
+            );
+        }
+        self.head = self.head.wrapping_add(1);
+        self.dirty = true;
+    }
+
+    fn flash_len(&self) -> u32 {
+        self.tail.wrapping_sub(self.head)
+    }
+
+    /// Moves the oldest notification in RAM, which `item` was, to the back of
+    /// flash, where it keeps its index
+    fn spill(&mut self, item: &[u8], removed: &mut Vec<usize>) -> color_eyre::Result<()> {
+        let store = self.store.as_mut().unwrap();
+        store.set_item(self.tail, item)?;
+        self.tail = self.tail.wrapping_add(1);
+
+        while self.flash_len() > self.config.flash_capacity {
+            self.evict(removed);
+        }
+
+        self.dirty = true;
+        self.save_bounds();
+
+        Ok(())
+    }
+
+    /// Throws away the oldest notification in flash, which is the front
+    fn evict(&mut self, removed: &mut Vec<usize>) {
+        let store = self.store.as_mut().unwrap();
+        if let Err(err) = store.erase_item(self.head) {
+            error!(
+                ?err,
+                index = self.head,
+                "Couldn't erase evicted notification"
+            );
+        }
+        self.head = self.head.wrapping_add(1);
+        self.dirty = true;
+        self.evicted += 1;
+        removed.push(0);
+    }
+
+    fn save_bounds(&mut self) {
+        if !std::mem::take(&mut self.dirty) {
+            return;
+        }
+
+        if let Some(store) = &mut self.store {
+            if let Err(err) = store.set_bounds(self.head, self.tail) {
+                error!(?err, "Couldn't save outbox bounds");
+            }
+        }
+    }
+}
+
+/// How far through the outbox each subscriber has got. A notification is only
+/// removed once every subscriber has it, and nobody is sent it twice.
+#[derive(Debug, Default)]
+pub struct Cursors(HashMap<u16, usize>);
+
+impl Cursors {
+    /// Starts anyone new at the front of the outbox, and forgets anyone who
+    /// has gone
+    pub fn set_subscribers(&mut self, subscribers: &[u16]) {
+        self.0
+            .retain(|conn_handle, _| subscribers.contains(conn_handle));
+        for &conn_handle in subscribers {
+            self.0.entry(conn_handle).or_insert(0);
+        }
+    }
+
+    /// How many notifications from the front `conn_handle` already has
+    pub fn get(&self, conn_handle: u16) -> usize {
+        self.0.get(&conn_handle).copied().unwrap_or(0)
+    }
+
+    pub fn advance(&mut self, conn_handle: u16, count: usize) {
+        *self.0.entry(conn_handle).or_insert(0) += count;
+    }
+
+    /// Follows the outbox throwing away the notification at `index`: anyone
+    /// who already had it moves back one, and anyone yet to get it just won't
+    pub fn removed(&mut self, index: usize) {
+        for cursor in self.0.values_mut() {
+            if *cursor > index {
+                *cursor -= 1;
+            }
+        }
+    }
+
+    /// How many notifications from the front everyone has, which are then
+    /// taken off everyone's count so they can be removed from the outbox
+    pub fn take_delivered(&mut self) -> usize {
+        let delivered = self.0.values().copied().min().unwrap_or(0);
+        for cursor in self.0.values_mut() {
+            *cursor -= delivered;
+        }
+        delivered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Keeps everything in RAM. Clones share what's kept, so an outbox can be
+    /// opened again on whatever the last one left, as if after a reboot.
+    #[derive(Debug, Default, Clone)]
+    struct MemoryStore(Arc<Mutex<Memory>>);
+
+    #[derive(Debug, Default)]
+    struct Memory {
+        config: Option<OutboxConfig>,
+        bounds: Option<(u32, u32)>,
+        items: HashMap<u32, Vec<u8>>,
+        /// Fails writes, like a full NVS partition
+        full: bool,
+    }
+
+    impl MemoryStore {
+        fn memory(&self) -> std::sync::MutexGuard<'_, Memory> {
+            self.0.lock().unwrap()
+        }
+    }
+
+    impl Store for MemoryStore {
+        fn config(&self) -> color_eyre::Result<Option<OutboxConfig>> {
+            Ok(self.memory().config.clone())
+        }
+
+        fn set_config(&mut self, config: &OutboxConfig) -> color_eyre::Result<()> {
+            self.memory().config = Some(config.clone());
+            Ok(())
+        }
+
+        fn bounds(&self) -> color_eyre::Result<Option<(u32, u32)>> {
+            Ok(self.memory().bounds)
+        }
+
+        fn set_bounds(&mut self, head: u32, tail: u32) -> color_eyre::Result<()> {
+            self.memory().bounds = Some((head, tail));
+            Ok(())
+        }
+
+        fn item(&self, index: u32) -> color_eyre::Result<Option<Vec<u8>>> {
+            Ok(self.memory().items.get(&index).cloned())
+        }
+
+        fn set_item(&mut self, index: u32, item: &[u8]) -> color_eyre::Result<()> {
+            let mut memory = self.memory();
+            ensure!(!memory.full, "No room for notification {}", index);
+            memory.items.insert(index, item.to_vec());
+            Ok(())
+        }
+
+        fn erase_item(&mut self, index: u32) -> color_eyre::Result<()> {
+            self.memory().items.remove(&index);
+            Ok(())
+        }
+    }
+
+    fn spilling(store: &MemoryStore, ram_capacity: u32, flash_capacity: u32) -> Outbox {
+        Outbox::new(
+            OutboxConfig {
+                ram_capacity,
+                flash_capacity,
+            },
+            Some(Box::new(store.clone())),
+        )
+    }
+
+    /// Keeps everything in RAM
+    fn outbox(ram_capacity: u32) -> Outbox {
+        Outbox::new(
+            OutboxConfig {
+                ram_capacity,
+                flash_capacity: 0,
+            },
+            None,
+        )
+    }
+
+    #[test]
+    fn evicts_the_oldest_when_full() {
+        let mut outbox = outbox(2);
+        assert!(outbox.push(vec![0]).is_empty());
+        assert!(outbox.push(vec![1]).is_empty());
+        assert_eq!(outbox.push(vec![2]), [0]);
+
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.evicted(), 1);
+        assert_eq!(outbox.get(0), Some(vec![1]));
+    }
+
+    #[test]
+    fn shrinking_evicts_what_no_longer_fits() {
+        let mut outbox = outbox(4);
+        for item in 0..4 {
+            outbox.push(vec![item]);
+        }
+
+        let removed = outbox.configure(OutboxConfig {
+            ram_capacity: 1,
+            flash_capacity: 0,
+        });
+        assert_eq!(removed, [0, 0, 0]);
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox.evicted(), 3);
+        assert_eq!(outbox.get(0), Some(vec![3]));
+    }
+
+    #[test]
+    fn spills_the_oldest_to_flash() {
+        let store = MemoryStore::default();
+        let mut outbox = spilling(&store, 2, 4);
+        for item in 0..4 {
+            assert!(outbox.push(vec![item]).is_empty());
+        }
+
+        assert_eq!(outbox.len(), 4);
+        assert_eq!(outbox.evicted(), 0);
+        assert_eq!(store.memory().items.len(), 2);
+        assert_eq!(store.memory().bounds, Some((0, 2)));
+        let items: Vec<_> = (0..4).map(|index| outbox.get(index)).collect();
+        assert_eq!(
+            items,
+            [Some(vec![0]), Some(vec![1]), Some(vec![2]), Some(vec![3])]
+        );
+    }
+
+    #[test]
+    fn spilled_notifications_survive_a_reboot() {
+        let store = MemoryStore::default();
+        let mut outbox = spilling(&store, 1, 4);
+        for item in 0..4 {
+            outbox.push(vec![item]);
+        }
+        outbox.remove(1);
+        drop(outbox);
+
+        // what was still in RAM is gone, and what was removed stays removed
+        let outbox = spilling(&store, 1, 4);
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.get(0), Some(vec![1]));
+        assert_eq!(outbox.get(1), Some(vec![2]));
+        assert_eq!(store.memory().items.len(), 2);
+    }
+
+    #[test]
+    fn evicts_the_oldest_from_flash_when_full() {
+        let store = MemoryStore::default();
+        let mut outbox = spilling(&store, 1, 2);
+        for item in 0..3 {
+            assert!(outbox.push(vec![item]).is_empty());
+        }
+
+        assert_eq!(outbox.push(vec![3]), [0]);
+        assert_eq!(outbox.len(), 3);
+        assert_eq!(outbox.evicted(), 1);
+        assert_eq!(outbox.get(0), Some(vec![1]));
+        assert!(!store.memory().items.contains_key(&0));
+
+        // shrinking flash throws away from the front too
+        let removed = outbox.configure(OutboxConfig {
+            ram_capacity: 1,
+            flash_capacity: 1,
+        });
+        assert_eq!(removed, [0]);
+        assert_eq!(outbox.get(0), Some(vec![2]));
+        assert_eq!(store.memory().bounds, Some((2, 3)));
+    }
+
+    #[test]
+    fn drops_what_cant_be_spilled() {
+        let store = MemoryStore::default();
+        let mut outbox = spilling(&store, 1, 4);
+        let mut cursors = Cursors::default();
+        cursors.set_subscribers(&[1, 2, 3]);
+
+        outbox.push(vec![0]);
+        outbox.push(vec![1]);
+        cursors.advance(1, 2);
+        cursors.advance(2, 1);
+
+        // 1 goes from RAM but can't go to flash, so it's lost from between 0
+        // in flash and 2 in RAM
+        store.memory().full = true;
+        let removed = outbox.push(vec![2]);
+        assert_eq!(removed, [1]);
+        assert_eq!(outbox.evicted(), 1);
+        for index in removed {
+            cursors.removed(index);
+        }
+
+        assert_eq!(outbox.get(cursors.get(1)), Some(vec![2]));
+        assert_eq!(outbox.get(cursors.get(2)), Some(vec![2]));
+        assert_eq!(outbox.get(cursors.get(3)), Some(vec![0]));
+    }
+
+    #[test]
+    fn keeps_the_config_in_the_store() {
+        let store = MemoryStore::default();
+        let config = OutboxConfig {
+            ram_capacity: 8,
+            flash_capacity: 32,
+        };
+
+        use_store(store.clone());
+        assert_eq!(current(), default_config());
+        set(config.clone()).unwrap();
+        assert!(set(OutboxConfig {
+            ram_capacity: 0,
+            flash_capacity: 0,
+        })
+        .is_err());
+
+        assert_eq!(current(), config);
+        assert_eq!(store.memory().config, Some(config));
+    }
+
+    #[test]
+    fn rejects_bad_configs() {
+        assert!(validate(&default_config()).is_ok());
+        assert!(validate(&OutboxConfig {
+            ram_capacity: 0,
+            flash_capacity: 0,
+        })
+        .is_err());
+        assert!(validate(&OutboxConfig {
+            ram_capacity: 1,
+            flash_capacity: MAX_FLASH_CAPACITY + 1,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn removes_only_what_everyone_has() {
+        let mut cursors = Cursors::default();
+        cursors.set_subscribers(&[1, 2]);
+
+        cursors.advance(1, 3);
+        cursors.advance(2, 1);
+        assert_eq!(cursors.take_delivered(), 1);
+        assert_eq!((cursors.get(1), cursors.get(2)), (2, 0));
+
+        cursors.advance(2, 2);
+        assert_eq!(cursors.take_delivered(), 2);
+        assert_eq!((cursors.get(1), cursors.get(2)), (0, 0));
+    }
+
+    #[test]
+    fn cursors_follow_evictions() {
+        let mut outbox = outbox(2);
+        let mut cursors = Cursors::default();
+        cursors.set_subscribers(&[1, 2]);
+
+        outbox.push(vec![0]);
+        outbox.push(vec![1]);
+        cursors.advance(1, 2);
+        cursors.advance(2, 1);
+
+        for index in outbox.push(vec![2]) {
+            cursors.removed(index);
+        }
+
+        // 1 has everything but the newest, and 2 lost the one it had
+        assert_eq!(outbox.get(cursors.get(1)), Some(vec![2]));
+        assert_eq!(outbox.get(cursors.get(2)), Some(vec![1]));
+    }
+
+    #[test]
+    fn cursors_follow_removals_from_the_middle() {
+        let mut cursors = Cursors::default();
+        cursors.set_subscribers(&[1, 2, 3]);
+        cursors.advance(1, 1);
+        cursors.advance(2, 2);
+        cursors.advance(3, 4);
+
+        cursors.removed(2);
+        assert_eq!((cursors.get(1), cursors.get(2), cursors.get(3)), (1, 2, 3));
+    }
+
+    #[test]
+    fn newcomers_start_from_the_front() {
+        let mut cursors = Cursors::default();
+        cursors.set_subscribers(&[1]);
+        cursors.advance(1, 2);
+
+        // 1 going away leaves nothing everyone has been sent
+        cursors.set_subscribers(&[2]);
+        assert_eq!(cursors.get(2), 0);
+        assert_eq!(cursors.take_delivered(), 0);
+
+        cursors.set_subscribers(&[]);
+        assert_eq!(cursors.take_delivered(), 0);
+    }
+}
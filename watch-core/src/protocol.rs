@@ -0,0 +1,432 @@
+//! Decodes, authenticates and dispatches messages from the phone, and sends
+//! notifications back, independent of the link they travel over.
+//!
+//! Each transport feeds whole payloads into [`receive`] and is registered with
+//! [`register`]. Replies go back over the transport the request came in on,
+//! and only notifications the phone didn't ask for go over every transport.
+//!
+//! Each transport numbers its requests itself, so they're renumbered on the
+//! way in to keep them apart on the bus, and replies get the number the phone
+//! used back on the way out.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+use crossbeam::channel;
+use once_cell::sync::{Lazy, OnceCell};
+use prost::Message;
+use tracing::{error, info};
+
+use crate::{auth, delivery, message};
+
+/// A link to the phone that notifications can be sent over
+pub trait Transport: Send {
+    /// What requests that came in over it are tagged with
+    fn name(&self) -> &'static str;
+
+    /// Sends one encoded notification
+    fn send(&mut self, payload: &[u8]) -> color_eyre::Result<()>;
+}
+
+/// A notification on its way out, and the transport to send it over, or
+/// `None` for all of them
+type Outbound = (Option<&'static str>, message::Notification);
+
+static QUEUE: Lazy<(channel::Sender<Outbound>, channel::Receiver<Outbound>)> =
+    Lazy::new(|| channel::bounded(4));
+
+static TRANSPORTS: Mutex<Vec<Box<dyn Transport>>> = Mutex::new(Vec::new());
+
+/// How many requests to remember the transport of
+const MAX_ROUTES: usize = 16;
+
+/// Where a request came from, so its reply can go back there
+struct Route {
+    /// What the request is known as on the bus
+    id: u32,
+    from: &'static str,
+    /// What the phone called it
+    request_id: u32,
+}
+
+/// The transport each recent request came in over, newest last
+static ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+
+/// The id given to the next request put on the bus
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+static TX_THREAD: OnceCell<JoinHandle<()>> = OnceCell::new();
+
+/// Adds a transport that notifications can be sent over
+pub fn register(transport: impl Transport + 'static) {
+    info!(transport = transport.name(), "Registered transport");
+    TRANSPORTS.lock().unwrap().push(Box::new(transport));
+
+    TX_THREAD.get_or_init(|| std::thread::spawn(tx_thread));
+}
+
+/// Queue a notification for the phone without blocking, dropping it if the
+/// queue is full. Replies go to the transport their request came in over.
+pub fn send_notification(mut msg: message::Notification) {
+    let to = match msg.request_id_mut() {
+        Some(id) => match route(*id) {
+            Some((from, request_id)) => {
+                *id = request_id;
+                Some(from)
+            }
+            None => {
+                delivery::dropped();
+                error!(id = *id, "Forgot where request came from, dropping reply");
+                return;
+            }
+        },
+        None => None,
+    };
+    send_to(to, msg);
+}
+
+fn send_to(to: Option<&'static str>, msg: message::Notification) {
+    if let Err(err) = QUEUE.0.try_send((to, msg)) {
+        delivery::dropped();
+        error!(?err, "Outbound queue full, dropping notification");
+    }
+}
+
+/// Remembers that request `request_id` came in over `from`, returning what
+/// it's known as on the bus
+fn record_route(request_id: u32, from: &'static str) -> u32 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut routes = ROUTES.lock().unwrap();
+    if routes.len() == MAX_ROUTES {
+        routes.remove(0);
+    }
+    routes.push(Route {
+        id,
+        from,
+        request_id,
+    });
+    id
+}
+
+/// The transport request `id` on the bus came in over and what the phone
+/// called it, if it's recent enough to be remembered
+fn route(id: u32) -> Option<(&'static str, u32)> {
+    ROUTES
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|route| route.id == id)
+        .map(|route| (route.from, route.request_id))
+}
+
+fn tx_thread() {
+    for (to, msg) in QUEUE.1.clone() {
+        let buf = msg.encode_to_vec();
+        for transport in TRANSPORTS.lock().unwrap().iter_mut() {
+            if matches!(to, Some(to) if to != transport.name()) {
+                continue;
+            }
+
+            if let Err(err) = transport.send(&buf) {
+                error!(?err, transport = transport.name(), "Error sending notif");
+            }
+        }
+    }
+}
+
+/// Handles a whole payload from the transport named `from`. `secure` is
+/// whether the link is private enough for a key to be provisioned over it.
+pub fn receive(from: &'static str, buf: &[u8], secure: bool) {
+    let msg = match message::validate_msg(buf, secure) {
+        Ok(msg) => msg,
+        Err(rejected) => {
+            error!(?rejected, "Rejected a message");
+            send_to(
+                Some(from),
+                message::Notification::error(
+                    rejected.request_id,
+                    rejected.code,
+                    format!("{:?}", rejected.reason),
+                ),
+            );
+            return;
+        }
+    };
+
+    dispatch(from, msg);
+}
+
+/// Hands an authenticated message from the transport named `from` to whatever
+/// handles it, for transports that vouch for the sender themselves
+pub fn dispatch(from: &'static str, mut msg: message::Message) {
+    info!(?msg, from, "Got message");
+    msg.request_id = record_route(msg.request_id, from);
+    let request_id = msg.request_id;
+
+    if let Some(message::message::Body::ProvisionKey(provision)) = &msg.body {
+        let result = auth::provision(&provision.key);
+        if let Err(err) = &result {
+            error!(?err, "Failed to provision key");
+        }
+        send_notification(message::Notification::reply(request_id, &result));
+        return;
+    }
+
+    if let Err(err) = message::push_message(msg) {
+        error!(?err, "Failed to push message");
+        let code = match err {
+            message::PushError::Full { .. } => message::ErrorCode::BusFull,
+            message::PushError::Empty | message::PushError::Unhandled(_) => {
+                message::ErrorCode::Unhandled
+            }
+        };
+        send_notification(message::Notification::error(
+            request_id,
+            code,
+            err.to_string(),
+        ));
+    }
+}
+
+#[cfg(all(test, not(feature = "legacy-origin")))]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Mutex, Once};
+    use std::time::Duration;
+
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::*;
+    use crate::message::{message::Body, notification, ErrorCode, Notification, Topic};
+
+    const KEY: [u8; auth::KEY_LEN] = [7; auth::KEY_LEN];
+
+    /// Hands everything sent over it to the test
+    struct MemoryTransport(&'static str, channel::Sender<Vec<u8>>);
+
+    impl Transport for MemoryTransport {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn send(&mut self, payload: &[u8]) -> color_eyre::Result<()> {
+            self.1.send(payload.to_vec())?;
+            Ok(())
+        }
+    }
+
+    const MEMORY: &str = "memory";
+    const OTHER: &str = "other";
+
+    /// Whatever the transport the tests send over was handed
+    static SENT: OnceCell<channel::Receiver<Vec<u8>>> = OnceCell::new();
+
+    /// Whatever a second transport, that the tests don't send over, was handed
+    static OTHER_SENT: OnceCell<channel::Receiver<Vec<u8>>> = OnceCell::new();
+
+    /// The tests share the queue, the bus and the key, so they take turns
+    static TURN: Mutex<()> = Mutex::new(());
+
+    static COUNTER: AtomicU64 = AtomicU64::new(1);
+
+    fn setup() -> std::sync::MutexGuard<'static, ()> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            auth::use_store(auth::MemoryStore {
+                key: Some(KEY.to_vec()),
+                counter: None,
+            });
+            let (tx, rx) = channel::unbounded();
+            SENT.set(rx).unwrap();
+            register(MemoryTransport(MEMORY, tx));
+
+            let (tx, rx) = channel::unbounded();
+            OTHER_SENT.set(rx).unwrap();
+            register(MemoryTransport(OTHER, tx));
+        });
+
+        let turn = TURN.lock().unwrap_or_else(|err| err.into_inner());
+        while sent().try_recv().is_ok() {}
+        while other_sent().try_recv().is_ok() {}
+        turn
+    }
+
+    fn seal(payload: Vec<u8>, counter: u64, key: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(&counter.to_le_bytes());
+        mac.update(&payload);
+
+        message::SignedMessage {
+            payload,
+            mac: mac.finalize().into_bytes().to_vec(),
+            counter,
+        }
+        .encode_to_vec()
+    }
+
+    fn signed(request_id: u32, body: Body) -> Vec<u8> {
+        let payload = message::Message {
+            origin: 0,
+            request_id,
+            body: Some(body),
+        }
+        .encode_to_vec();
+        seal(payload, COUNTER.fetch_add(1, Ordering::Relaxed), &KEY)
+    }
+
+    fn sent() -> &'static channel::Receiver<Vec<u8>> {
+        SENT.get().unwrap()
+    }
+
+    fn other_sent() -> &'static channel::Receiver<Vec<u8>> {
+        OTHER_SENT.get().unwrap()
+    }
+
+    fn next_sent() -> Notification {
+        let buf = sent()
+            .recv_timeout(Duration::from_secs(1))
+            .expect("Nothing was sent");
+        Notification::decode(buf.as_slice()).unwrap()
+    }
+
+    fn error_of(notif: &Notification) -> (u32, ErrorCode) {
+        match &notif.body {
+            Some(notification::Body::Error(error)) => (error.request_id, error.code()),
+            body => panic!("Expected an error, got {:?}", body),
+        }
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let _turn = setup();
+
+        receive(MEMORY, &[0xff, 0xff, 0xff], false);
+        assert_eq!(error_of(&next_sent()), (0, ErrorCode::DecodeFailure));
+    }
+
+    #[test]
+    fn rejects_bad_mac() {
+        let _turn = setup();
+
+        let payload = message::Message {
+            request_id: 3,
+            body: Some(Body::GetDiagnostics(message::GetDiagnostics {})),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        receive(MEMORY, &seal(payload, u64::MAX, &[8; auth::KEY_LEN]), false);
+        assert_eq!(error_of(&next_sent()), (0, ErrorCode::Unauthenticated));
+    }
+
+    #[test]
+    fn dispatches_to_subscribers_once() {
+        let _turn = setup();
+        let rx = message::subscribe("test", &[(Topic::GetDiagnostics, 1)]);
+
+        let buf = signed(42, Body::GetDiagnostics(message::GetDiagnostics {}));
+        receive(MEMORY, &buf, false);
+        assert_eq!(route(rx.recv().unwrap().request_id), Some((MEMORY, 42)));
+
+        receive(MEMORY, &buf, false);
+        assert_eq!(error_of(&next_sent()), (0, ErrorCode::Replayed));
+    }
+
+    #[test]
+    fn mac_covers_the_bytes_as_sent() {
+        let _turn = setup();
+        let rx = message::subscribe("test", &[(Topic::GetDiagnostics, 1)]);
+
+        // fields in an order prost wouldn't write them, and one from a newer
+        // app that prost drops when decoding
+        let mut payload = vec![0x6a, 0x00, 0x28, 0x07];
+        payload.extend_from_slice(&[0xa0, 0x06, 0x01]);
+        receive(
+            MEMORY,
+            &seal(payload, COUNTER.fetch_add(1, Ordering::Relaxed), &KEY),
+            false,
+        );
+        assert_eq!(route(rx.recv().unwrap().request_id), Some((MEMORY, 7)));
+    }
+
+    #[test]
+    fn reports_unhandled_messages() {
+        let _turn = setup();
+
+        let body = Body::SetPin(message::SetPin {
+            pin: message::Pins::G26 as i32,
+            op: message::PinOperation::SetHigh as i32,
+        });
+        receive(MEMORY, &signed(9, body), false);
+        assert_eq!(error_of(&next_sent()), (9, ErrorCode::Unhandled));
+    }
+
+    #[test]
+    fn replies_go_back_the_way_the_request_came() {
+        let _turn = setup();
+        let rx = message::subscribe("test", &[(Topic::GetDiagnostics, 1)]);
+
+        receive(MEMORY, &[0xff, 0xff, 0xff], false);
+        assert_eq!(error_of(&next_sent()), (0, ErrorCode::DecodeFailure));
+
+        let buf = signed(11, Body::GetDiagnostics(message::GetDiagnostics {}));
+        receive(MEMORY, &buf, false);
+        let msg = rx.recv().unwrap();
+        send_notification(Notification::ack(msg.request_id));
+        assert!(matches!(
+            next_sent().body,
+            Some(notification::Body::Ack(message::Ack { request_id: 11 }))
+        ));
+
+        assert!(other_sent()
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+    }
+
+    #[test]
+    fn keeps_requests_with_the_same_id_apart() {
+        let _turn = setup();
+        let rx = message::subscribe("test", &[(Topic::GetDiagnostics, 2)]);
+
+        let body = Body::GetDiagnostics(message::GetDiagnostics {});
+        receive(MEMORY, &signed(5, body.clone()), false);
+        receive(OTHER, &signed(5, body), false);
+        let first = rx.recv().unwrap();
+        let second = rx.recv().unwrap();
+        assert_ne!(first.request_id, second.request_id);
+
+        // answered out of order, each still goes back where it came from
+        send_notification(Notification::ack(second.request_id));
+        send_notification(Notification::ack(first.request_id));
+        let ack = |buf: Vec<u8>| Notification::decode(buf.as_slice()).unwrap().body;
+        assert_eq!(
+            ack(other_sent().recv_timeout(Duration::from_secs(1)).unwrap()),
+            Some(notification::Body::Ack(message::Ack { request_id: 5 }))
+        );
+        assert_eq!(
+            next_sent().body,
+            Some(notification::Body::Ack(message::Ack { request_id: 5 }))
+        );
+        assert!(sent().recv_timeout(Duration::from_millis(100)).is_err());
+        assert!(other_sent()
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+    }
+
+    #[test]
+    fn unsolicited_notifications_go_everywhere() {
+        let _turn = setup();
+
+        send_notification(Notification {
+            body: Some(notification::Body::NotificationAction(
+                message::NotificationAction::default(),
+            )),
+        });
+        next_sent();
+        other_sent()
+            .recv_timeout(Duration::from_secs(1))
+            .expect("Nothing was sent over the other transport");
+    }
+}
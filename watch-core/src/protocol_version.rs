@@ -0,0 +1,6 @@
+// Included by both watch-core and watchctl, so the emulator always claims
+// to speak the same protocol as the watch it's built alongside
+
+/// Bumped whenever a change to `messages.proto` means older apps or watches
+/// can't talk to each other properly.
+pub const PROTOCOL_VERSION: u32 = 3;
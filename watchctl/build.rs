@@ -0,0 +1,10 @@
+fn main() -> anyhow::Result<()> {
+    // Shares the schema with the firmware so the two can't drift apart
+    prost_build::compile_protos(
+        &["../watch-core/src/messages.proto"],
+        &["../watch-core/src/"],
+    )?;
+    println!("cargo:rerun-if-changed=../watch-core/src/messages.proto");
+
+    Ok(())
+}
@@ -0,0 +1,327 @@
+//! A stand-in for the watch, answering messages the way the firmware does.
+
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use prost::Message as _;
+
+use crate::link;
+use crate::message::{self, message::Body, ErrorCode, Notification, PinOperation, Pins};
+
+/// The length of key the firmware accepts in `ProvisionKey`
+const KEY_LEN: usize = 32;
+
+pub struct Emulator {
+    /// Without a key, messages are checked by origin like `legacy-origin`
+    /// firmware does
+    key: Option<Vec<u8>>,
+    last_counter: u64,
+    bonds: Vec<message::Bond>,
+}
+
+impl Emulator {
+    pub fn new(key: Option<Vec<u8>>) -> Self {
+        Self {
+            key,
+            last_counter: 0,
+            bonds: vec![message::Bond {
+                address: vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+                address_type: 0,
+                connected: true,
+            }],
+        }
+    }
+
+    /// Everything the watch would send back for this payload
+    pub fn handle(&mut self, payload: &[u8]) -> Vec<Notification> {
+        let msg = match self.open(payload) {
+            Ok(msg) => msg,
+            Err((request_id, code, detail)) => {
+                return vec![Notification::error(request_id, code, detail)]
+            }
+        };
+
+        println!("<- {:?}", msg);
+
+        let request_id = msg.request_id;
+
+        match msg.body {
+            None => vec![Notification::error(
+                request_id,
+                ErrorCode::Unhandled,
+                "Message has no body",
+            )],
+            Some(Body::SyncClock(sync)) => match sync.timestamp {
+                Some(ts) => {
+                    println!("   clock set to {}.{:09}", ts.seconds, ts.nanos);
+                    vec![Notification::ack(request_id)]
+                }
+                None => vec![Notification::error(
+                    request_id,
+                    ErrorCode::HandlerError,
+                    "SyncClock is missing a timestamp",
+                )],
+            },
+            Some(Body::PushNotification(notif)) => {
+                println!("   showing {:?}: {:?}", notif.title, notif.body);
+                vec![Notification::ack(request_id)]
+            }
+            Some(Body::SetPin(set_pin)) => {
+                let pin = set_pin.pin();
+                // a read is answered by its PinRead, like on the watch
+                if set_pin.op() == PinOperation::AnalogueRead {
+                    return vec![Notification {
+                        body: Some(message::notification::Body::PinRead(message::PinRead {
+                            pin: pin as i32,
+                            value: fake_reading(pin),
+                            request_id,
+                        })),
+                    }];
+                }
+                vec![Notification::ack(request_id)]
+            }
+            Some(Body::ProvisionKey(provision)) => {
+                if provision.key.len() != KEY_LEN {
+                    return vec![Notification::error(
+                        request_id,
+                        ErrorCode::HandlerError,
+                        format!(
+                            "Keys must be {} bytes, got {}",
+                            KEY_LEN,
+                            provision.key.len()
+                        ),
+                    )];
+                }
+                self.key = Some(provision.key);
+                self.last_counter = 0;
+                vec![Notification::ack(request_id)]
+            }
+            Some(Body::GetDeviceInfo(_)) => vec![Notification {
+                body: Some(message::notification::Body::DeviceInfo(
+                    message::DeviceInfo {
+                        request_id,
+                        crate_version: env!("CARGO_PKG_VERSION").to_owned(),
+                        git_hash: "emulated".to_owned(),
+                        build_profile: "emulated".to_owned(),
+                        protocol_version: message::PROTOCOL_VERSION,
+                        features: Vec::new(),
+                        board: "watchctl-emulator".to_owned(),
+                        uptime_ms: 0,
+                        battery_percent: 100,
+                    },
+                )),
+            }],
+            Some(Body::GetDiagnostics(_)) => vec![Notification {
+                body: Some(message::notification::Body::Diagnostics(
+                    message::Diagnostics {
+                        request_id,
+                        connections: Vec::new(),
+                        radio: Some(message::RadioDiagnostics {
+                            on: true,
+                            ..Default::default()
+                        }),
+                        tx: Some(message::TxDiagnostics::default()),
+                        bus: Vec::new(),
+                    },
+                )),
+            }],
+            Some(Body::ListBonds(_)) => vec![Notification {
+                body: Some(message::notification::Body::Bonds(message::Bonds {
+                    request_id,
+                    bonds: self.bonds.clone(),
+                })),
+            }],
+            Some(Body::SetAdvertisingConfig(set)) => match set.config {
+                Some(config) => {
+                    println!(
+                        "   advertising as {:?} every {}ms, scan response {:?}",
+                        config.name,
+                        config.interval_ms,
+                        config.scan_response().collect::<Vec<_>>()
+                    );
+                    vec![Notification::ack(request_id)]
+                }
+                None => vec![Notification::error(
+                    request_id,
+                    ErrorCode::HandlerError,
+                    "SetAdvertisingConfig is missing a config",
+                )],
+            },
+            Some(Body::SetRadioConfig(set)) => match set.config {
+                Some(config) => {
+                    println!(
+                        "   radio off after {}s idle, back every {}s",
+                        config.idle_timeout_secs, config.sync_interval_secs
+                    );
+                    vec![Notification::ack(request_id)]
+                }
+                None => vec![Notification::error(
+                    request_id,
+                    ErrorCode::HandlerError,
+                    "SetRadioConfig is missing a config",
+                )],
+            },
+            Some(Body::SetOutboxConfig(set)) => match set.config {
+                Some(config) => {
+                    println!(
+                        "   holding {} notifications in RAM, {} in flash",
+                        config.ram_capacity, config.flash_capacity
+                    );
+                    vec![Notification::ack(request_id)]
+                }
+                None => vec![Notification::error(
+                    request_id,
+                    ErrorCode::HandlerError,
+                    "SetOutboxConfig is missing a config",
+                )],
+            },
+            Some(Body::DeleteBond(delete)) => {
+                let address = delete.bond.map(|bond| bond.address).unwrap_or_default();
+                let before = self.bonds.len();
+                self.bonds.retain(|bond| bond.address != address);
+                if self.bonds.len() == before {
+                    return vec![Notification::error(
+                        request_id,
+                        ErrorCode::HandlerError,
+                        format!("No bond with {}", message::format_address(&address)),
+                    )];
+                }
+                println!("   forgot {}", message::format_address(&address));
+                vec![Notification::ack(request_id)]
+            }
+        }
+    }
+
+    /// Checks a `SignedMessage` and decodes the message inside, or says why
+    /// it was turned away and which request to answer
+    fn open(&mut self, payload: &[u8]) -> Result<message::Message, (u32, ErrorCode, String)> {
+        let decode_failure =
+            |err: prost::DecodeError| (0, ErrorCode::DecodeFailure, format!("{:?}", err));
+        let signed = message::SignedMessage::decode(payload).map_err(decode_failure)?;
+
+        let key = match &self.key {
+            Some(key) => key,
+            None => {
+                let msg =
+                    message::Message::decode(signed.payload.as_slice()).map_err(decode_failure)?;
+                if msg.origin != message::ORIGIN {
+                    return Err((
+                        msg.request_id,
+                        ErrorCode::BadOrigin,
+                        format!("Bad origin, should be {}", message::ORIGIN),
+                    ));
+                }
+                return Ok(msg);
+            }
+        };
+
+        if !message::verify(key, &signed) {
+            return Err((0, ErrorCode::Unauthenticated, "Bad MAC".to_owned()));
+        }
+
+        if signed.counter <= self.last_counter {
+            return Err((
+                0,
+                ErrorCode::Replayed,
+                format!(
+                    "Counter {} is not after the last accepted counter {}",
+                    signed.counter, self.last_counter
+                ),
+            ));
+        }
+        self.last_counter = signed.counter;
+
+        message::Message::decode(signed.payload.as_slice()).map_err(decode_failure)
+    }
+}
+
+/// Something plausible for each pin, so scripts can tell them apart
+fn fake_reading(pin: Pins) -> f32 {
+    match pin {
+        Pins::G26 => 0.5,
+        Pins::G25 => 1.5,
+        Pins::G0 => 3.3,
+    }
+}
+
+/// Serves one connection at a time until killed
+pub fn serve(addr: impl ToSocketAddrs, key: Option<Vec<u8>>) -> color_eyre::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("Emulating a watch on {}", listener.local_addr()?);
+
+    let mut emulator = Emulator::new(key);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        println!("Connection from {}", peer);
+
+        if let Err(err) = serve_connection(&mut emulator, stream) {
+            eprintln!("Connection from {} failed: {:?}", peer, err);
+        }
+
+        println!("{} disconnected", peer);
+    }
+
+    Ok(())
+}
+
+fn serve_connection(emulator: &mut Emulator, stream: TcpStream) -> color_eyre::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    while let Some(payload) = link::read_frame(&mut reader)? {
+        for reply in emulator.handle(&payload) {
+            println!("-> {}", message::describe(&reply));
+            link::write_frame(&stream, &reply.encode_to_vec())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(body: Body, request_id: u32, counter: u64, key: Option<&[u8]>) -> Vec<u8> {
+        let msg = message::envelope(body, request_id);
+        message::seal(&msg, counter, key).encode_to_vec()
+    }
+
+    #[test]
+    fn reports_the_firmwares_protocol_version() {
+        let mut emulator = Emulator::new(None);
+        let replies = emulator.handle(&request(
+            Body::GetDeviceInfo(message::GetDeviceInfo {}),
+            4,
+            1,
+            None,
+        ));
+
+        match &replies[..] {
+            [Notification {
+                body: Some(message::notification::Body::DeviceInfo(info)),
+            }] => {
+                assert_eq!(info.request_id, 4);
+                assert_eq!(info.protocol_version, message::PROTOCOL_VERSION);
+            }
+            other => panic!("Expected device info, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_replayed_counters() {
+        let key = [0x55; 32];
+        let mut emulator = Emulator::new(Some(key.to_vec()));
+        let payload = request(Body::ListBonds(message::ListBonds {}), 5, 10, Some(&key));
+
+        assert_eq!(emulator.handle(&payload)[0].request_id(), Some(5));
+        let replies = emulator.handle(&payload);
+        assert!(matches!(
+            &replies[..],
+            [Notification {
+                body: Some(message::notification::Body::Error(error)),
+            }] if error.code == ErrorCode::Replayed as i32
+        ));
+    }
+}
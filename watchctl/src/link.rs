@@ -0,0 +1,84 @@
+//! Carries encoded messages over TCP, each prefixed with its length as a
+//! little endian `u32`.
+//!
+//! This stands in for the BLE characteristics, which do their own framing, so
+//! scripts can talk to the emulator without a phone in the loop.
+
+use std::io::{self, Read, Write};
+
+use color_eyre::eyre::ensure;
+
+/// Same limit as the firmware puts on reassembled messages
+pub const MAX_FRAME_LEN: usize = 16 * 1024;
+
+pub fn write_frame<W: Write>(mut w: W, payload: &[u8]) -> color_eyre::Result<()> {
+    ensure!(
+        payload.len() <= MAX_FRAME_LEN,
+        "Frame of {} bytes is over the {} byte limit",
+        payload.len(),
+        MAX_FRAME_LEN
+    );
+
+    w.write_all(&(payload.len() as u32).to_le_bytes())?;
+    w.write_all(payload)?;
+    w.flush()?;
+
+    Ok(())
+}
+
+/// Reads the next frame, or `None` if the other end closed the connection
+/// between frames
+pub fn read_frame<R: Read>(mut r: R) -> color_eyre::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+
+    let len = u32::from_le_bytes(len) as usize;
+    ensure!(
+        len <= MAX_FRAME_LEN,
+        "Frame of {} bytes is over the {} byte limit",
+        len,
+        MAX_FRAME_LEN
+    );
+
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_come_back_as_written() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hello").unwrap();
+        write_frame(&mut buf, b"").unwrap();
+        assert_eq!(&buf[..4], &5u32.to_le_bytes());
+
+        let mut r = buf.as_slice();
+        assert_eq!(read_frame(&mut r).unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(read_frame(&mut r).unwrap(), Some(Vec::new()));
+        assert_eq!(read_frame(&mut r).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        assert!(write_frame(Vec::new(), &vec![0; MAX_FRAME_LEN + 1]).is_err());
+
+        let len = (MAX_FRAME_LEN as u32 + 1).to_le_bytes();
+        assert!(read_frame(len.as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let mut buf = 5u32.to_le_bytes().to_vec();
+        buf.extend_from_slice(b"hel");
+        assert!(read_frame(buf.as_slice()).is_err());
+    }
+}
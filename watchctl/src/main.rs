@@ -0,0 +1,634 @@
+//! Builds, decodes and sends the watch's protocol messages, so they can be
+//! crafted by hand or from scripts without a phone.
+
+use std::io::{BufReader, Read};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use base64::Engine as _;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use color_eyre::eyre::{bail, ensure, eyre};
+use prost::Message as _;
+use sha2::{Digest, Sha256};
+
+mod emulator;
+mod link;
+mod message;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Build a message and print its encoding
+    Encode {
+        #[arg(long, value_enum, default_value_t = Format::Hex)]
+        format: Format,
+        #[command(flatten)]
+        envelope: Envelope,
+        #[command(subcommand)]
+        body: Body,
+    },
+    /// Print a notification from the watch in readable form, one line for
+    /// each in a batch
+    Decode {
+        #[arg(long, value_enum, default_value_t = Format::Hex)]
+        format: Format,
+        /// The encoded notification, read from stdin if not given
+        data: Option<String>,
+    },
+    /// Send a message to a watch emulator and print its replies
+    Send {
+        #[arg(long, default_value = DEFAULT_ADDR)]
+        addr: String,
+        /// Seconds to wait for the watch to answer
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+        #[command(flatten)]
+        envelope: Envelope,
+        #[command(subcommand)]
+        body: Body,
+    },
+    /// Listen for messages and answer them like the watch would
+    Emulate {
+        #[arg(long, default_value = DEFAULT_ADDR)]
+        addr: String,
+        /// Require messages to be signed with this key, as if it had been
+        /// provisioned. Without one, messages are checked by origin.
+        #[arg(long, value_parser = parse_key)]
+        key: Option<Key>,
+    },
+    /// Print the Start command for a firmware update, to write to the watch's
+    /// DFU control point
+    DfuStart {
+        /// The app image, as built by `cargo espflash save-image`
+        image: PathBuf,
+        /// Hex encoded Ed25519 signature of the image's SHA-256, for watches
+        /// built with `signed-ota`
+        #[arg(long, value_parser = parse_signature)]
+        signature: Option<Signature>,
+    },
+}
+
+#[derive(Args)]
+struct Envelope {
+    /// Echoed back in the watch's reply
+    #[arg(long, global = true, default_value_t = 1)]
+    request_id: u32,
+    /// Hex encoded key to sign the message with
+    #[arg(long, global = true, value_parser = parse_key)]
+    key: Option<Key>,
+    /// Must be larger than that of the last message the watch accepted.
+    /// Defaults to the current time in milliseconds.
+    #[arg(long, global = true)]
+    counter: Option<u64>,
+}
+
+#[derive(Subcommand)]
+enum Body {
+    /// Set the watch's clock
+    SyncClock {
+        /// Seconds since the epoch, defaults to now
+        #[arg(long)]
+        unix: Option<i64>,
+    },
+    /// Show a notification on the watch
+    Push {
+        body: String,
+        #[arg(long, default_value = "")]
+        title: String,
+        #[arg(long, default_value = "")]
+        app_id: String,
+        #[arg(long, default_value = "")]
+        sender: String,
+        #[arg(long, value_enum, default_value_t = Category::Other)]
+        category: Category,
+        #[arg(long, value_enum, default_value_t = Priority::Normal)]
+        priority: Priority,
+        /// A choice the user can respond with, may be repeated
+        #[arg(long = "action")]
+        actions: Vec<String>,
+        /// Sent back with the user's response
+        #[arg(long, default_value_t = 0)]
+        id: u32,
+    },
+    /// Drive or read one of the GPIO pins
+    SetPin {
+        #[arg(value_enum)]
+        pin: Pin,
+        #[arg(value_enum)]
+        op: PinOp,
+    },
+    /// List the phones the watch has bonded with
+    ListBonds,
+    /// Make the watch forget a bonded phone
+    DeleteBond {
+        /// Written most significant byte first, like AA:BB:CC:DD:EE:FF
+        #[arg(value_parser = parse_address)]
+        address: Address,
+        /// The address is random static rather than public
+        #[arg(long)]
+        random: bool,
+    },
+    /// Change how the watch advertises itself, which it keeps across reboots
+    Advertising {
+        /// Defaults to the crab emoji
+        #[arg(long, default_value = "")]
+        name: String,
+        /// A 16 or 128 bit service UUID to advertise, may be repeated
+        #[arg(long = "uuid", value_parser = parse_uuid)]
+        uuids: Vec<Uuid>,
+        /// A field to send in the scan response rather than the
+        /// advertisement, may be repeated
+        #[arg(long = "scan-response", value_enum)]
+        scan_response: Vec<AdvertisingField>,
+        /// Zero picks the default
+        #[arg(long, default_value_t = 0)]
+        interval_ms: u32,
+        /// Zero advertises until something connects
+        #[arg(long, default_value_t = 60)]
+        duration_secs: u32,
+        /// How long to keep trying to get a bonded phone back after losing
+        /// it, zero picks the default
+        #[arg(long, default_value_t = 0)]
+        reconnect_secs: u32,
+    },
+    /// Ask how the watch's connections are running
+    Diagnostics,
+    /// Change when the watch turns its radio off, which it keeps across
+    /// reboots
+    Radio {
+        /// Zero leaves the radio on
+        #[arg(long, default_value_t = 300)]
+        idle_timeout_secs: u32,
+        /// Zero only turns the radio back on with the side button
+        #[arg(long, default_value_t = 1800)]
+        sync_interval_secs: u32,
+    },
+    /// Change how many notifications the watch holds while the phone is
+    /// away, which it keeps across reboots
+    Outbox {
+        /// Held in RAM before the oldest are spilled to flash
+        #[arg(long, default_value_t = 16)]
+        ram_capacity: u32,
+        /// Held in flash before the oldest are thrown away, zero to not spill
+        #[arg(long, default_value_t = 64)]
+        flash_capacity: u32,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Hex,
+    Base64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Category {
+    Other,
+    Message,
+    Call,
+    Calendar,
+    Email,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Priority {
+    Low,
+    Normal,
+    High,
+    Urgent,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum AdvertisingField {
+    Name,
+    TxPower,
+    ServiceUuids,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Pin {
+    G26,
+    G25,
+    G0,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PinOp {
+    High,
+    Low,
+    Read,
+}
+
+#[derive(Clone)]
+struct Key(Vec<u8>);
+
+fn parse_key(s: &str) -> color_eyre::Result<Key> {
+    Ok(Key(hex::decode(s.trim())?))
+}
+
+/// An Ed25519 signature over a firmware image's digest
+#[derive(Clone)]
+struct Signature(Vec<u8>);
+
+fn parse_signature(s: &str) -> color_eyre::Result<Signature> {
+    let signature = hex::decode(s.trim())?;
+    ensure!(
+        signature.len() == 64,
+        "Signatures are 64 bytes, got {}",
+        signature.len()
+    );
+    Ok(Signature(signature))
+}
+
+/// A Bluetooth address, least significant byte first like the watch sends it
+#[derive(Clone)]
+struct Address(Vec<u8>);
+
+fn parse_address(s: &str) -> color_eyre::Result<Address> {
+    let mut addr = hex::decode(s.trim().replace(':', ""))?;
+    ensure!(addr.len() == 6, "Addresses are 6 bytes, got {}", addr.len());
+    addr.reverse();
+    Ok(Address(addr))
+}
+
+/// A service UUID, least significant byte first as it goes over the air
+#[derive(Clone)]
+struct Uuid(Vec<u8>);
+
+fn parse_uuid(s: &str) -> color_eyre::Result<Uuid> {
+    let mut uuid = hex::decode(s.trim().replace('-', ""))?;
+    ensure!(
+        uuid.len() == 2 || uuid.len() == 16,
+        "UUIDs are 2 or 16 bytes, got {}",
+        uuid.len()
+    );
+    uuid.reverse();
+    Ok(Uuid(uuid))
+}
+
+impl From<Category> for message::Category {
+    fn from(category: Category) -> Self {
+        match category {
+            Category::Other => Self::Other,
+            Category::Message => Self::Message,
+            Category::Call => Self::Call,
+            Category::Calendar => Self::Calendar,
+            Category::Email => Self::Email,
+        }
+    }
+}
+
+impl From<Priority> for message::Priority {
+    fn from(priority: Priority) -> Self {
+        match priority {
+            Priority::Low => Self::Low,
+            Priority::Normal => Self::Normal,
+            Priority::High => Self::High,
+            Priority::Urgent => Self::Urgent,
+        }
+    }
+}
+
+impl From<AdvertisingField> for message::AdvertisingField {
+    fn from(field: AdvertisingField) -> Self {
+        match field {
+            AdvertisingField::Name => Self::Name,
+            AdvertisingField::TxPower => Self::TxPower,
+            AdvertisingField::ServiceUuids => Self::ServiceUuids,
+        }
+    }
+}
+
+impl From<Pin> for message::Pins {
+    fn from(pin: Pin) -> Self {
+        match pin {
+            Pin::G26 => Self::G26,
+            Pin::G25 => Self::G25,
+            Pin::G0 => Self::G0,
+        }
+    }
+}
+
+impl From<PinOp> for message::PinOperation {
+    fn from(op: PinOp) -> Self {
+        match op {
+            PinOp::High => Self::SetHigh,
+            PinOp::Low => Self::SetLow,
+            PinOp::Read => Self::AnalogueRead,
+        }
+    }
+}
+
+impl Body {
+    fn build(self) -> message::message::Body {
+        match self {
+            Body::SyncClock { unix } => {
+                let timestamp = match unix {
+                    Some(seconds) => prost_types::Timestamp { seconds, nanos: 0 },
+                    None => message::now(),
+                };
+                message::message::Body::SyncClock(message::SyncClock {
+                    timestamp: Some(timestamp),
+                })
+            }
+            Body::Push {
+                body,
+                title,
+                app_id,
+                sender,
+                category,
+                priority,
+                actions,
+                id,
+            } => message::message::Body::PushNotification(message::PushNotification {
+                body,
+                title,
+                app_id,
+                sender,
+                timestamp: Some(message::now()),
+                category: message::Category::from(category) as i32,
+                priority: message::Priority::from(priority) as i32,
+                actions,
+                id,
+            }),
+            Body::SetPin { pin, op } => message::message::Body::SetPin(message::SetPin {
+                pin: message::Pins::from(pin) as i32,
+                op: message::PinOperation::from(op) as i32,
+            }),
+            Body::ListBonds => message::message::Body::ListBonds(message::ListBonds {}),
+            Body::Diagnostics => message::message::Body::GetDiagnostics(message::GetDiagnostics {}),
+            Body::DeleteBond {
+                address: Address(address),
+                random,
+            } => message::message::Body::DeleteBond(message::DeleteBond {
+                bond: Some(message::Bond {
+                    address,
+                    address_type: random as u32,
+                    connected: false,
+                }),
+            }),
+            Body::Advertising {
+                name,
+                uuids,
+                scan_response,
+                interval_ms,
+                duration_secs,
+                reconnect_secs,
+            } => message::message::Body::SetAdvertisingConfig(message::SetAdvertisingConfig {
+                config: Some(message::AdvertisingConfig {
+                    name,
+                    service_uuids: uuids.into_iter().map(|Uuid(uuid)| uuid).collect(),
+                    scan_response: scan_response
+                        .into_iter()
+                        .map(|field| message::AdvertisingField::from(field) as i32)
+                        .collect(),
+                    interval_ms,
+                    duration_secs,
+                    reconnect_secs,
+                }),
+            }),
+            Body::Radio {
+                idle_timeout_secs,
+                sync_interval_secs,
+            } => message::message::Body::SetRadioConfig(message::SetRadioConfig {
+                config: Some(message::RadioConfig {
+                    idle_timeout_secs,
+                    sync_interval_secs,
+                }),
+            }),
+            Body::Outbox {
+                ram_capacity,
+                flash_capacity,
+            } => message::message::Body::SetOutboxConfig(message::SetOutboxConfig {
+                config: Some(message::OutboxConfig {
+                    ram_capacity,
+                    flash_capacity,
+                }),
+            }),
+        }
+    }
+}
+
+impl Envelope {
+    fn wrap(self, body: Body) -> message::SignedMessage {
+        let counter = self.counter.unwrap_or_else(message::default_counter);
+        let msg = message::envelope(body.build(), self.request_id);
+        let key = self.key.as_ref().map(|Key(key)| key.as_slice());
+        message::seal(&msg, counter, key)
+    }
+}
+
+impl Format {
+    fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            Format::Hex => hex::encode(bytes),
+            Format::Base64 => base64::engine::general_purpose::STANDARD.encode(bytes),
+        }
+    }
+
+    fn decode(self, s: &str) -> color_eyre::Result<Vec<u8>> {
+        let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+        Ok(match self {
+            Format::Hex => hex::decode(s)?,
+            Format::Base64 => base64::engine::general_purpose::STANDARD.decode(s)?,
+        })
+    }
+}
+
+fn send(
+    addr: &str,
+    timeout: Duration,
+    request_id: u32,
+    msg: message::SignedMessage,
+) -> color_eyre::Result<()> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(timeout))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    link::write_frame(&stream, &msg.encode_to_vec())?;
+
+    // anything unrelated, like a PinRead, is printed on the way to our reply
+    loop {
+        let payload = link::read_frame(&mut reader)
+            .map_err(|err| eyre!("No reply to request {}: {}", request_id, err))?
+            .ok_or_else(|| eyre!("The watch hung up without replying"))?;
+        let notifs = message::Notification::decode(payload.as_slice())?.unbatch();
+        for notif in &notifs {
+            println!("{}", message::describe(notif));
+        }
+
+        // messages turned away before they could be trusted are answered
+        // with request id 0, and ours is the only one in flight
+        if notifs.iter().any(|notif| {
+            notif.request_id() == Some(0)
+                && matches!(notif.body, Some(message::notification::Body::Error(_)))
+        }) {
+            bail!("The watch rejected request {}", request_id);
+        }
+
+        if let Some(notif) = notifs
+            .iter()
+            .find(|notif| notif.request_id() == Some(request_id))
+        {
+            if let Some(message::notification::Body::Error(_)) = notif.body {
+                bail!("The watch rejected request {}", request_id);
+            }
+            return Ok(());
+        }
+    }
+}
+
+fn main() -> color_eyre::Result<()> {
+    color_eyre::install()?;
+
+    match Cli::parse().command {
+        Command::Encode {
+            format,
+            envelope,
+            body,
+        } => {
+            let msg = envelope.wrap(body);
+            println!("{}", format.encode(&msg.encode_to_vec()));
+        }
+        Command::Decode { format, data } => {
+            let data = match data {
+                Some(data) => data,
+                None => {
+                    let mut data = String::new();
+                    std::io::stdin().read_to_string(&mut data)?;
+                    data
+                }
+            };
+            let bytes = format.decode(&data)?;
+            ensure!(!bytes.is_empty(), "Nothing to decode");
+            for notif in message::Notification::decode(bytes.as_slice())?.unbatch() {
+                println!("{}", message::describe(&notif));
+            }
+        }
+        Command::Send {
+            addr,
+            timeout,
+            envelope,
+            body,
+        } => {
+            let request_id = envelope.request_id;
+            let msg = envelope.wrap(body);
+            send(&addr, Duration::from_secs(timeout), request_id, msg)?;
+        }
+        Command::Emulate { addr, key } => {
+            emulator::serve(addr, key.map(|Key(key)| key))?;
+        }
+        Command::DfuStart { image, signature } => {
+            let image = std::fs::read(image)?;
+            let size = u32::try_from(image.len())?;
+            let digest = Sha256::digest(&image);
+
+            let mut start = vec![1];
+            start.extend_from_slice(&size.to_le_bytes());
+            start.extend_from_slice(&digest);
+            if let Some(Signature(signature)) = signature {
+                start.extend_from_slice(&signature);
+            }
+
+            println!("sha256 {}", hex::encode(digest));
+            println!("start {}", hex::encode(start));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(args: &[&str]) -> (Format, String) {
+        let args = ["watchctl", "encode"].iter().chain(args);
+        match Cli::try_parse_from(args).unwrap().command {
+            Command::Encode {
+                format,
+                envelope,
+                body,
+            } => (format, format.encode(&envelope.wrap(body).encode_to_vec())),
+            _ => unreachable!(),
+        }
+    }
+
+    fn decode(format: Format, encoded: &str) -> (message::SignedMessage, message::Message) {
+        let bytes = format.decode(encoded).unwrap();
+        let signed = message::SignedMessage::decode(bytes.as_slice()).unwrap();
+        let msg = message::Message::decode(signed.payload.as_slice()).unwrap();
+        (signed, msg)
+    }
+
+    #[test]
+    fn encoded_messages_decode_to_what_was_asked_for() {
+        let (format, encoded) = encode(&[
+            "--request-id",
+            "9",
+            "--counter",
+            "100",
+            "radio",
+            "--idle-timeout-secs",
+            "30",
+        ]);
+        let (signed, msg) = decode(format, &encoded);
+
+        assert_eq!(signed.counter, 100);
+        assert!(signed.mac.is_empty());
+        assert_eq!(msg.request_id, 9);
+        assert_eq!(msg.origin, message::ORIGIN);
+        match msg.body {
+            Some(message::message::Body::SetRadioConfig(set)) => {
+                assert_eq!(set.config.unwrap().idle_timeout_secs, 30);
+            }
+            other => panic!("Expected a radio config, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn base64_round_trips_and_signs() {
+        let key = "55".repeat(32);
+        let (format, encoded) = encode(&[
+            "--format",
+            "base64",
+            "--key",
+            &key,
+            "--counter",
+            "7",
+            "push",
+            "hello",
+            "--title",
+            "hi",
+        ]);
+        let (signed, msg) = decode(format, &format!("{}\n", encoded));
+
+        assert!(message::verify(&[0x55; 32], &signed));
+        match msg.body {
+            Some(message::message::Body::PushNotification(push)) => {
+                assert_eq!(push.body, "hello");
+                assert_eq!(push.title, "hi");
+            }
+            other => panic!("Expected a push notification, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn formats_ignore_whitespace() {
+        let bytes = [0x00, 0x10, 0xff];
+        for format in [Format::Hex, Format::Base64] {
+            let encoded = format.encode(&bytes);
+            let spaced: String = encoded.chars().flat_map(|c| [c, ' ']).collect();
+            assert_eq!(format.decode(&spaced).unwrap(), bytes);
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! The watch's protocol messages, and helpers for building and printing them.
+
+// prost nests the `Message` oneof in a module called `message`
+#![allow(clippy::module_inception)]
+
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use prost::Message as _;
+use sha2::Sha256;
+
+include!(concat!(env!("OUT_DIR"), "/messages.items.rs"));
+include!("../../watch-core/src/protocol_version.rs");
+
+/// The origin the firmware accepts when built with `legacy-origin`
+pub const ORIGIN: u32 = 3387062;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn now() -> prost_types::Timestamp {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    prost_types::Timestamp {
+        seconds: since_epoch.as_secs() as i64,
+        nanos: since_epoch.subsec_nanos() as i32,
+    }
+}
+
+/// Milliseconds since the epoch, which always makes for a fresh counter
+pub fn default_counter() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+pub fn envelope(body: message::Body, request_id: u32) -> Message {
+    Message {
+        origin: ORIGIN,
+        request_id,
+        body: Some(body),
+    }
+}
+
+/// HMAC-SHA256 of the counter as 8 little endian bytes followed by the
+/// payload, as the firmware expects
+fn hmac(key: &[u8], counter: u64, payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&counter.to_le_bytes());
+    mac.update(payload);
+    mac
+}
+
+/// Encodes `msg` into what goes over the wire, signed if there's a key
+pub fn seal(msg: &Message, counter: u64, key: Option<&[u8]>) -> SignedMessage {
+    let payload = msg.encode_to_vec();
+    let mac = key
+        .map(|key| {
+            hmac(key, counter, &payload)
+                .finalize()
+                .into_bytes()
+                .to_vec()
+        })
+        .unwrap_or_default();
+
+    SignedMessage {
+        payload,
+        mac,
+        counter,
+    }
+}
+
+/// Whether the MAC covers the payload and counter exactly as they are
+pub fn verify(key: &[u8], signed: &SignedMessage) -> bool {
+    hmac(key, signed.counter, &signed.payload)
+        .verify_slice(&signed.mac)
+        .is_ok()
+}
+
+impl Notification {
+    pub fn ack(request_id: u32) -> Self {
+        Self {
+            body: Some(notification::Body::Ack(Ack { request_id })),
+        }
+    }
+
+    pub fn error(request_id: u32, code: ErrorCode, detail: impl Into<String>) -> Self {
+        Self {
+            body: Some(notification::Body::Error(Error {
+                request_id,
+                code: code as i32,
+                detail: detail.into(),
+            })),
+        }
+    }
+
+    /// The request this notification answers, if it answers one
+    pub fn request_id(&self) -> Option<u32> {
+        match &self.body {
+            Some(notification::Body::Ack(ack)) => Some(ack.request_id),
+            Some(notification::Body::Error(error)) => Some(error.request_id),
+            Some(notification::Body::DeviceInfo(info)) => Some(info.request_id),
+            Some(notification::Body::Bonds(bonds)) => Some(bonds.request_id),
+            Some(notification::Body::Diagnostics(diag)) => Some(diag.request_id),
+            _ => None,
+        }
+    }
+
+    /// The notifications in a batch, or just this one if it isn't
+    pub fn unbatch(self) -> Vec<Notification> {
+        match self.body {
+            Some(notification::Body::Batch(batch)) => batch.notifications,
+            _ => vec![self],
+        }
+    }
+}
+
+/// A one line, human readable rendering of a notification
+pub fn describe(notif: &Notification) -> String {
+    let mut out = String::new();
+
+    match &notif.body {
+        None => out.push_str("empty notification"),
+        Some(notification::Body::Ack(ack)) => {
+            write!(out, "ack request_id={}", ack.request_id).unwrap();
+        }
+        Some(notification::Body::Error(error)) => {
+            write!(
+                out,
+                "error request_id={} code={:?} detail={:?}",
+                error.request_id,
+                ErrorCode::from_i32(error.code).unwrap_or(ErrorCode::Unknown),
+                error.detail
+            )
+            .unwrap();
+        }
+        Some(notification::Body::PinRead(read)) => {
+            write!(
+                out,
+                "pin_read request_id={} pin={:?} value={}",
+                read.request_id,
+                Pins::from_i32(read.pin),
+                read.value
+            )
+            .unwrap();
+        }
+        Some(notification::Body::DeviceInfo(info)) => {
+            write!(
+                out,
+                "device_info request_id={} version={} git={} profile={} protocol={} \
+                 features={:?} board={} uptime_ms={} battery={}%",
+                info.request_id,
+                info.crate_version,
+                info.git_hash,
+                info.build_profile,
+                info.protocol_version,
+                info.features,
+                info.board,
+                info.uptime_ms,
+                info.battery_percent
+            )
+            .unwrap();
+        }
+        Some(notification::Body::NotificationAction(action)) => {
+            write!(
+                out,
+                "notification_action id={} action={:?} index={} label={:?}",
+                action.id,
+                Action::from_i32(action.action),
+                action.index,
+                action.label
+            )
+            .unwrap();
+        }
+        Some(notification::Body::Bonds(bonds)) => {
+            write!(out, "bonds request_id={}", bonds.request_id).unwrap();
+            for bond in &bonds.bonds {
+                write!(out, " {}", format_address(&bond.address)).unwrap();
+                if bond.address_type != 0 {
+                    out.push_str("(random)");
+                }
+                if bond.connected {
+                    out.push_str("(connected)");
+                }
+            }
+        }
+        Some(notification::Body::Diagnostics(diag)) => {
+            write!(out, "diagnostics request_id={}", diag.request_id).unwrap();
+            for conn in &diag.connections {
+                write!(
+                    out,
+                    " {} interval={}us latency={} timeout={}ms mtu={} profile={:?}",
+                    format_address(&conn.address),
+                    conn.interval_us,
+                    conn.latency,
+                    conn.supervision_timeout_ms,
+                    conn.mtu,
+                    conn.profile()
+                )
+                .unwrap();
+                if conn.encrypted {
+                    out.push_str("(encrypted)");
+                }
+            }
+            if let Some(radio) = &diag.radio {
+                write!(
+                    out,
+                    " radio={} on={}s off={}s restarts={} power={}mW/{}mW hours_left={:.1}/{:.1}",
+                    if radio.on { "on" } else { "off" },
+                    radio.on_ms / 1000,
+                    radio.off_ms / 1000,
+                    radio.restarts,
+                    radio.on_power_mw,
+                    radio.off_power_mw,
+                    radio.hours_left,
+                    radio.hours_left_always_on
+                )
+                .unwrap();
+            }
+            if let Some(tx) = &diag.tx {
+                write!(
+                    out,
+                    " tx sent={} retried={} failed={} indicated={} coalesced={} dropped={} \
+                     evicted={} pending={}",
+                    tx.sent,
+                    tx.retried,
+                    tx.failed,
+                    tx.indicated,
+                    tx.coalesced,
+                    tx.dropped,
+                    tx.evicted,
+                    tx.pending
+                )
+                .unwrap();
+            }
+            for queue in &diag.bus {
+                write!(
+                    out,
+                    " bus {}/{} queued={}/{} high_water={} delivered={} dropped={}",
+                    queue.subscriber,
+                    queue.topic,
+                    queue.queued,
+                    queue.depth,
+                    queue.high_water,
+                    queue.delivered,
+                    queue.dropped
+                )
+                .unwrap();
+            }
+        }
+        Some(notification::Body::Batch(batch)) => {
+            let notifs: Vec<_> = batch.notifications.iter().map(describe).collect();
+            write!(out, "batch of {}: {}", notifs.len(), notifs.join("; ")).unwrap();
+        }
+    }
+
+    out
+}
+
+/// Most significant byte first, the way addresses are usually written
+pub fn format_address(addr: &[u8]) -> String {
+    addr.iter()
+        .rev()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sync_clock(seconds: i64) -> message::Body {
+        message::Body::SyncClock(SyncClock {
+            timestamp: Some(prost_types::Timestamp { seconds, nanos: 0 }),
+        })
+    }
+
+    #[test]
+    fn sealed_messages_decode_to_what_was_sealed() {
+        let msg = envelope(sync_clock(1_700_000_000), 7);
+        let signed = seal(&msg, 42, None);
+
+        let signed = SignedMessage::decode(signed.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(signed.counter, 42);
+        assert!(signed.mac.is_empty());
+        assert_eq!(Message::decode(signed.payload.as_slice()).unwrap(), msg);
+    }
+
+    #[test]
+    fn signed_messages_verify_until_tampered_with() {
+        let key = [0x55; 32];
+        let msg = envelope(sync_clock(1_700_000_000), 7);
+        let signed =
+            SignedMessage::decode(seal(&msg, 42, Some(&key)).encode_to_vec().as_slice()).unwrap();
+        assert!(verify(&key, &signed));
+        assert!(!verify(&[0xaa; 32], &signed));
+
+        let mut replayed = signed.clone();
+        replayed.counter += 1;
+        assert!(!verify(&key, &replayed));
+
+        let mut tampered = signed;
+        tampered.payload[0] ^= 1;
+        assert!(!verify(&key, &tampered));
+    }
+
+    #[test]
+    fn notifications_survive_the_wire() {
+        let notif = Notification::error(3, ErrorCode::Replayed, "too old");
+        let decoded = Notification::decode(notif.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, notif);
+        assert_eq!(decoded.request_id(), Some(3));
+    }
+
+    #[test]
+    fn batches_unpack_into_their_notifications() {
+        let notifs = vec![Notification::ack(1), Notification::ack(2)];
+        let batch = Notification {
+            body: Some(notification::Body::Batch(NotificationBatch {
+                notifications: notifs.clone(),
+            })),
+        };
+
+        let decoded = Notification::decode(batch.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(
+            describe(&decoded),
+            "batch of 2: ack request_id=1; ack request_id=2"
+        );
+        assert_eq!(decoded.unbatch(), notifs);
+        assert_eq!(Notification::ack(3).unbatch(), vec![Notification::ack(3)]);
+    }
+
+    #[test]
+    fn formats_addresses_most_significant_first() {
+        assert_eq!(
+            format_address(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+            "06:05:04:03:02:01"
+        );
+    }
+}